@@ -1,8 +1,9 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use lofigirl_shared_common::config::{
-    ConfigError, LastFMApiConfig, LastFMClientConfig, ListenBrainzConfig, ServerConfig,
+    ConfigError, LastFMApiConfig, LastFMClientConfig, ListenBrainzConfig, OverlayConfig,
+    ServerConfig,
 };
 use serde::{Deserialize, Serialize};
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
@@ -10,17 +11,26 @@ use tracing::info;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// File the listens which could not be sent are kept in until they are
+    /// sent, `outbox.json` if not set. Comes first as toml puts plain values
+    /// before the tables.
+    pub outbox: Option<PathBuf>,
     pub lastfm: Option<LastFMClientConfig>,
     pub lastfm_api: Option<LastFMApiConfig>,
     pub listenbrainz: Option<ListenBrainzConfig>,
     pub session: Option<TokenConfig>,
     pub server: Option<ServerConfig>,
+    pub overlay: Option<OverlayConfig>,
+    /// File the config is loaded from.
+    #[serde(skip)]
+    pub file: PathBuf,
 }
 
 impl Config {
     pub async fn from_toml(file_name: &Path) -> Result<Config> {
         let file_contents = String::from_utf8(tokio::fs::read(file_name).await?)?;
-        let config: Config = toml::from_str(&file_contents)?;
+        let mut config: Config = toml::from_str(&file_contents)?;
+        config.file = file_name.to_owned();
         (config.lastfm.is_some() || config.listenbrainz.is_some())
             .then_some(())
             .ok_or(ConfigError::EmptyListeners)?;
@@ -44,8 +54,21 @@ impl Config {
         buffer.write_all(contents.as_bytes()).await?;
         Ok(())
     }
+
+    /// Replaces the stored session in the config file, as the previous
+    /// refresh token has been used up.
+    #[cfg(not(feature = "standalone"))]
+    pub async fn store_session(file_name: &Path, session: TokenConfig) -> Result<()> {
+        let mut config = Config::from_toml(file_name).await?;
+        config.session = Some(session);
+        config.to_toml(file_name).await
+    }
 }
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TokenConfig {
-    pub token: String,
+    /// Refresh token to obtain short lived access tokens from the server.
+    /// Configs written by older versions store a plain `token` instead,
+    /// which fails to refresh and is replaced on the next start.
+    #[serde(alias = "token")]
+    pub refresh_token: String,
 }
@@ -0,0 +1,76 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use lofigirl_shared_common::api::{PlayingAtQuery, PlayingAtResponse, TimelineEntry};
+use lofigirl_shared_common::config::ConfigError;
+use lofigirl_shared_common::moment::Moment;
+use lofigirl_shared_common::stream::StreamId;
+use lofigirl_shared_common::STREAMS_END_POINT;
+use reqwest::Client;
+
+use crate::config::Config;
+
+/// Prints the track which was on air on the stream at the given time, with
+/// the neighboring tracks around it.
+pub async fn print_playing_at(
+    config: &Config,
+    stream_id: &StreamId,
+    time: Moment,
+    context: u32,
+) -> Result<()> {
+    let base_url = config
+        .server
+        .as_ref()
+        .ok_or(ConfigError::EmptyServerConfig)?
+        .link
+        .as_str();
+    let response: PlayingAtResponse = Client::new()
+        .get(format!(
+            "{}{}/{}/at",
+            base_url, STREAMS_END_POINT, stream_id
+        ))
+        .query(&PlayingAtQuery {
+            time,
+            context: Some(context),
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    for entry in &response.before {
+        println!("  {}", format_entry(entry));
+    }
+    match &response.track {
+        Some(entry) => println!("> {}", format_entry(entry)),
+        None => println!(
+            "> no track was detected at {}",
+            format_timestamp(response.time)
+        ),
+    }
+    for entry in &response.after {
+        println!("  {}", format_entry(entry));
+    }
+    Ok(())
+}
+
+fn format_entry(entry: &TimelineEntry) -> String {
+    format!(
+        "{} - {}  {}",
+        format_timestamp(entry.started_at),
+        entry
+            .ended_at
+            .map(format_timestamp)
+            .unwrap_or_else(|| "on air".to_owned()),
+        entry.track
+    )
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|t| {
+            t.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|| timestamp.to_string())
+}
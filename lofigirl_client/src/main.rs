@@ -1,11 +1,17 @@
 mod config;
+#[cfg(not(feature = "standalone"))]
+mod lookup;
+mod outbox;
 mod worker;
 
 use anyhow::Result;
 use clap::Parser;
 use config::Config;
+use lofigirl_shared_common::stream::StreamId;
 use std::path::PathBuf;
 use worker::Worker;
+#[cfg(not(feature = "standalone"))]
+use {clap::Subcommand, lofigirl_shared_common::moment::Moment};
 
 #[cfg(not(feature = "standalone"))]
 const APP_NAME: &str = "lofigirl_client";
@@ -20,9 +26,26 @@ struct Opt {
     /// Configuration toml file.
     #[clap(short, long, value_parser, default_value = "config.toml")]
     config: PathBuf,
-    /// LofiGirl Youtube stream URL.
+    /// LofiGirl Youtube stream URL, in any form, or its video id.
     #[clap(short, long, value_parser)]
-    url: url::Url,
+    url: StreamId,
+    #[cfg(not(feature = "standalone"))]
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[cfg(not(feature = "standalone"))]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the track which was on air on the stream at the given time.
+    At {
+        /// Unix seconds, RFC 3339, now or relative to now such as -25m or -1h30m.
+        #[clap(allow_hyphen_values = true)]
+        time: Moment,
+        /// Number of the neighboring tracks to show on each side.
+        #[clap(long, default_value_t = 2)]
+        context: u32,
+    },
 }
 
 fn main() -> Result<()> {
@@ -36,11 +59,14 @@ async fn body() -> Result<()> {
     tracing_subscriber::fmt::init();
     let opt = Opt::parse();
     let mut config = Config::from_toml(&opt.config).await?;
-    let requested_url = opt.url;
-    let (mut worker, changed) = Worker::new(&mut config, requested_url).await?;
+    #[cfg(not(feature = "standalone"))]
+    if let Some(Command::At { time, context }) = opt.command {
+        return lookup::print_playing_at(&config, &opt.url, time, context).await;
+    }
+    let (mut worker, changed) = Worker::new(&mut config, &opt.url).await?;
     if changed {
         // modify config file so that we can store token and/or session_key
-        config.to_toml(&opt.config).await?;
+        config.to_toml(&config.file).await?;
     }
     worker.work().await?;
     Ok(())
@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use lofigirl_shared_common::track::Track;
+use lofigirl_shared_listen::outbox::{is_too_old, retry_delay};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// A listen which could not be sent, with the unix second it happened at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedListen {
+    pub track: Track,
+    pub listened_at: i64,
+    /// Service the listen has failed on, for the standalone client which
+    /// sends to each service itself.
+    #[serde(default)]
+    pub service: Option<String>,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+}
+
+/// Listens waiting to be sent again, kept in a JSON file so that they
+/// survive a restart.
+pub struct Outbox {
+    path: PathBuf,
+    listens: Vec<QueuedListen>,
+}
+
+impl Outbox {
+    /// Loads the outbox from the file, empty if there is no file yet.
+    pub async fn load(path: PathBuf) -> Result<Outbox> {
+        let listens = match tokio::fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let outbox = Outbox { path, listens };
+        if !outbox.listens.is_empty() {
+            outbox.report();
+        }
+        Ok(outbox)
+    }
+
+    /// Queues a listen which has just failed.
+    pub async fn queue(&mut self, track: &Track, listened_at: i64, service: Option<&str>) {
+        self.listens.push(QueuedListen {
+            track: track.clone(),
+            listened_at,
+            service: service.map(str::to_owned),
+            attempts: 1,
+            next_attempt_at: chrono::Utc::now().timestamp() + retry_delay(1).as_secs() as i64,
+        });
+        self.save().await;
+    }
+
+    /// Takes the listens due for a retry out of the outbox, oldest first.
+    /// The listens too old for the services are dropped.
+    pub fn take_due(&mut self) -> Vec<QueuedListen> {
+        let now = chrono::Utc::now().timestamp();
+        let count = self.listens.len();
+        self.listens
+            .retain(|listen| !is_too_old(listen.listened_at, now));
+        if self.listens.len() < count {
+            warn!(
+                "Dropped {} queued listens too old to be sent",
+                count - self.listens.len()
+            );
+        }
+        let (mut due, waiting) = self
+            .listens
+            .drain(..)
+            .partition::<Vec<_>, _>(|listen| listen.next_attempt_at <= now);
+        self.listens = waiting;
+        due.sort_by_key(|listen| listen.listened_at);
+        due
+    }
+
+    /// Puts back the listens which have failed again, to be retried later.
+    pub fn postpone(&mut self, listens: Vec<QueuedListen>) {
+        let now = chrono::Utc::now().timestamp();
+        self.listens.extend(listens.into_iter().map(|mut listen| {
+            listen.attempts += 1;
+            listen.next_attempt_at = now + retry_delay(listen.attempts).as_secs() as i64;
+            listen
+        }));
+    }
+
+    /// Makes the waiting listens of the service due, as the service works again.
+    pub fn release(&mut self, service: Option<&str>) {
+        let now = chrono::Utc::now().timestamp();
+        self.listens
+            .iter_mut()
+            .filter(|listen| listen.service.as_deref() == service)
+            .for_each(|listen| listen.next_attempt_at = listen.next_attempt_at.min(now));
+    }
+
+    /// Writes the outbox to its file and reports its size.
+    pub async fn save(&self) {
+        let written = match serde_json::to_vec(&self.listens) {
+            Ok(contents) => tokio::fs::write(&self.path, contents)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            warn!("Cannot write the outbox to {}: {}", self.path.display(), e);
+        }
+        self.report();
+    }
+
+    fn report(&self) {
+        info!("{} listens are waiting to be sent", self.listens.len());
+    }
+}
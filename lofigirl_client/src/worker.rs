@@ -1,42 +1,65 @@
 use crate::config::Config;
+use crate::outbox::{Outbox, QueuedListen};
 use anyhow::Result;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 use lofigirl_shared_common::config::LastFMClientConfig;
 
+use lofigirl_shared_common::stream::StreamId;
 use lofigirl_shared_common::track::Track;
+use lofigirl_shared_listen::listener::SendListensError;
+use lofigirl_shared_listen::outbox::split_sent;
 
+use tokio::sync::Mutex;
+use tracing::warn;
 use url::Url;
 
+/// Outbox file used if the config does not name one.
+const DEFAULT_OUTBOX_FILE: &str = "outbox.json";
+
+/// Queued listens sent at once. The standalone client sends them to the
+/// services in batches, while the server batches the listens it queues itself.
+#[cfg(feature = "standalone")]
+const QUEUED_BATCH_SIZE: usize = lofigirl_shared_listen::outbox::OUTBOX_BATCH_SIZE;
+#[cfg(not(feature = "standalone"))]
+const QUEUED_BATCH_SIZE: usize = 1;
+
 #[cfg(not(feature = "standalone"))]
 use {
     crate::config::TokenConfig,
     anyhow::bail,
     futures_util::{SinkExt as _, StreamExt as _, TryStreamExt as _},
     lofigirl_shared_common::api::Action,
+    lofigirl_shared_common::api::EncryptionKeyResponse,
+    lofigirl_shared_common::api::RefreshRequest,
     lofigirl_shared_common::api::ScrobbleRequest,
     lofigirl_shared_common::api::SessionRequest,
     lofigirl_shared_common::api::SessionResponse,
     lofigirl_shared_common::api::TokenRequest,
     lofigirl_shared_common::api::TokenResponse,
+    lofigirl_shared_common::api::{SocketMessage, WorkerState, SOCKET_PROTOCOL_VERSION},
     lofigirl_shared_common::config::ConfigError,
     lofigirl_shared_common::config::LastFMClientPasswordConfig,
     lofigirl_shared_common::config::LastFMClientSessionConfig,
-    lofigirl_shared_common::jwt::JWTClaims,
+    lofigirl_shared_common::encrypt::install_server_public_key,
+    lofigirl_shared_common::ENCRYPTION_KEY_END_POINT,
     lofigirl_shared_common::LASTFM_SESSION_END_POINT,
     lofigirl_shared_common::SEND_END_POINT,
     lofigirl_shared_common::TOKEN_END_POINT,
+    lofigirl_shared_common::TOKEN_REFRESH_END_POINT,
     lofigirl_shared_common::{CLIENT_PING_INTERVAL, TRACK_SOCKET_END_POINT},
-    reqwest::Client,
+    reqwest::{Client, StatusCode},
     reqwest_websocket::{Message, RequestBuilderExt},
     tracing::info,
 };
 
 #[cfg(feature = "standalone")]
 use {
+    lofigirl_shared_common::config::OverlayProfile,
     lofigirl_shared_common::{FAST_TRY_INTERVAL, REGULAR_INTERVAL},
-    lofigirl_shared_listen::listener::Listener,
+    lofigirl_shared_listen::listener::{Listener, PendingListen, Service},
     lofigirl_sys::image::ImageProcessor,
-    tracing::warn,
 };
 #[cfg(feature = "notify")]
 use {notify_rust::Notification, notify_rust::Timeout};
@@ -47,13 +70,24 @@ pub struct Worker {
     requested_url: String,
     track_send_url: String,
     track_socket_url: String,
-    token: String,
+    token_refresh_url: String,
+    tokens: Mutex<SessionTokens>,
+    outbox: Mutex<Outbox>,
+    config_file: PathBuf,
+}
+
+#[cfg(not(feature = "standalone"))]
+struct SessionTokens {
+    access_token: String,
+    refresh_token: String,
 }
 
 #[cfg(feature = "standalone")]
 pub struct Worker {
     listener: Listener,
     url: Url,
+    profile: OverlayProfile,
+    outbox: Mutex<Outbox>,
 }
 
 impl Worker {
@@ -70,10 +104,37 @@ impl Worker {
             .appname("lofigirl")
             .timeout(Timeout::Milliseconds(6000))
             .show()?;
+        // failed listens are queued and sent again later
+        let listened_at = chrono::Utc::now().timestamp();
         #[cfg(feature = "standalone")]
-        self.listener.send_listen(track)?;
+        {
+            let report = self
+                .listener
+                .send_listen_report_at(track, listened_at)
+                .await;
+            let failures = report.failures();
+            if !failures.is_empty() {
+                let mut outbox = self.outbox.lock().await;
+                for (service, e) in failures {
+                    warn!("Cannot send listen to {}, queued: {}", service.as_str(), e);
+                    outbox
+                        .queue(track, listened_at, Some(service.as_str()))
+                        .await;
+                }
+            }
+        }
         #[cfg(not(feature = "standalone"))]
-        self.post_track(track, Action::Listened).await?;
+        if let Err(e) = self
+            .post_track(track, Action::Listened, Some(listened_at))
+            .await
+        {
+            warn!("Cannot send listen, queued: {}", e);
+            self.outbox
+                .lock()
+                .await
+                .queue(track, listened_at, None)
+                .await;
+        }
         Ok(())
     }
 
@@ -85,45 +146,137 @@ impl Worker {
             .appname("lofigirl")
             .timeout(Timeout::Milliseconds(6000))
             .show()?;
+        // now playing is only useful now, so it is not queued
         #[cfg(feature = "standalone")]
-        self.listener.send_now_playing(track)?;
+        let sent = self.listener.send_now_playing(track).await;
         #[cfg(not(feature = "standalone"))]
-        self.post_track(track, Action::PlayingNow).await?;
+        let sent = self.post_track(track, Action::PlayingNow, None).await;
+        if let Err(e) = sent {
+            warn!("Cannot send now playing: {}", e);
+        }
         Ok(())
     }
+
+    /// Sends the queued listens which are due again, in batches per service.
+    async fn retry_queued(&self) {
+        let mut outbox = self.outbox.lock().await;
+        let due = outbox.take_due();
+        if due.is_empty() {
+            return;
+        }
+        let mut by_service: BTreeMap<Option<String>, Vec<QueuedListen>> = BTreeMap::new();
+        for listen in due {
+            by_service
+                .entry(listen.service.clone())
+                .or_default()
+                .push(listen);
+        }
+        for (service, listens) in by_service {
+            let mut failed = false;
+            for batch in listens.chunks(QUEUED_BATCH_SIZE) {
+                // the rest waits for the next retry once a batch fails
+                let unsent = if failed {
+                    batch
+                } else {
+                    match self.send_queued(service.as_deref(), batch).await {
+                        Ok(()) => {
+                            outbox.release(service.as_deref());
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Cannot send queued listens: {}", e);
+                            failed = true;
+                            // the listens sent before the failure are not sent again
+                            split_sent(batch, e.sent).1
+                        }
+                    }
+                };
+                outbox.postpone(unsent.to_vec());
+            }
+        }
+        outbox.save().await;
+    }
+}
+
+/// Loads the outbox named in the config.
+async fn load_outbox(config: &Config) -> Result<Outbox> {
+    let path = config
+        .outbox
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_OUTBOX_FILE));
+    Outbox::load(path).await
 }
 
 #[cfg(feature = "standalone")]
 impl Worker {
-    pub async fn new(config: &mut Config, url: Url) -> Result<(Worker, bool)> {
+    pub async fn new(config: &mut Config, stream_id: &StreamId) -> Result<(Worker, bool)> {
         let mut config_changed = false;
         let lastfm_session_config = if let Some(client) = &config.lastfm {
             if let Some(api) = &config.lastfm_api {
                 config_changed = true;
-                Some(Listener::convert_client_to_session(api, client)?)
+                Some(Listener::convert_client_to_session(api, client).await?)
             } else {
                 None
             }
         } else {
             None
         };
-        let mut listener = Listener::default();
+        let mut listener = Listener::new()?;
         if let Some(session) = lastfm_session_config {
             config.lastfm = Some(LastFMClientConfig::SessionAuth(session.to_owned()));
             if let Some(api) = &config.lastfm_api {
-                listener.set_lastfm_listener(api, &LastFMClientConfig::SessionAuth(session))?;
+                listener
+                    .set_lastfm_listener(api, &LastFMClientConfig::SessionAuth(session))
+                    .await?;
             }
         }
         if let Some(listenbrainz) = &config.listenbrainz {
-            listener.set_listenbrainz_listener(listenbrainz)?;
+            listener.set_listenbrainz_listener(listenbrainz).await?;
         }
-        Ok((Worker { listener, url }, config_changed))
+        let profile = config
+            .overlay
+            .as_ref()
+            .map(|overlay| overlay.profile_for(stream_id.as_str()))
+            .unwrap_or_default();
+        let outbox = load_outbox(config).await?;
+        Ok((
+            Worker {
+                listener,
+                url: stream_id.url(),
+                profile,
+                outbox: Mutex::new(outbox),
+            },
+            config_changed,
+        ))
+    }
+
+    async fn send_queued(
+        &self,
+        service: Option<&str>,
+        listens: &[QueuedListen],
+    ) -> Result<(), SendListensError> {
+        let service = service
+            .unwrap_or_default()
+            .parse::<Service>()
+            .map_err(|e| SendListensError {
+                sent: 0,
+                error: e.into(),
+            })?;
+        let listens: Vec<PendingListen> = listens
+            .iter()
+            .map(|listen| PendingListen {
+                track: listen.track.clone(),
+                listened_at: listen.listened_at,
+            })
+            .collect();
+        self.listener.send_listens(service, &listens).await
     }
 
     async fn work_with_connection(&self) -> anyhow::Result<()> {
-        let mut image_proc = ImageProcessor::new(self.url.clone())?;
+        let mut image_proc = ImageProcessor::new(self.url.clone(), &self.profile)?;
         let mut current_track: Track = Track::default();
         loop {
+            self.retry_queued().await;
             match image_proc.next_track().await {
                 Ok(next_track) => {
                     if current_track != next_track {
@@ -146,7 +299,7 @@ impl Worker {
 
 #[cfg(not(feature = "standalone"))]
 impl Worker {
-    pub async fn new(config: &mut Config, requested_url: Url) -> Result<(Worker, bool)> {
+    pub async fn new(config: &mut Config, stream_id: &StreamId) -> Result<(Worker, bool)> {
         let mut config_changed = false;
         let client = Client::new();
         let base_url = config
@@ -157,8 +310,10 @@ impl Worker {
             .as_str()
             .to_owned();
 
-        let token = Worker::get_token(config, &client, &base_url, &mut config_changed).await?;
+        Worker::fetch_encryption_key(&client, &base_url).await?;
+        let tokens = Worker::get_tokens(config, &client, &base_url, &mut config_changed).await?;
         let track_send_url = format!("{}{}", base_url, SEND_END_POINT);
+        let token_refresh_url = format!("{}{}", base_url, TOKEN_REFRESH_END_POINT);
         info!("Client worker initialized");
 
         // ws socket url
@@ -174,28 +329,56 @@ impl Worker {
             bail!("Cannot work on outside http/https")
         }
         let track_socket_url = url.join(TRACK_SOCKET_END_POINT)?.into();
+        let outbox = load_outbox(config).await?;
 
         Ok((
             Worker {
                 client,
-                requested_url: requested_url.into(),
+                requested_url: stream_id.url().into(),
                 track_send_url,
                 track_socket_url,
-                token,
+                token_refresh_url,
+                tokens: Mutex::new(tokens),
+                outbox: Mutex::new(outbox),
+                config_file: config.file.clone(),
             },
             config_changed,
         ))
     }
 
-    async fn get_token(
+    async fn fetch_encryption_key(client: &Client, base_url: &str) -> Result<()> {
+        let key = client
+            .get(&format!("{}{}", base_url, ENCRYPTION_KEY_END_POINT))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<EncryptionKeyResponse>()
+            .await?;
+        install_server_public_key(&key.key_id, &key.public_key_base64)?;
+        Ok(())
+    }
+
+    async fn get_tokens(
         config: &mut Config,
         client: &Client,
         base_url: &str,
         config_changed: &mut bool,
-    ) -> anyhow::Result<String> {
-        let token_config = config.session.take();
-        let token = match token_config {
-            Some(token) => token.token,
+    ) -> anyhow::Result<SessionTokens> {
+        let refreshed = match config.session.take() {
+            Some(session) => {
+                let refresh_url = format!("{}{}", base_url, TOKEN_REFRESH_END_POINT);
+                match Worker::refresh_tokens(client, &refresh_url, &session.refresh_token).await {
+                    Ok(tokens) => Some(tokens),
+                    Err(e) => {
+                        warn!("Cannot refresh the session, requesting a new one: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let tokens = match refreshed {
+            Some(tokens) => tokens,
             None => {
                 let lastfm_session_config = if let Some(lastfm) = &config.lastfm {
                     match &lastfm {
@@ -230,49 +413,120 @@ impl Worker {
                     None
                 };
                 let listenbrainz_token = config.listenbrainz.as_ref().map(|l| l.token.to_owned());
-                let token =
-                    Worker::request_token(client, lastfm_session_key, listenbrainz_token, base_url)
-                        .await?;
-                *config_changed = true;
-                config.session = Some(TokenConfig {
-                    token: token.clone(),
-                });
-                token
+                Worker::request_tokens(client, lastfm_session_key, listenbrainz_token, base_url)
+                    .await?
             }
         };
-        Ok(token)
+        // Refresh tokens are single use, so the new one always needs to be stored
+        *config_changed = true;
+        config.session = Some(TokenConfig {
+            refresh_token: tokens.refresh_token.clone(),
+        });
+        Ok(tokens)
     }
 
-    async fn request_token(
+    async fn request_tokens(
         client: &Client,
         lastfm_session_key: Option<String>,
         listenbrainz_token: Option<String>,
         base_url: &str,
-    ) -> Result<String> {
+    ) -> Result<SessionTokens> {
         let token_response = client
             .post(&format!("{}{}", base_url, TOKEN_END_POINT))
             .json(&TokenRequest {
                 secure_lastfm_session_key: lastfm_session_key.map(|s| s.into()),
                 secure_listenbrainz_token: listenbrainz_token.map(|s| s.into()),
+                label: Some(env!("CARGO_PKG_NAME").to_owned()),
             })
             .send()
             .await?
+            .error_for_status()?
             .json::<TokenResponse>()
             .await?;
-        Ok(token_response.secure_token.into())
+        Ok(token_response.into())
     }
 
-    async fn post_track(&self, track: &Track, action: Action) -> Result<()> {
-        let jwt_token = JWTClaims::encode(self.token.to_owned())?;
-        self.client
-            .post(&self.track_send_url)
-            .header("Authorization", "Bearer ".to_owned() + &jwt_token)
-            .json(&ScrobbleRequest {
-                action,
-                track: track.to_owned(),
+    async fn refresh_tokens(
+        client: &Client,
+        refresh_url: &str,
+        refresh_token: &str,
+    ) -> Result<SessionTokens> {
+        let token_response = client
+            .post(refresh_url)
+            .json(&RefreshRequest {
+                secure_refresh_token: refresh_token.to_owned().into(),
             })
             .send()
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await?;
+        Ok(token_response.into())
+    }
+
+    /// Sends the action to the server. Succeeds if the server has sent the
+    /// action or queued it to send later.
+    async fn post_track(
+        &self,
+        track: &Track,
+        action: Action,
+        listened_at: Option<i64>,
+    ) -> Result<()> {
+        let mut tokens = self.tokens.lock().await;
+        let request = ScrobbleRequest {
+            action,
+            track: track.to_owned(),
+            stream_url: Some(self.requested_url.clone()),
+            listened_at,
+        };
+        let mut response = self
+            .client
+            .post(&self.track_send_url)
+            .bearer_auth(&tokens.access_token)
+            .json(&request)
+            .send()
             .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            // Access token has expired, get a new pair and try once more
+            info!("Access token has expired, refreshing");
+            *tokens = Worker::refresh_tokens(
+                &self.client,
+                &self.token_refresh_url,
+                &tokens.refresh_token,
+            )
+            .await?;
+            let session = TokenConfig {
+                refresh_token: tokens.refresh_token.clone(),
+            };
+            if let Err(e) = Config::store_session(&self.config_file, session).await {
+                warn!(
+                    "Cannot store the refreshed session in {}: {}",
+                    self.config_file.display(),
+                    e
+                );
+            }
+            response = self
+                .client
+                .post(&self.track_send_url)
+                .bearer_auth(&tokens.access_token)
+                .json(&request)
+                .send()
+                .await?;
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    async fn send_queued(
+        &self,
+        _service: Option<&str>,
+        listens: &[QueuedListen],
+    ) -> Result<(), SendListensError> {
+        for (sent, listen) in listens.iter().enumerate() {
+            self.post_track(&listen.track, Action::Listened, Some(listen.listened_at))
+                .await
+                .map_err(|error| SendListensError { sent, error })?;
+        }
         Ok(())
     }
 
@@ -307,8 +561,17 @@ impl Worker {
         let (mut tx, mut rx) = websocket.split();
         let mut current_track = Track::default();
 
-        // Send initial Url message
-        tx.send(Message::Text(self.requested_url.clone())).await?;
+        // Greet the server and follow the requested stream
+        let hello = SocketMessage::Hello {
+            version: SOCKET_PROTOCOL_VERSION,
+        };
+        tx.send(Message::Text(serde_json::to_string(&hello)?))
+            .await?;
+        let subscribe = SocketMessage::Subscribe {
+            stream_url: self.requested_url.clone(),
+        };
+        tx.send(Message::Text(serde_json::to_string(&subscribe)?))
+            .await?;
 
         // Setup periodic ping message
         tokio::spawn(async move {
@@ -320,18 +583,47 @@ impl Worker {
             }
         });
 
+        self.retry_queued().await;
         while let Some(message) = rx.try_next().await? {
-            if let Message::Text(text) = message {
-                let next_track: Track = serde_json::from_str(&text)?;
-                if !current_track.is_empty() {
-                    info!("Sent listen for: \"{}\"", current_track);
-                    self.send_listen(&current_track).await?;
+            self.retry_queued().await;
+            let Message::Text(text) = message else {
+                continue;
+            };
+            match serde_json::from_str(&text)? {
+                SocketMessage::Track {
+                    track: next_track, ..
+                } => {
+                    if !current_track.is_empty() {
+                        info!("Sent listen for: \"{}\"", current_track);
+                        self.send_listen(&current_track).await?;
+                    }
+                    info!("Sent now playing info for: \"{}\"", next_track);
+                    self.send_now_playing(&next_track).await?;
+                    current_track = next_track;
                 }
-                info!("Sent now playing info for: \"{}\"", next_track);
-                self.send_now_playing(&next_track).await?;
-                current_track = next_track;
+                SocketMessage::StreamStatus {
+                    state, last_error, ..
+                } => match last_error {
+                    Some(error) if state == WorkerState::Failing => {
+                        warn!("Server cannot read the stream: {}", error)
+                    }
+                    _ => info!("Stream is {:?} on the server", state),
+                },
+                // the only stream of this connection cannot be followed
+                SocketMessage::Error { error, .. } => bail!(error),
+                _ => {}
             }
         }
         Ok(())
     }
 }
+
+#[cfg(not(feature = "standalone"))]
+impl From<TokenResponse> for SessionTokens {
+    fn from(response: TokenResponse) -> Self {
+        SessionTokens {
+            access_token: response.access_token,
+            refresh_token: response.secure_refresh_token.into(),
+        }
+    }
+}
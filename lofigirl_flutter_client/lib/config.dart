@@ -25,10 +25,35 @@ class TokenRequest {
     if (listenbrainzToken != null) {
       data['secure_listenbrainz_token'] = listenbrainzToken?.toJson();
     }
+    data['label'] = 'lofigirl_flutter_client';
     return data;
   }
 }
 
+class RefreshRequest {
+  final SecureString refreshToken;
+  RefreshRequest(this.refreshToken);
+
+  Map<String, dynamic> toJson() => {
+        'secure_refresh_token': refreshToken.toJson(),
+      };
+}
+
+class TokenResponse {
+  final String accessToken;
+  final int expiresIn;
+  final SecureString refreshToken;
+
+  TokenResponse(this.accessToken, this.expiresIn, this.refreshToken);
+
+  factory TokenResponse.fromJson(Map<String, dynamic> json) {
+    return TokenResponse(
+        json['access_token'] as String,
+        json['expires_in'] as int,
+        SecureString.fromJson(json['secure_refresh_token']));
+  }
+}
+
 class Track {
   final String artist;
   final String song;
@@ -45,14 +70,29 @@ class Track {
   }
 }
 
+/// Version of the `/track_ws` protocol this client speaks.
+const socketProtocolVersion = 1;
+
+Map<String, dynamic> socketHello() => {
+      'type': 'Hello',
+      'version': socketProtocolVersion,
+    };
+
+Map<String, dynamic> socketSubscribe(String streamUrl) => {
+      'type': 'Subscribe',
+      'stream_url': streamUrl,
+    };
+
 class ScrobbleRequest {
   final String action;
   final Track track;
+  final String? streamUrl;
 
-  ScrobbleRequest(this.track, this.action);
+  ScrobbleRequest(this.track, this.action, this.streamUrl);
 
   Map<String, dynamic> toJson() => {
         'action': action,
         'track': track.toJson(),
+        'stream_url': streamUrl,
       };
 }
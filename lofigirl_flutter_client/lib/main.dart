@@ -43,7 +43,9 @@ class _LofiGirlState extends State<LofiGirl> {
   String? _lastFmSessionKey;
   String? _listenBrainzToken;
   String? _serverUrl;
+  // refresh token, persisted as the session token
   String? _sessionToken;
+  String? _accessToken;
   bool _isScrobbling = false;
   Track? _currentTrack;
   String? _lastFmUsername;
@@ -97,8 +99,9 @@ class _LofiGirlState extends State<LofiGirl> {
     _channel = WebSocketChannel.connect(
       socketUrl,
     );
-    // send initial messageu
-    _channel?.sink.add('$_streamUrl');
+    // greet the server and follow the stream
+    _channel?.sink.add(json.encode(socketHello()));
+    _channel?.sink.add(json.encode(socketSubscribe('$_streamUrl')));
     // set periodic ping
     _pingTimerHandle = Timer.periodic(const Duration(seconds: 30), (timer) {
       developer.log('Pinging socket with binary data');
@@ -106,7 +109,18 @@ class _LofiGirlState extends State<LofiGirl> {
     });
     _socketStreamHandle = _channel?.stream.listen(
       (dynamic message) {
-        final nextTrack = Track.fromJson(json.decode(message));
+        final socketMessage = json.decode(message) as Map<String, dynamic>;
+        switch (socketMessage['type']) {
+          case 'Track':
+            break;
+          case 'Error':
+            final error = socketMessage['error'] as Map<String, dynamic>;
+            developer.log('ws error ${error['code']}');
+            return;
+          default:
+            return;
+        }
+        final nextTrack = Track.fromJson(socketMessage['track']);
         if (_currentTrack != null) {
           _sendInfo("Listened");
         }
@@ -128,26 +142,65 @@ class _LofiGirlState extends State<LofiGirl> {
     final url = Uri.parse('$_serverUrl/send');
     developer.log('POST $url', name: 'LofiGirl');
     if (url.isAbsolute) {
-      final request = ScrobbleRequest(_currentTrack!, info);
+      final request = ScrobbleRequest(_currentTrack!, info, _streamUrl);
       final body = json.encode(request.toJson());
-      final jwt = JWTClaims(SecureString(_sessionToken!)).toJWT();
-      http
-          .post(url,
-              headers: <String, String>{
-                'Content-Type': 'application/json; charset=UTF-8',
-                'Authorization': 'Bearer $jwt'
-              },
-              body: body)
-          .then((http.Response response) {
-        if (response.statusCode == 200) {
-          developer.log('Info sent correctly', name: 'LofiGirl');
-        } else {
-          developer.log('Error sending scrobble', name: 'LofiGirl');
-        }
-      });
+      if (_accessToken == null) {
+        await _refreshSession();
+      }
+      var response = await _postWithAccessToken(url, body);
+      if (response.statusCode == 401 && await _refreshSession()) {
+        // access token has expired, try once more with the new one
+        response = await _postWithAccessToken(url, body);
+      }
+      if (response.statusCode == 200) {
+        developer.log('Info sent correctly', name: 'LofiGirl');
+      } else {
+        developer.log('Error sending scrobble', name: 'LofiGirl');
+      }
     }
   }
 
+  Future<http.Response> _postWithAccessToken(Uri url, String body) {
+    return http.post(url,
+        headers: <String, String>{
+          'Content-Type': 'application/json; charset=UTF-8',
+          'Authorization': 'Bearer $_accessToken'
+        },
+        body: body);
+  }
+
+  Future<bool> _refreshSession() async {
+    final url = Uri.parse('$_serverUrl/token/refresh');
+    developer.log('POST $url', name: 'LofiGirl');
+    if (_sessionToken == null || !url.isAbsolute) {
+      return false;
+    }
+    await KeyAgreement.ensure(_serverUrl!);
+    final request = RefreshRequest(SecureString(_sessionToken!));
+    final response = await http.post(
+      url,
+      headers: <String, String>{
+        'Content-Type': 'application/json; charset=UTF-8',
+      },
+      body: json.encode(request.toJson()),
+    );
+    if (response.statusCode != 200) {
+      developer.log('Error refreshing session', name: 'LofiGirl');
+      return false;
+    }
+    await _setTokens(TokenResponse.fromJson(json.decode(response.body)));
+    return true;
+  }
+
+  Future<void> _setTokens(TokenResponse tokens) async {
+    final SharedPreferences prefs = await SharedPreferences.getInstance();
+    setState(() {
+      _accessToken = tokens.accessToken;
+      _sessionToken = tokens.refreshToken.value;
+      prefs.setString("sessionToken", tokens.refreshToken.value);
+    });
+  }
+
   void onServerUrlChanged(String value) {
     final url = Uri.parse('$value/health');
     if (url.isAbsolute) {
@@ -233,6 +286,7 @@ class _LofiGirlState extends State<LofiGirl> {
       final url = Uri.parse('$_serverUrl/session');
       developer.log('POST $url', name: 'LofiGirl');
       if (url.isAbsolute) {
+        await KeyAgreement.ensure(_serverUrl!);
         final request = SessionRequest(_lastFmUsername!, SecureString(value));
         final body = json.encode(request.toJson());
         final response = await http.post(
@@ -275,11 +329,11 @@ class _LofiGirlState extends State<LofiGirl> {
   }
 
   Future<bool> onSessionTokenRequested() async {
-    final SharedPreferences prefs = await SharedPreferences.getInstance();
     if (_lastFmSessionKey != null || _listenBrainzToken != null) {
       final url = Uri.parse('$_serverUrl/token');
       developer.log('POST $url', name: 'LofiGirl');
       if (url.isAbsolute) {
+        await KeyAgreement.ensure(_serverUrl!);
         final request = TokenRequest(
             _lastFmSessionKey != null ? SecureString(_lastFmSessionKey!) : null,
             _listenBrainzToken != null
@@ -294,12 +348,7 @@ class _LofiGirlState extends State<LofiGirl> {
           body: body,
         );
         if (response.statusCode == 200) {
-          final secureToken =
-              SecureString.fromJson(json.decode(response.body)["secure_token"]);
-          setState(() {
-            _sessionToken = secureToken.value;
-            prefs.setString("sessionToken", secureToken.value);
-          });
+          await _setTokens(TokenResponse.fromJson(json.decode(response.body)));
           const snackBar = SnackBar(
             content: Text('Session token is set!'),
           );
@@ -317,6 +366,7 @@ class _LofiGirlState extends State<LofiGirl> {
     final SharedPreferences prefs = await SharedPreferences.getInstance();
     setState(() {
       _sessionToken = null;
+      _accessToken = null;
       prefs.remove('sessionToken');
     });
   }
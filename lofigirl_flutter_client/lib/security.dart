@@ -1,8 +1,49 @@
+import 'dart:convert';
+
+import 'package:cryptography/cryptography.dart' as crypto;
 import 'package:encrypt/encrypt.dart';
-import 'package:dart_jsonwebtoken/dart_jsonwebtoken.dart';
+import 'package:http/http.dart' as http;
+
+/// Key agreed with the server via X25519, used by every SecureString.
+class KeyAgreement {
+  static String? _keyId;
+  static String? _serverPublicKeyBase64;
+  static String? _publicKeyBase64;
+  static Key? _aesKey;
 
-class Secret {
-  static const String aesKey = "MTIzNDU2NzgxMjM0NTY3ODEyMzQ1Njc4MTIzNDU2Nzg=";
+  /// Fetches the public key of the server and derives the shared key.
+  /// The derived key is kept as long as the server key stays the same.
+  static Future<void> ensure(String serverUrl) async {
+    final url = Uri.parse('$serverUrl/key');
+    final response = await http.get(url);
+    if (response.statusCode != 200) {
+      throw Exception('Cannot fetch the server key');
+    }
+    final body = json.decode(response.body);
+    final keyId = body['key_id'] as String;
+    final serverPublicKeyBase64 = body['public_key_base64'] as String;
+    if (keyId == _keyId && serverPublicKeyBase64 == _serverPublicKeyBase64) {
+      return;
+    }
+    final algorithm = crypto.X25519();
+    final keyPair = await algorithm.newKeyPair();
+    final publicKey = await keyPair.extractPublicKey();
+    final sharedSecret = await algorithm.sharedSecretKey(
+      keyPair: keyPair,
+      remotePublicKey: crypto.SimplePublicKey(
+          base64.decode(serverPublicKeyBase64),
+          type: crypto.KeyPairType.x25519),
+    );
+    final hkdf = crypto.Hkdf(hmac: crypto.Hmac.sha256(), outputLength: 32);
+    final derived = await hkdf.deriveKey(
+      secretKey: sharedSecret,
+      info: utf8.encode('lofigirl secure string'),
+    );
+    _keyId = keyId;
+    _serverPublicKeyBase64 = serverPublicKeyBase64;
+    _publicKeyBase64 = base64.encode(publicKey.bytes);
+    _aesKey = Key.fromBase64(base64.encode(await derived.extractBytes()));
+  }
 }
 
 class SecureString {
@@ -13,12 +54,17 @@ class SecureString {
   Map<String, dynamic> toJson() {
     final (encryptedBase64, nonceBase64) = AesHelper.encrypt(value);
     return {
+      'key_id': KeyAgreement._keyId,
+      'public_key_base64': KeyAgreement._publicKeyBase64,
       'encrypted_base64': encryptedBase64,
       'nonce_base64': nonceBase64,
     };
   }
 
   factory SecureString.fromJson(Map<String, dynamic> json) {
+    if (json['key_id'] != KeyAgreement._keyId) {
+      throw Exception('Unknown encryption key');
+    }
     final encryptedBase64 = json['encrypted_base64'] as String;
     final nonceBase64 = json['nonce_base64'] as String;
     final value = AesHelper.decrypt(encryptedBase64, nonceBase64);
@@ -27,34 +73,25 @@ class SecureString {
 }
 
 class AesHelper {
+  static Key _key() {
+    final key = KeyAgreement._aesKey;
+    if (key == null) {
+      throw Exception('Encryption key is not agreed with the server');
+    }
+    return key;
+  }
+
   static (String, String) encrypt(String value) {
-    final key = Key.fromBase64(Secret.aesKey);
-    final nonce = IV.fromLength(12);
-    final encrypter = Encrypter(AES(key, mode: AESMode.gcm));
+    final nonce = IV.fromSecureRandom(12);
+    final encrypter = Encrypter(AES(_key(), mode: AESMode.gcm));
     final encrypted = encrypter.encrypt(value, iv: nonce);
     return (encrypted.base64, nonce.base64);
   }
 
   static String decrypt(String encryptedBase64, String nonceBase64) {
-    final key = Key.fromBase64(Secret.aesKey);
     final nonce = IV.fromBase64(nonceBase64);
-    final encrypter = Encrypter(AES(key, mode: AESMode.gcm));
+    final encrypter = Encrypter(AES(_key(), mode: AESMode.gcm));
     final encrypted = Encrypted.from64(encryptedBase64);
     return encrypter.decrypt(encrypted, iv: nonce);
   }
 }
-
-class JWTClaims {
-  final SecureString value;
-
-  JWTClaims(this.value);
-
-  String toJWT() {
-    final secretKey = SecretKey(Secret.aesKey);
-    final jsonValue = value.toJson();
-    final jwt = JWT({"secure_token": jsonValue});
-    final token = jwt.sign(secretKey,
-        expiresIn: const Duration(hours: 1), notBefore: const Duration());
-    return token;
-  }
-}
@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use chrono::DateTime;
+use lofigirl_shared_common::config::OverlayConfig;
+use lofigirl_sys::deps::check_dependencies;
+
+use crate::session::TokenDB;
+
+pub async fn migrate(token_db: &TokenDB) -> Result<()> {
+    token_db.migrate().await?;
+    println!("Database is up to date");
+    Ok(())
+}
+
+pub async fn list_tokens(token_db: &TokenDB) -> Result<()> {
+    println!(
+        "{:>6}  {:<24}  {:<20}  {:<20}  revoked",
+        "id", "label", "created", "last used"
+    );
+    for token in token_db.get_all_tokens().await? {
+        println!(
+            "{:>6}  {:<24}  {:<20}  {:<20}  {}",
+            token.id,
+            token.label.as_deref().unwrap_or("-"),
+            format_timestamp(Some(token.created_at)),
+            format_timestamp(token.last_used_at),
+            token.revoked
+        );
+    }
+    Ok(())
+}
+
+pub async fn revoke_token(token_db: &TokenDB, id: i64) -> Result<()> {
+    if !token_db.revoke_token_by_id(id).await? {
+        bail!("Token {} is not found", id);
+    }
+    println!("Token {} is revoked", id);
+    Ok(())
+}
+
+pub async fn list_streams(token_db: &TokenDB) -> Result<()> {
+    println!("{:<12}  {:>11}  url", "id", "subscribers");
+    for (stream_id, stream_url) in token_db.get_subscribed_streams().await? {
+        let subscribers = token_db.get_stream_subscribers(&stream_id).await?;
+        println!(
+            "{:<12}  {:>11}  {}",
+            stream_id,
+            subscribers.len(),
+            stream_url
+        );
+    }
+    Ok(())
+}
+
+pub async fn vacuum(token_db: &TokenDB) -> Result<()> {
+    token_db.vacuum().await?;
+    println!("Database is vacuumed");
+    Ok(())
+}
+
+pub async fn backup(token_db: &TokenDB, file: &Path) -> Result<()> {
+    if file.try_exists()? {
+        bail!("{} already exists", file.display());
+    }
+    let filename = file
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a valid path", file.display()))?;
+    token_db.backup(filename).await?;
+    println!("Database is backed up to {}", file.display());
+    Ok(())
+}
+
+/// Prints every dependency check and fails if any of them has failed.
+pub fn check_deps(overlay: &OverlayConfig) -> Result<()> {
+    let mut failed = 0;
+    for check in check_dependencies(overlay) {
+        match check.result {
+            Ok(details) => println!("[ok]     {}: {}", check.name, details),
+            Err(e) => {
+                failed += 1;
+                println!("[failed] {}: {}", check.name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} dependency check(s) failed", failed);
+    }
+    Ok(())
+}
+
+fn format_timestamp(timestamp: Option<i64>) -> String {
+    timestamp
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "-".to_owned())
+}
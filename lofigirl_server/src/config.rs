@@ -1,7 +1,7 @@
 use std::path::Path;
 
 use anyhow::Result;
-use lofigirl_shared_common::config::{LastFMApiConfig, ServerSettingsConfig};
+use lofigirl_shared_common::config::{LastFMApiConfig, OverlayConfig, ServerSettingsConfig};
 use serde::Deserialize;
 use tracing::info;
 
@@ -9,6 +9,61 @@ use tracing::info;
 pub struct ServerConfig {
     pub lastfm_api: Option<LastFMApiConfig>,
     pub server_settings: ServerSettingsConfig,
+    #[serde(default)]
+    pub overlay: OverlayConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// Streams the server follows. Without `streams` and `channels` every stream
+/// is allowed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// Video ids or links, or id patterns with `*` and `?`.
+    pub streams: Vec<String>,
+    /// Channel ids (`UC...`) or handles (`@name`) whose videos are allowed.
+    pub channels: Vec<String>,
+    /// Streams started at boot and kept running without clients. They are
+    /// allowed as well.
+    pub always_on: Vec<String>,
+}
+
+/// Request budgets of the public endpoints. Every field has a default, and a
+/// budget of 0 denies every request.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Requests per minute from an IP address on `/token`, `/session`,
+    /// `/send`, `/track`, `/track_ws` and `/streams/{id}/events`.
+    pub ip_per_minute: u32,
+    pub ip_burst: u32,
+    /// Requests per minute with a token on `/send`.
+    pub token_per_minute: u32,
+    pub token_burst: u32,
+    /// Streams without a worker an IP address can start per hour.
+    pub new_streams_per_hour: u32,
+    /// Workers running at once. Streams with subscriptions are started at boot regardless.
+    pub max_workers: usize,
+    /// Reads the client address from the `Forwarded` and `X-Forwarded-For`
+    /// headers. Only enable behind a reverse proxy which sets them.
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            ip_per_minute: 60,
+            ip_burst: 20,
+            token_per_minute: 30,
+            token_burst: 10,
+            new_streams_per_hour: 5,
+            max_workers: 10,
+            trust_proxy_headers: false,
+        }
+    }
 }
 
 impl ServerConfig {
@@ -0,0 +1,125 @@
+use aes_gcm::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, KeyInit as _, Nonce,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const ENCRYPTION_INFO: &[u8] = b"lofigirl data encryption";
+const LOOKUP_INFO: &[u8] = b"lofigirl data lookup";
+
+/// Encrypts the credentials stored in the token database. Both the AEAD key
+/// and the lookup hash key are derived from a single configured data key.
+pub struct DataKey {
+    cipher: Aes256Gcm,
+    lookup_key: [u8; 32],
+}
+
+impl DataKey {
+    pub fn new(key: &[u8; 32]) -> anyhow::Result<DataKey> {
+        let hkdf = Hkdf::<Sha256>::new(None, key);
+        let mut encryption_key = [0u8; 32];
+        hkdf.expand(ENCRYPTION_INFO, &mut encryption_key)
+            .map_err(anyhow::Error::msg)?;
+        let mut lookup_key = [0u8; 32];
+        hkdf.expand(LOOKUP_INFO, &mut lookup_key)
+            .map_err(anyhow::Error::msg)?;
+        Ok(DataKey {
+            cipher: Aes256Gcm::new(&encryption_key.into()),
+            lookup_key,
+        })
+    }
+
+    /// Returns the ciphertext and its nonce. `column` is bound as associated
+    /// data so that a value cannot be moved to another column.
+    pub fn encrypt(&self, column: &str, value: &str) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value.as_bytes(),
+                    aad: column.as_bytes(),
+                },
+            )
+            .map_err(anyhow::Error::msg)?;
+        Ok((encrypted, nonce.to_vec()))
+    }
+
+    pub fn decrypt(&self, column: &str, encrypted: &[u8], nonce: &[u8]) -> anyhow::Result<String> {
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: encrypted,
+                    aad: column.as_bytes(),
+                },
+            )
+            .map_err(anyhow::Error::msg)?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    /// Keyed hash of the value to look rows up by, hex encoded.
+    pub fn lookup_hash(&self, column: &str, value: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.lookup_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(column.as_bytes());
+        mac.update(b"\0");
+        mac.update(value.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_key(byte: u8) -> DataKey {
+        DataKey::new(&[byte; 32]).unwrap()
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let key = data_key(1);
+        let (encrypted, nonce) = key.encrypt("lastfm.session_key", "secret").unwrap();
+        assert_ne!(encrypted, b"secret");
+        assert_eq!(
+            key.decrypt("lastfm.session_key", &encrypted, &nonce)
+                .unwrap(),
+            "secret"
+        );
+        // a fresh nonce is drawn for every value
+        let (again, _) = key.encrypt("lastfm.session_key", "secret").unwrap();
+        assert_ne!(encrypted, again);
+    }
+
+    #[test]
+    fn rejects_a_value_moved_to_another_column() {
+        let key = data_key(1);
+        let (encrypted, nonce) = key.encrypt("lastfm.session_key", "secret").unwrap();
+        assert!(key
+            .decrypt("listenbrainz.token", &encrypted, &nonce)
+            .is_err());
+        assert!(data_key(2)
+            .decrypt("lastfm.session_key", &encrypted, &nonce)
+            .is_err());
+    }
+
+    #[test]
+    fn hashes_the_same_value_the_same_way_per_column() {
+        let key = data_key(1);
+        let hash = key.lookup_hash("lastfm.session_key", "value");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, data_key(1).lookup_hash("lastfm.session_key", "value"));
+        assert_ne!(hash, key.lookup_hash("listenbrainz.token", "value"));
+        assert_ne!(hash, key.lookup_hash("lastfm.session_key", "other"));
+        assert_ne!(hash, data_key(2).lookup_hash("lastfm.session_key", "value"));
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use actix_web::web;
+use lofigirl_shared_common::api::{CheckStatus, DependencyStatus, ReadinessResponse};
+use lofigirl_sys::deps::check_dependencies;
+use tokio_util::sync::CancellationToken;
+
+use crate::webserver::AppState;
+
+/// Workers failing more than this ratio of their recent reads degrade readiness.
+const WORKER_FAILURE_RATE_THRESHOLD: f64 = 0.5;
+/// How often the OCR and capture dependencies are checked again.
+const DEPENDENCY_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Checks the database, the Last.fm API config and the error rates of the
+/// workers. The OCR and capture dependencies are costly to check, so their
+/// last results from [`check_dependencies_periodically`] are used.
+pub async fn readiness(state: &AppState) -> ReadinessResponse {
+    let mut checks = vec![database_check(state).await];
+    checks.extend(state.dependencies.read().iter().cloned());
+    checks.push(lastfm_check(state));
+    checks.push(worker_check(state));
+    ReadinessResponse {
+        ready: checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Failed),
+        checks,
+    }
+}
+
+async fn database_check(state: &AppState) -> DependencyStatus {
+    let (status, details) = match state.token_db.check_writable().await {
+        Ok(()) => (CheckStatus::Ok, "reachable and writable".to_owned()),
+        Err(e) => (CheckStatus::Failed, e.to_string()),
+    };
+    DependencyStatus {
+        name: "database".to_owned(),
+        status,
+        details,
+    }
+}
+
+/// Checks the OCR and capture dependencies again and again, until cancelled.
+/// The first check is done by [`refresh_dependencies`] before the server starts.
+pub async fn check_dependencies_periodically(
+    state: web::Data<AppState>,
+    cancel: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep(DEPENDENCY_CHECK_INTERVAL) => {}
+        }
+        refresh_dependencies(&state).await;
+    }
+}
+
+/// Checks the OCR and capture dependencies and keeps the results for readiness.
+pub async fn refresh_dependencies(state: &AppState) {
+    let checks = dependency_checks(state).await;
+    *state.dependencies.write() = checks;
+}
+
+async fn dependency_checks(state: &AppState) -> Vec<DependencyStatus> {
+    let overlay = state.overlay.clone();
+    // tesseract initialization and yt-dlp are blocking
+    match web::block(move || check_dependencies(&overlay)).await {
+        Ok(checks) => checks
+            .into_iter()
+            .map(|check| {
+                let (status, details) = match check.result {
+                    Ok(details) => (CheckStatus::Ok, details),
+                    Err(e) => (CheckStatus::Failed, e.to_string()),
+                };
+                DependencyStatus {
+                    name: check.name,
+                    status,
+                    details,
+                }
+            })
+            .collect(),
+        Err(e) => vec![DependencyStatus {
+            name: "dependencies".to_owned(),
+            status: CheckStatus::Failed,
+            details: e.to_string(),
+        }],
+    }
+}
+
+fn lastfm_check(state: &AppState) -> DependencyStatus {
+    let (status, details) = match &state.lastfm_api {
+        Some(_) => (CheckStatus::Ok, "configured".to_owned()),
+        None => (
+            CheckStatus::Degraded,
+            "not configured, only ListenBrainz is available".to_owned(),
+        ),
+    };
+    DependencyStatus {
+        name: "Last.fm API".to_owned(),
+        status,
+        details,
+    }
+}
+
+fn worker_check(state: &AppState) -> DependencyStatus {
+    let workers = state.workers.read();
+    let failing: Vec<String> = workers
+        .iter()
+        .filter_map(|(stream_id, status)| {
+            status
+                .failure_rate()
+                .filter(|rate| *rate > WORKER_FAILURE_RATE_THRESHOLD)
+                .map(|rate| format!("{} ({:.0}% failed)", stream_id, rate * 100.0))
+        })
+        .collect();
+    let (status, details) = if failing.is_empty() {
+        (CheckStatus::Ok, format!("{} running", workers.len()))
+    } else {
+        (
+            CheckStatus::Degraded,
+            format!(
+                "{} of {} failing: {}",
+                failing.len(),
+                workers.len(),
+                failing.join(", ")
+            ),
+        )
+    };
+    DependencyStatus {
+        name: "workers".to_owned(),
+        status,
+        details,
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::LazyLock;
+
+use jwt_compact::{
+    alg::{Hs256, Hs256Key},
+    AlgorithmExt as _, Token, UntrustedToken,
+};
+use serde::{Deserialize, Serialize};
+
+pub static ACCESS_TOKEN_DURATION: LazyLock<chrono::Duration> =
+    LazyLock::new(|| chrono::Duration::minutes(15));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AccessClaims {
+    token: String,
+}
+
+/// Issues and verifies the access tokens. The key never leaves the server.
+pub struct JWTSigner {
+    key: Hs256Key,
+}
+
+impl JWTSigner {
+    pub fn new(secret: &[u8]) -> JWTSigner {
+        JWTSigner {
+            key: Hs256Key::new(secret),
+        }
+    }
+
+    pub fn random() -> JWTSigner {
+        JWTSigner::new(&rand::random::<[u8; 32]>())
+    }
+
+    pub fn encode(&self, token: &str) -> anyhow::Result<String> {
+        let time_options = jwt_compact::TimeOptions::default();
+        let claims = jwt_compact::Claims::new(AccessClaims {
+            token: token.to_owned(),
+        })
+        .set_duration_and_issuance(&time_options, *ACCESS_TOKEN_DURATION)
+        .set_not_before(chrono::Utc::now());
+        let header = jwt_compact::Header::empty();
+        let token_string = Hs256.token(&header, &claims, &self.key)?;
+        Ok(token_string)
+    }
+
+    pub fn decode(&self, encoded: &str) -> anyhow::Result<String> {
+        let time_options = jwt_compact::TimeOptions::default();
+        let token = UntrustedToken::new(encoded)?;
+        let token: Token<AccessClaims> = Hs256.validator(&self.key).validate(&token)?;
+        token
+            .claims()
+            .validate_expiration(&time_options)?
+            .validate_maturity(&time_options)?;
+        Ok(token.claims().custom.token.clone())
+    }
+}
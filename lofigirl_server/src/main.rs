@@ -1,26 +1,99 @@
+mod admin;
 mod config;
+mod data_key;
+mod health;
+mod jwt;
+mod metrics;
+mod outbox;
+mod policy;
+mod ratelimit;
 mod session;
-mod util;
+mod subscription;
+mod supervisor;
+mod timeline;
 mod webserver;
 mod worker;
 
 use std::path::PathBuf;
 
 use actix_web::web;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use lofigirl_shared_common::config::ConfigError;
+use lofigirl_shared_common::encrypt::{
+    decode_secret_key, generate_secret_key, install_server_keys,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
 use crate::config::ServerConfig;
+use crate::data_key::DataKey;
+use crate::jwt::JWTSigner;
+use crate::policy::StreamPolicy;
+use crate::ratelimit::RateLimits;
+use crate::session::TokenDB;
 use webserver::{AppState, LofiServer};
 
 const APP_NAME: &str = "lofigirl_server";
+const JWT_SECRET_ENV: &str = "LOFIGIRL_JWT_SECRET";
+const ENCRYPTION_KEYS_ENV: &str = "LOFIGIRL_ENCRYPTION_KEYS";
+const DATA_KEY_ENV: &str = "LOFIGIRL_DATA_KEY";
 
 /// Scrobble the tracks you listen on lofigirl streams.
 #[derive(Parser, Debug)]
 #[clap(name = APP_NAME, author, version, about, long_about = None)]
 struct Opt {
     /// Configuration toml file.
-    #[clap(short, long, value_parser, default_value = "config.toml")]
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value = "config.toml",
+        global = true
+    )]
     config: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start the web server. Default if no command is given.
+    Serve,
+    /// Apply the pending database migrations.
+    Migrate,
+    /// Manage the issued tokens.
+    #[clap(subcommand)]
+    Tokens(TokensCommand),
+    /// Inspect the streams.
+    #[clap(subcommand)]
+    Streams(StreamsCommand),
+    /// Maintain the token database.
+    #[clap(subcommand)]
+    Db(DbCommand),
+    /// Verify that tesseract, tessdata and yt-dlp are available.
+    CheckDeps,
+}
+
+#[derive(Subcommand, Debug)]
+enum TokensCommand {
+    /// List every token.
+    List,
+    /// Revoke the token with the given id.
+    Revoke { id: i64 },
+}
+
+#[derive(Subcommand, Debug)]
+enum StreamsCommand {
+    /// List the streams with subscriptions.
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Rebuild the database file to reclaim unused space.
+    Vacuum,
+    /// Write a copy of the database to the given file.
+    Backup { file: PathBuf },
 }
 
 #[actix_web::main]
@@ -29,10 +102,145 @@ async fn main() -> std::io::Result<()> {
     let opt = Opt::parse();
     let config = ServerConfig::from_toml(&opt.config)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
-    let state = web::Data::new(
-        AppState::new(config.lastfm_api, &config.server_settings.token_db)
-            .await
+    let result = match opt.command.unwrap_or(Command::Serve) {
+        Command::Serve => return serve(config).await,
+        Command::CheckDeps => admin::check_deps(&config.overlay),
+        command => run_admin(command, &config).await,
+    };
+    result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+async fn run_admin(command: Command, config: &ServerConfig) -> anyhow::Result<()> {
+    let token_db = TokenDB::new(&config.server_settings.token_db, data_key(config)?).await?;
+    // only migrate may work on a database which is not on the known schema
+    if !matches!(command, Command::Migrate) {
+        token_db.check_migrated().await?;
+    }
+    match command {
+        Command::Migrate => admin::migrate(&token_db).await,
+        Command::Tokens(TokensCommand::List) => admin::list_tokens(&token_db).await,
+        Command::Tokens(TokensCommand::Revoke { id }) => admin::revoke_token(&token_db, id).await,
+        Command::Streams(StreamsCommand::List) => admin::list_streams(&token_db).await,
+        Command::Db(DbCommand::Vacuum) => admin::vacuum(&token_db).await,
+        Command::Db(DbCommand::Backup { file }) => admin::backup(&token_db, &file).await,
+        Command::Serve | Command::CheckDeps => unreachable!("handled in main"),
+    }
+}
+
+async fn serve(config: ServerConfig) -> std::io::Result<()> {
+    let jwt = match config
+        .server_settings
+        .jwt_secret
+        .clone()
+        .or_else(|| std::env::var(JWT_SECRET_ENV).ok())
+    {
+        Some(secret) => JWTSigner::new(secret.as_bytes()),
+        None => {
+            warn!("No JWT secret is configured, issued access tokens will not survive a restart");
+            JWTSigner::random()
+        }
+    };
+    install_server_keys(
+        encryption_keys(&config)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?,
-    );
-    LofiServer::start(state, config.server_settings.port).await
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let data_key = data_key(&config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let token_db = TokenDB::new(&config.server_settings.token_db, data_key)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    token_db
+        .migrate()
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    // the workers only end their own tracks, so close what a crash left open
+    match token_db
+        .end_dangling_stream_tracks(chrono::Utc::now().timestamp())
+        .await
+    {
+        Ok(0) => {}
+        Ok(ended) => info!("Ended {} track(s) left on air", ended),
+        Err(e) => warn!("Cannot end the tracks left on air: {}", e),
+    }
+    let limits = RateLimits::new(&config.limits);
+    let policy = StreamPolicy::new(&config.policy)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let state = web::Data::new(AppState::new(
+        config.lastfm_api,
+        config.overlay,
+        token_db,
+        jwt,
+        limits,
+        policy,
+    ));
+    health::refresh_dependencies(&state).await;
+    // a stream which cannot be opened yet is retried by the supervisor
+    worker::start_always_on_workers(&state).await;
+    worker::start_subscribed_workers(&state)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let background_cancel = CancellationToken::new();
+    let outbox = actix_rt::spawn(outbox::retry_listens(
+        state.clone(),
+        background_cancel.clone(),
+    ));
+    let dependencies = actix_rt::spawn(health::check_dependencies_periodically(
+        state.clone(),
+        background_cancel.clone(),
+    ));
+    // returns once the server is stopped, e.g. on SIGTERM or SIGINT
+    let result = LofiServer::start(state.clone(), config.server_settings.port).await;
+    background_cancel.cancel();
+    let _ = outbox.await;
+    let _ = dependencies.await;
+    state.supervisor.shutdown().await;
+    result
+}
+
+/// Reads the encryption keys from the config, or from the environment as
+/// comma separated `id:secret` pairs. The first key is the current one.
+fn encryption_keys(config: &ServerConfig) -> anyhow::Result<Vec<(String, [u8; 32])>> {
+    let pairs = match (
+        &config.server_settings.encryption_keys,
+        std::env::var(ENCRYPTION_KEYS_ENV),
+    ) {
+        (Some(keys), _) => keys
+            .iter()
+            .map(|key| (key.id.clone(), key.secret.clone()))
+            .collect(),
+        (None, Ok(keys)) => keys
+            .split(',')
+            .map(|pair| {
+                pair.split_once(':')
+                    .map(|(id, secret)| (id.trim().to_owned(), secret.trim().to_owned()))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("{} should be in id:secret form", ENCRYPTION_KEYS_ENV)
+                    })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        (None, Err(_)) => {
+            warn!("No encryption key is configured, clients need to fetch the key again after a restart");
+            return Ok(vec![(
+                uuid::Uuid::new_v4().to_string(),
+                generate_secret_key(),
+            )]);
+        }
+    };
+    pairs
+        .into_iter()
+        .map(|(id, secret)| Ok((id, decode_secret_key(&secret)?)))
+        .collect()
+}
+
+/// Reads the key for the stored credentials. Unlike the other keys it cannot
+/// be generated, since the credentials would be unreadable after a restart.
+fn data_key(config: &ServerConfig) -> anyhow::Result<DataKey> {
+    let key = config
+        .server_settings
+        .data_key
+        .clone()
+        .or_else(|| std::env::var(DATA_KEY_ENV).ok())
+        .ok_or(ConfigError::EmptyDataKey)?;
+    DataKey::new(&decode_secret_key(&key)?)
 }
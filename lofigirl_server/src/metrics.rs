@@ -0,0 +1,212 @@
+//! Prometheus metrics, served on `/metrics` with the `metrics` feature. The
+//! functions here are no-ops without the feature.
+
+use std::time::Duration;
+
+use actix_web::web;
+use lofigirl_shared_listen::listener::SendReport;
+
+#[cfg(feature = "metrics")]
+use {
+    actix_web::HttpResponse,
+    lofigirl_shared_common::METRICS_END_POINT,
+    prometheus::{
+        register_histogram, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+        Encoder as _, Histogram, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+    },
+    std::sync::LazyLock,
+    std::time::Instant,
+};
+
+#[cfg(feature = "metrics")]
+static TRACK_READS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "lofigirl_track_reads_total",
+        "Track reads from the streams by result",
+        &["result"]
+    )
+    .expect("metric is registered once")
+});
+
+#[cfg(feature = "metrics")]
+static TRACK_READ_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "lofigirl_track_read_duration_seconds",
+        "Time to capture a frame and read the track from it"
+    )
+    .expect("metric is registered once")
+});
+
+#[cfg(feature = "metrics")]
+static ACTIVE_WORKERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("lofigirl_active_workers", "Running stream workers")
+        .expect("metric is registered once")
+});
+
+#[cfg(feature = "metrics")]
+static SOCKET_SUBSCRIBERS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "lofigirl_socket_subscribers",
+        "Connected track websocket clients"
+    )
+    .expect("metric is registered once")
+});
+
+#[cfg(feature = "metrics")]
+static SCROBBLES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "lofigirl_scrobbles_total",
+        "Scrobbles sent by source, service and result",
+        &["source", "service", "result"]
+    )
+    .expect("metric is registered once")
+});
+
+#[cfg(feature = "metrics")]
+static DB_QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "lofigirl_db_query_duration_seconds",
+        "Time spent in the token database by query",
+        &["query"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0]
+    )
+    .expect("metric is registered once")
+});
+
+/// Where a scrobble comes from.
+#[derive(Clone, Copy)]
+pub enum ScrobbleSource {
+    /// `/send` requests of the clients.
+    Client,
+    /// Scrobbles of the server for the subscriptions.
+    Subscription,
+}
+
+impl ScrobbleSource {
+    #[cfg(feature = "metrics")]
+    fn as_str(&self) -> &'static str {
+        match self {
+            ScrobbleSource::Client => "client",
+            ScrobbleSource::Subscription => "subscription",
+        }
+    }
+}
+
+/// Adds the metrics endpoint to the app.
+#[cfg(feature = "metrics")]
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.route(METRICS_END_POINT, web::get().to(metrics));
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn configure(_cfg: &mut web::ServiceConfig) {}
+
+#[cfg(feature = "metrics")]
+async fn metrics() -> actix_web::Result<HttpResponse> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
+#[cfg(feature = "metrics")]
+pub fn observe_track_read(duration: Duration, success: bool) {
+    TRACK_READ_DURATION.observe(duration.as_secs_f64());
+    TRACK_READS
+        .with_label_values(&[if success { "ok" } else { "error" }])
+        .inc();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn observe_track_read(_duration: Duration, _success: bool) {}
+
+#[cfg(feature = "metrics")]
+pub fn worker_started() {
+    ACTIVE_WORKERS.inc();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn worker_started() {}
+
+#[cfg(feature = "metrics")]
+pub fn worker_stopped() {
+    ACTIVE_WORKERS.dec();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn worker_stopped() {}
+
+#[cfg(feature = "metrics")]
+pub fn socket_opened() {
+    SOCKET_SUBSCRIBERS.inc();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn socket_opened() {}
+
+#[cfg(feature = "metrics")]
+pub fn socket_closed() {
+    SOCKET_SUBSCRIBERS.dec();
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn socket_closed() {}
+
+#[cfg(feature = "metrics")]
+pub fn observe_scrobble(source: ScrobbleSource, report: &SendReport) {
+    let services = [
+        ("lastfm", report.lastfm.as_ref()),
+        ("listenbrainz", report.listenbrainz.as_ref()),
+    ];
+    for (service, result) in services {
+        if let Some(result) = result {
+            let result = if result.is_ok() { "sent" } else { "failed" };
+            SCROBBLES
+                .with_label_values(&[source.as_str(), service, result])
+                .inc();
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub fn observe_scrobble(_source: ScrobbleSource, _report: &SendReport) {}
+
+/// Observes the time spent in a database query when dropped.
+#[cfg(feature = "metrics")]
+pub struct DbTimer {
+    query: &'static str,
+    start: Instant,
+}
+
+#[cfg(feature = "metrics")]
+impl DbTimer {
+    pub fn start(query: &'static str) -> DbTimer {
+        DbTimer {
+            query,
+            start: Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        DB_QUERY_DURATION
+            .with_label_values(&[self.query])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub struct DbTimer;
+
+#[cfg(not(feature = "metrics"))]
+impl DbTimer {
+    pub fn start(_query: &'static str) -> DbTimer {
+        DbTimer
+    }
+}
@@ -0,0 +1,108 @@
+use std::time::Duration;
+
+use actix_web::web;
+use lofigirl_shared_common::track::Track;
+use lofigirl_shared_listen::listener::{PendingListen, SendReport};
+use lofigirl_shared_listen::outbox::{oldest_listen_at, split_sent};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::webserver::AppState;
+
+/// How often the outbox is checked for listens due for a retry.
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Queues the listen for every service it has failed on. Returns `false` if
+/// there was no failure or the listen could not be queued.
+pub async fn queue_failed_listen(
+    state: &AppState,
+    token: &str,
+    track: &Track,
+    listened_at: i64,
+    report: &SendReport,
+) -> bool {
+    let failures = report.failures();
+    if failures.is_empty() {
+        return false;
+    }
+    let listen = PendingListen {
+        track: track.clone(),
+        listened_at,
+    };
+    let mut queued = true;
+    for (service, error) in failures {
+        if let Err(e) = state
+            .token_db
+            .queue_listen(token, service, &listen, &error.to_string())
+            .await
+        {
+            warn!("Cannot queue listen for {}: {}", service.as_str(), e);
+            queued = false;
+        }
+    }
+    queued
+}
+
+/// Sends the queued listens again as they are due, until cancelled.
+pub async fn retry_listens(state: web::Data<AppState>, cancel: CancellationToken) {
+    let mut interval = tokio::time::interval(OUTBOX_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        if let Err(e) = retry_due_listens(&state).await {
+            warn!("Cannot retry queued listens: {}", e);
+        }
+    }
+}
+
+async fn retry_due_listens(state: &AppState) -> anyhow::Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    let dropped = state
+        .token_db
+        .drop_listens_before(oldest_listen_at(now))
+        .await?;
+    if dropped > 0 {
+        warn!("Dropped {} queued listens too old to be sent", dropped);
+    }
+    for batch in state.token_db.get_due_listens(now).await? {
+        let listener = match state.listener_for_token(&batch.token).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Cannot create listener for queued listens: {}", e);
+                continue;
+            }
+        };
+        let listens: Vec<PendingListen> = batch
+            .entries
+            .iter()
+            .map(|entry| entry.listen.clone())
+            .collect();
+        match listener.send_listens(batch.service, &listens).await {
+            Ok(()) => {
+                info!(
+                    "Sent {} queued listens to {}",
+                    listens.len(),
+                    batch.service.as_str()
+                );
+                state
+                    .token_db
+                    .complete_listens(&batch, &batch.entries, now)
+                    .await?;
+            }
+            Err(e) => {
+                // the listens sent before the failure are not sent again
+                let (sent, failed) = split_sent(&batch.entries, e.sent);
+                if !sent.is_empty() {
+                    state.token_db.complete_listens(&batch, sent, now).await?;
+                }
+                state
+                    .token_db
+                    .postpone_listens(failed, &e.to_string())
+                    .await?
+            }
+        }
+    }
+    Ok(())
+}
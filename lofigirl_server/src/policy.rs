@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use lofigirl_shared_common::stream::StreamId;
+use lofigirl_sys::channel::video_channel;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::config::PolicyConfig;
+
+/// Channel lookups kept before they are forgotten.
+const MAX_CACHED_CHANNELS: usize = 1_000;
+/// How long a failed channel lookup is answered from the cache, so that
+/// repeated requests do not run it again.
+const FAILED_LOOKUP_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum PolicyError {
+    #[error("Stream is not allowed on this server")]
+    NotAllowed,
+    #[error("Cannot find the channel of the stream: {0}")]
+    UnknownChannel(String),
+}
+
+/// The cached channel lookup of a stream.
+#[derive(Clone)]
+enum ChannelLookup {
+    /// Whether the channel is allowed.
+    Found(bool),
+    Failed {
+        error: String,
+        at: Instant,
+    },
+}
+
+/// Streams clients may start workers for, see [`PolicyConfig`]. Every
+/// stream is allowed if there is neither a stream nor a channel allowlist.
+pub struct StreamPolicy {
+    streams: Vec<String>,
+    channels: Vec<String>,
+    always_on: Vec<StreamId>,
+    /// Whether the channel of each looked up stream is allowed.
+    channel_cache: Mutex<HashMap<String, ChannelLookup>>,
+}
+
+impl StreamPolicy {
+    pub fn new(config: &PolicyConfig) -> anyhow::Result<StreamPolicy> {
+        let always_on = config
+            .always_on
+            .iter()
+            .map(|stream| {
+                stream
+                    .parse::<StreamId>()
+                    .with_context(|| format!("Invalid always-on stream {}", stream))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(StreamPolicy {
+            // links are reduced to their ids, patterns are kept as they are
+            streams: config
+                .streams
+                .iter()
+                .map(|s| {
+                    s.parse::<StreamId>()
+                        .map_or_else(|_| s.trim().to_owned(), String::from)
+                })
+                .collect(),
+            channels: config
+                .channels
+                .iter()
+                .map(|c| c.trim().to_owned())
+                .collect(),
+            always_on,
+            channel_cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Streams started at boot and kept running without subscribers.
+    pub fn always_on(&self) -> &[StreamId] {
+        &self.always_on
+    }
+
+    /// Checks that the stream may be followed. The channel of the stream is
+    /// looked up only if its id is not allowed by itself.
+    pub async fn check(&self, stream_id: &StreamId) -> Result<(), PolicyError> {
+        if self.streams.is_empty() && self.channels.is_empty() {
+            return Ok(());
+        }
+        let id = stream_id.as_str();
+        if self.always_on.contains(stream_id)
+            || self
+                .streams
+                .iter()
+                .any(|pattern| matches_pattern(pattern.as_bytes(), id.as_bytes()))
+        {
+            return Ok(());
+        }
+        if self.channels.is_empty() {
+            return Err(PolicyError::NotAllowed);
+        }
+        let cached = self.channel_cache.lock().get(id).cloned();
+        let allowed = match cached {
+            Some(ChannelLookup::Found(allowed)) => allowed,
+            Some(ChannelLookup::Failed { error, at }) if at.elapsed() < FAILED_LOOKUP_TTL => {
+                return Err(PolicyError::UnknownChannel(error));
+            }
+            _ => {
+                let lookup = match video_channel(&stream_id.url()).await {
+                    Ok(channel) => ChannelLookup::Found(self.channels.iter().any(|allowed| {
+                        *allowed == channel.id
+                            || channel
+                                .handle
+                                .as_ref()
+                                .is_some_and(|handle| handle.eq_ignore_ascii_case(allowed))
+                    })),
+                    Err(e) => ChannelLookup::Failed {
+                        error: e.to_string(),
+                        at: Instant::now(),
+                    },
+                };
+                let mut cache = self.channel_cache.lock();
+                if cache.len() >= MAX_CACHED_CHANNELS {
+                    cache.clear();
+                }
+                cache.insert(id.to_owned(), lookup.clone());
+                match lookup {
+                    ChannelLookup::Found(allowed) => allowed,
+                    ChannelLookup::Failed { error, .. } => {
+                        return Err(PolicyError::UnknownChannel(error));
+                    }
+                }
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(PolicyError::NotAllowed)
+        }
+    }
+}
+
+/// Matches the id against a pattern in which `*` stands for any number of
+/// characters and `?` for a single one.
+fn matches_pattern(pattern: &[u8], id: &[u8]) -> bool {
+    match (pattern.split_first(), id.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            matches_pattern(rest, id) || (!id.is_empty() && matches_pattern(pattern, &id[1..]))
+        }
+        (Some((b'?', rest)), Some((_, id_rest))) => matches_pattern(rest, id_rest),
+        (Some((p, rest)), Some((c, id_rest))) => p == c && matches_pattern(rest, id_rest),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, id: &str) -> bool {
+        matches_pattern(pattern.as_bytes(), id.as_bytes())
+    }
+
+    #[test]
+    fn matches_exact_ids() {
+        assert!(matches("jfKfPfyJRdk", "jfKfPfyJRdk"));
+        assert!(!matches("jfKfPfyJRdk", "jfKfPfyJRdK"));
+        assert!(!matches("jfKfPfyJRdk", "jfKfPfyJRd"));
+        assert!(!matches("", "jfKfPfyJRdk"));
+    }
+
+    #[test]
+    fn matches_any_characters() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "jfKfPfyJRdk"));
+        assert!(matches("jfKf*", "jfKfPfyJRdk"));
+        assert!(matches("*JRdk", "jfKfPfyJRdk"));
+        assert!(matches("jf*Pfy*dk", "jfKfPfyJRdk"));
+        assert!(matches("jfKfPfyJRdk*", "jfKfPfyJRdk"));
+        assert!(!matches("jfKf*x", "jfKfPfyJRdk"));
+        assert!(!matches("*x*", "jfKfPfyJRdk"));
+    }
+
+    #[test]
+    fn matches_single_characters() {
+        assert!(matches("jfKfPfyJRd?", "jfKfPfyJRdk"));
+        assert!(matches("???????????", "jfKfPfyJRdk"));
+        assert!(!matches("??????????", "jfKfPfyJRdk"));
+        assert!(!matches("jfKfPfyJRdk?", "jfKfPfyJRdk"));
+        assert!(matches("?*", "j"));
+        assert!(!matches("?*", ""));
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_web::HttpRequest;
+use parking_lot::Mutex;
+
+use crate::config::LimitsConfig;
+
+/// Buckets kept before the full ones, then the least recently used ones, are
+/// forgotten.
+const MAX_BUCKETS: usize = 10_000;
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+/// How long clients are asked to wait when every worker is taken.
+pub const WORKERS_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Whole seconds for the `Retry-After` header, rounded up so that the client
+/// does not retry too early.
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after
+        .as_secs()
+        .saturating_add(u64::from(retry_after.subsec_nanos() > 0))
+        .max(1)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets keyed by client. Each bucket holds up to `burst` requests and
+/// refills at a steady rate. A `burst` of 0 denies every request.
+pub struct TokenBuckets {
+    /// Tokens added per second.
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl TokenBuckets {
+    /// Allows `amount` requests in each `period`, and up to `burst` at once.
+    pub fn new(amount: u32, period: Duration, burst: u32) -> TokenBuckets {
+        TokenBuckets {
+            rate: f64::from(amount) / period.as_secs_f64(),
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for the key, or returns how long until one is available.
+    pub fn take(&self, key: &str) -> Result<(), Duration> {
+        self.take_at(key, Instant::now())
+    }
+
+    fn take_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        if buckets.len() >= MAX_BUCKETS {
+            // keeps the newer half, so that this does not run on every request
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, cutoff, _) = updated.select_nth_unstable(MAX_BUCKETS / 2);
+            let cutoff = *cutoff;
+            buckets.retain(|_, bucket| bucket.updated > cutoff);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let tokens = self.refill(bucket, now);
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            bucket.updated = now;
+            return Ok(());
+        }
+        if self.rate <= 0.0 || self.burst < 1.0 {
+            return Err(Duration::MAX);
+        }
+        Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Request budgets of the clients, see [`LimitsConfig`].
+pub struct RateLimits {
+    pub ip: TokenBuckets,
+    pub token: TokenBuckets,
+    pub new_streams: TokenBuckets,
+    pub max_workers: usize,
+    trust_proxy_headers: bool,
+}
+
+impl RateLimits {
+    pub fn new(config: &LimitsConfig) -> RateLimits {
+        RateLimits {
+            ip: TokenBuckets::new(config.ip_per_minute, MINUTE, config.ip_burst),
+            token: TokenBuckets::new(config.token_per_minute, MINUTE, config.token_burst),
+            new_streams: TokenBuckets::new(
+                config.new_streams_per_hour,
+                HOUR,
+                config.new_streams_per_hour,
+            ),
+            max_workers: config.max_workers,
+            trust_proxy_headers: config.trust_proxy_headers,
+        }
+    }
+
+    /// The IP address the request comes from, read from the proxy headers
+    /// only if they are trusted.
+    pub fn client_ip(&self, req: &HttpRequest) -> String {
+        let info = req.connection_info();
+        let ip = if self.trust_proxy_headers {
+            info.realip_remote_addr()
+        } else {
+            info.peer_addr()
+        };
+        ip.unwrap_or("unknown").to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_the_burst_at_once() {
+        let buckets = TokenBuckets::new(60, MINUTE, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(buckets.take_at("client", now).is_ok());
+        }
+        assert_eq!(buckets.take_at("client", now), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let buckets = TokenBuckets::new(60, MINUTE, 1);
+        let now = Instant::now();
+        assert!(buckets.take_at("client", now).is_ok());
+        let retry_after = buckets
+            .take_at("client", now + Duration::from_millis(250))
+            .unwrap_err();
+        assert!((retry_after.as_secs_f64() - 0.75).abs() < 1e-6);
+        assert!(buckets
+            .take_at("client", now + Duration::from_secs(1))
+            .is_ok());
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let buckets = TokenBuckets::new(60, MINUTE, 2);
+        let now = Instant::now();
+        assert!(buckets.take_at("client", now).is_ok());
+        let later = now + HOUR;
+        assert!(buckets.take_at("client", later).is_ok());
+        assert!(buckets.take_at("client", later).is_ok());
+        assert!(buckets.take_at("client", later).is_err());
+    }
+
+    #[test]
+    fn keeps_a_bucket_per_key() {
+        let buckets = TokenBuckets::new(1, HOUR, 1);
+        let now = Instant::now();
+        assert!(buckets.take_at("first", now).is_ok());
+        assert!(buckets.take_at("first", now).is_err());
+        assert!(buckets.take_at("second", now).is_ok());
+    }
+
+    #[test]
+    fn never_refills_without_a_rate() {
+        let buckets = TokenBuckets::new(0, MINUTE, 1);
+        let now = Instant::now();
+        assert!(buckets.take_at("client", now).is_ok());
+        assert_eq!(buckets.take_at("client", now + HOUR), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn denies_everything_without_a_burst() {
+        let buckets = TokenBuckets::new(60, MINUTE, 0);
+        let now = Instant::now();
+        assert_eq!(buckets.take_at("client", now), Err(Duration::MAX));
+        assert_eq!(buckets.take_at("client", now + HOUR), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn forgets_the_least_recently_used_buckets() {
+        let buckets = TokenBuckets::new(0, MINUTE, 1);
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let at = now + Duration::from_millis(i as u64);
+            assert!(buckets.take_at(&i.to_string(), at).is_ok());
+        }
+        let later = now + HOUR;
+        assert!(buckets.take_at("new", later).is_ok());
+        assert!(buckets.buckets.lock().len() <= MAX_BUCKETS / 2 + 1);
+        // the oldest client starts over, the newest one is still drained
+        assert!(buckets.take_at("0", later).is_ok());
+        let newest = (MAX_BUCKETS - 1).to_string();
+        assert_eq!(buckets.take_at(&newest, later), Err(Duration::MAX));
+    }
+
+    #[test]
+    fn rounds_retry_after_up() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after_secs(Duration::from_millis(2001)), 3);
+        assert_eq!(retry_after_secs(Duration::MAX), u64::MAX);
+    }
+}
@@ -1,25 +1,297 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
+use lofigirl_shared_common::api::{
+    Action, HistoryPage, HistoryQuery, OutboxStatus, ScrobbleRecord, ServiceOutcome, Subscription,
+    TimelineEntry, TimelineQuery, TokenInfo,
+};
 use lofigirl_shared_common::config::{ConfigError, LastFMClientSessionConfig, ListenBrainzConfig};
+use lofigirl_shared_common::schedule::Schedule;
+use lofigirl_shared_common::track::Track;
+use lofigirl_shared_listen::listener::{PendingListen, SendReport, Service};
+use lofigirl_shared_listen::outbox::{retry_delay, OUTBOX_BATCH_SIZE};
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::sqlite::SqliteConnectOptions;
 use sqlx::SqlitePool;
+use thiserror::Error;
+use tracing::info;
 use uuid::Uuid;
 
+use crate::data_key::DataKey;
+use crate::metrics::DbTimer;
+use crate::timeline::timeline_limit;
+
+const REFRESH_TOKEN_DURATION_SECS: i64 = 30 * 24 * 60 * 60;
+const LASTFM_SESSION_KEY_COLUMN: &str = "lastfm.session_key";
+const LISTENBRAINZ_TOKEN_COLUMN: &str = "listenbrainz.token";
+const DEFAULT_HISTORY_LIMIT: u32 = 50;
+const MAX_HISTORY_LIMIT: u32 = 500;
+const STATUS_SENT: &str = "sent";
+const STATUS_FAILED: &str = "failed";
+/// Most listens retried in a single pass over the outbox.
+const MAX_DUE_LISTENS: i64 = 1_000;
+
+static MIGRATOR: Migrator = sqlx::migrate!("../migrations");
+
+/// Queries counting the tables and columns each migration creates. Databases
+/// created by hand before the migrations were embedded have no migration
+/// history, so the migrations already in effect are recorded from these.
+const LEGACY_MIGRATION_PROBES: &[(i64, &str)] = &[
+    (
+        20210525000135,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'tokens'",
+    ),
+    (
+        20261019120000,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'subscriptions'",
+    ),
+    (
+        20261019130000,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'refresh_tokens'",
+    ),
+    (
+        20261019140000,
+        "SELECT COUNT(*) FROM pragma_table_info('lastfm') WHERE name = 'session_key_hash'",
+    ),
+    (
+        20261019150000,
+        "SELECT COUNT(*) FROM pragma_table_info('tokens') WHERE name = 'label'",
+    ),
+    (
+        20261019160000,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'scrobbles'",
+    ),
+    (
+        20261019170000,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'stream_tracks'",
+    ),
+    (
+        20261019180000,
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'outbox'",
+    ),
+];
+
+#[derive(Error, Debug)]
+pub enum SchemaError {
+    #[error(
+        "Database schema version {applied} is newer than the latest known version {known}, upgrade the server"
+    )]
+    NewerSchema { applied: i64, known: i64 },
+    #[error("Database is not migrated to the latest version {known}, run the migrate command")]
+    PendingMigrations { known: i64 },
+}
+
+pub struct StreamSubscriber {
+    pub token: String,
+    pub schedule: Option<Schedule>,
+}
+
+/// A listen waiting in the outbox.
+pub struct OutboxEntry {
+    pub id: i64,
+    pub listen: PendingListen,
+    pub attempts: i64,
+}
+
+/// Due listens of a token for a single service, sent in one go.
+pub struct OutboxBatch {
+    pub token: String,
+    pub service: Service,
+    pub entries: Vec<OutboxEntry>,
+}
+
 pub struct TokenDB {
     pool: SqlitePool,
+    data_key: DataKey,
 }
 
 impl TokenDB {
-    pub async fn new(filename: &str) -> Result<Self> {
+    pub async fn new(filename: &str, data_key: DataKey) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(filename)
+            .create_if_missing(true);
         let token_db = TokenDB {
-            pool: SqlitePool::connect(&format!("sqlite:{}", filename)).await?,
+            pool: SqlitePool::connect_with(options).await?,
+            data_key,
         };
         Ok(token_db)
     }
 
+    /// Fails if the database was migrated by a newer server, or misses
+    /// migrations, which are only applied by `serve` and `migrate`.
+    pub async fn check_migrated(&self) -> Result<()> {
+        self.check_schema_version().await?;
+        let known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+        if self
+            .applied_version()
+            .await?
+            .is_none_or(|applied| applied < known)
+        {
+            return Err(SchemaError::PendingMigrations { known }.into());
+        }
+        Ok(())
+    }
+
+    /// Applies the migrations which are not applied yet, then encrypts the
+    /// credentials left in plaintext by older versions. Fails without any
+    /// change if the database was migrated by a newer server.
+    pub async fn migrate(&self) -> Result<()> {
+        self.check_schema_version().await?;
+        self.record_legacy_migrations().await?;
+        MIGRATOR.run(&self.pool).await?;
+        self.encrypt_plaintext_credentials().await
+    }
+
+    async fn has_migration_history(&self) -> Result<bool> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations'",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count > 0)
+    }
+
+    /// Latest migration applied on the database, `None` without migration history.
+    async fn applied_version(&self) -> Result<Option<i64>> {
+        if !self.has_migration_history().await? {
+            return Ok(None);
+        }
+        let applied = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(applied)
+    }
+
+    async fn check_schema_version(&self) -> Result<()> {
+        let applied = self.applied_version().await?;
+        let known = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+        match applied {
+            Some(applied) if applied > known => {
+                Err(SchemaError::NewerSchema { applied, known }.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the migrations in effect on a database without migration
+    /// history, so that they are not applied a second time.
+    async fn record_legacy_migrations(&self) -> Result<()> {
+        if self.has_migration_history().await? {
+            return Ok(());
+        }
+        let mut conn = self.pool.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        for migration in MIGRATOR.iter() {
+            let Some((_, probe)) = LEGACY_MIGRATION_PROBES
+                .iter()
+                .find(|(version, _)| *version == migration.version)
+            else {
+                break;
+            };
+            let count: i64 = sqlx::query_scalar(probe).fetch_one(&mut *conn).await?;
+            if count == 0 {
+                break;
+            }
+            sqlx::query(
+                r#"
+                    INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+                    VALUES (?1, ?2, TRUE, ?3, 0)
+                "#,
+            )
+            .bind(migration.version)
+            .bind(&*migration.description)
+            .bind(&*migration.checksum)
+            .execute(&mut *conn)
+            .await?;
+            info!(
+                "Recorded migration {} of the existing database",
+                migration.version
+            );
+        }
+        Ok(())
+    }
+
+    /// Encrypts the credentials stored before encryption at rest and empties
+    /// their plaintext columns.
+    pub async fn encrypt_plaintext_credentials(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let lastfm_recs = sqlx::query!(
+            r#"
+                SELECT id, session_key FROM lastfm WHERE session_key != ''
+            "#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for rec in &lastfm_recs {
+            let hash = self
+                .data_key
+                .lookup_hash(LASTFM_SESSION_KEY_COLUMN, &rec.session_key);
+            let (encrypted, nonce) = self
+                .data_key
+                .encrypt(LASTFM_SESSION_KEY_COLUMN, &rec.session_key)?;
+            sqlx::query!(
+                r#"
+                    UPDATE lastfm
+                    SET session_key = '', session_key_hash = ?1,
+                        encrypted_session_key = ?2, session_key_nonce = ?3
+                    WHERE id = ?4
+                "#,
+                hash,
+                encrypted,
+                nonce,
+                rec.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let listenbrainz_recs = sqlx::query!(
+            r#"
+                SELECT id, token FROM listenbrainz WHERE token != ''
+            "#
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for rec in &listenbrainz_recs {
+            let hash = self
+                .data_key
+                .lookup_hash(LISTENBRAINZ_TOKEN_COLUMN, &rec.token);
+            let (encrypted, nonce) = self
+                .data_key
+                .encrypt(LISTENBRAINZ_TOKEN_COLUMN, &rec.token)?;
+            sqlx::query!(
+                r#"
+                    UPDATE listenbrainz
+                    SET token = '', token_hash = ?1, encrypted_token = ?2, token_nonce = ?3
+                    WHERE id = ?4
+                "#,
+                hash,
+                encrypted,
+                nonce,
+                rec.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        if !lastfm_recs.is_empty() || !listenbrainz_recs.is_empty() {
+            info!(
+                "Encrypted {} Last.fm and {} ListenBrainz credentials",
+                lastfm_recs.len(),
+                listenbrainz_recs.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the active token issued for the same credentials and label,
+    /// or issues a new one.
     pub async fn get_or_generate_token(
         &self,
         lastfm_session_key: Option<&String>,
         listenbrainz_token: Option<&String>,
+        label: Option<&str>,
     ) -> Result<String> {
+        let _timer = DbTimer::start("get_or_generate_token");
         (lastfm_session_key.is_some() || listenbrainz_token.is_some())
             .then_some(())
             .ok_or(ConfigError::EmptyListeners)?;
@@ -37,10 +309,12 @@ impl TokenDB {
 
         let optional_token = sqlx::query!(
             r#"
-                SELECT token FROM tokens WHERE lastfm_id = ?1 AND listenbrainz_id = ?2
+                SELECT token FROM tokens
+                WHERE lastfm_id IS ?1 AND listenbrainz_id IS ?2 AND label IS ?3 AND NOT revoked
             "#,
             lfm_id,
-            lb_id
+            lb_id,
+            label
         )
         .fetch_optional(&mut *conn)
         .await?;
@@ -52,14 +326,17 @@ impl TokenDB {
                     .hyphenated()
                     .encode_lower(&mut Uuid::encode_buffer())
                     .to_owned();
+                let now = chrono::Utc::now().timestamp();
                 let _id = sqlx::query!(
                     r#"
-                        INSERT INTO tokens ( token, lastfm_id, listenbrainz_id )
-                        VALUES ( ?1, ?2, ?3)
+                        INSERT INTO tokens ( token, lastfm_id, listenbrainz_id, label, created_at )
+                        VALUES ( ?1, ?2, ?3, ?4, ?5 )
                     "#,
                     token_str,
                     lfm_id,
-                    lb_id
+                    lb_id,
+                    label,
+                    now
                 )
                 .execute(&mut *conn)
                 .await?
@@ -71,23 +348,32 @@ impl TokenDB {
 
     async fn get_lastfm_id(&self, lastfm_session_key: &str) -> Result<i64> {
         let mut conn = self.pool.acquire().await?;
+        let hash = self
+            .data_key
+            .lookup_hash(LASTFM_SESSION_KEY_COLUMN, lastfm_session_key);
         let optional_id = sqlx::query!(
             r#"
-                SELECT id FROM lastfm WHERE session_key = ?1
+                SELECT id FROM lastfm WHERE session_key_hash = ?1
             "#,
-            lastfm_session_key
+            hash
         )
         .fetch_optional(&mut *conn)
         .await?;
         match optional_id {
             Some(rec) => Ok(rec.id),
             None => {
+                let (encrypted, nonce) = self
+                    .data_key
+                    .encrypt(LASTFM_SESSION_KEY_COLUMN, lastfm_session_key)?;
                 let id = sqlx::query!(
                     r#"
-                        INSERT INTO lastfm ( session_key )
-                        VALUES ( ?1 )
+                        INSERT INTO lastfm
+                        ( session_key, session_key_hash, encrypted_session_key, session_key_nonce )
+                        VALUES ( '', ?1, ?2, ?3 )
                     "#,
-                    lastfm_session_key,
+                    hash,
+                    encrypted,
+                    nonce
                 )
                 .execute(&mut *conn)
                 .await?
@@ -99,23 +385,31 @@ impl TokenDB {
 
     async fn get_listenbrainz_id(&self, listenbrainz_token: &str) -> Result<i64> {
         let mut conn = self.pool.acquire().await?;
+        let hash = self
+            .data_key
+            .lookup_hash(LISTENBRAINZ_TOKEN_COLUMN, listenbrainz_token);
         let optional_id = sqlx::query!(
             r#"
-                SELECT id FROM listenbrainz WHERE token = ?1
+                SELECT id FROM listenbrainz WHERE token_hash = ?1
             "#,
-            listenbrainz_token
+            hash
         )
         .fetch_optional(&mut *conn)
         .await?;
         match optional_id {
             Some(rec) => Ok(rec.id),
             None => {
+                let (encrypted, nonce) = self
+                    .data_key
+                    .encrypt(LISTENBRAINZ_TOKEN_COLUMN, listenbrainz_token)?;
                 let id = sqlx::query!(
                     r#"
-                        INSERT INTO listenbrainz ( token )
-                        VALUES ( ?1 )
+                        INSERT INTO listenbrainz ( token, token_hash, encrypted_token, token_nonce )
+                        VALUES ( '', ?1, ?2, ?3 )
                     "#,
-                    listenbrainz_token,
+                    hash,
+                    encrypted,
+                    nonce
                 )
                 .execute(&mut *conn)
                 .await?
@@ -132,6 +426,7 @@ impl TokenDB {
         Option<LastFMClientSessionConfig>,
         Option<ListenBrainzConfig>,
     )> {
+        let _timer = DbTimer::start("get_info_from_token");
         let mut conn = self.pool.acquire().await?;
         let rec = sqlx::query!(
             r#"
@@ -159,29 +454,1029 @@ impl TokenDB {
         let mut conn = self.pool.acquire().await?;
         let rec = sqlx::query!(
             r#"
-                SELECT token FROM listenbrainz
+                SELECT encrypted_token AS "encrypted_token!", token_nonce AS "token_nonce!"
+                FROM listenbrainz
                 WHERE id = ?1
             "#,
             id
         )
         .fetch_one(&mut *conn)
         .await?;
-        Ok(ListenBrainzConfig { token: rec.token })
+        let token = self.data_key.decrypt(
+            LISTENBRAINZ_TOKEN_COLUMN,
+            &rec.encrypted_token,
+            &rec.token_nonce,
+        )?;
+        Ok(ListenBrainzConfig { token })
     }
 
     async fn get_lastfm_config(&self, id: i64) -> Result<LastFMClientSessionConfig> {
         let mut conn = self.pool.acquire().await?;
         let rec = sqlx::query!(
             r#"
-                SELECT session_key FROM lastfm
+                SELECT encrypted_session_key AS "encrypted_session_key!",
+                    session_key_nonce AS "session_key_nonce!"
+                FROM lastfm
                 WHERE id = ?1
             "#,
             id
         )
         .fetch_one(&mut *conn)
         .await?;
-        Ok(LastFMClientSessionConfig {
-            session_key: rec.session_key,
+        let session_key = self.data_key.decrypt(
+            LASTFM_SESSION_KEY_COLUMN,
+            &rec.encrypted_session_key,
+            &rec.session_key_nonce,
+        )?;
+        Ok(LastFMClientSessionConfig { session_key })
+    }
+
+    pub async fn add_subscription(
+        &self,
+        token_str: &str,
+        stream_id: &str,
+        stream_url: &str,
+        schedule: Option<&Schedule>,
+    ) -> Result<i64> {
+        let _timer = DbTimer::start("add_subscription");
+        let mut conn = self.pool.acquire().await?;
+        let token = sqlx::query!(
+            r#"
+                SELECT id FROM tokens WHERE token = ?1
+            "#,
+            token_str
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let schedule = schedule.map(|s| s.to_string());
+        let id = sqlx::query!(
+            r#"
+                INSERT INTO subscriptions ( token_id, stream_id, stream_url, schedule )
+                VALUES ( ?1, ?2, ?3, ?4 )
+            "#,
+            token.id,
+            stream_id,
+            stream_url,
+            schedule
+        )
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+        Ok(id)
+    }
+
+    pub async fn get_subscriptions(&self, token_str: &str) -> Result<Vec<Subscription>> {
+        let _timer = DbTimer::start("get_subscriptions");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT subscriptions.id, subscriptions.stream_url, subscriptions.schedule
+                FROM subscriptions JOIN tokens ON subscriptions.token_id = tokens.id
+                WHERE tokens.token = ?1
+            "#,
+            token_str
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        recs.into_iter()
+            .map(|rec| -> Result<Subscription> {
+                Ok(Subscription {
+                    id: rec.id,
+                    stream_url: rec.stream_url,
+                    schedule: rec.schedule.map(|s| s.parse()).transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn remove_subscription(&self, token_str: &str, id: i64) -> Result<bool> {
+        let _timer = DbTimer::start("remove_subscription");
+        let mut conn = self.pool.acquire().await?;
+        let rows = sqlx::query!(
+            r#"
+                DELETE FROM subscriptions
+                WHERE id = ?1 AND token_id IN (SELECT id FROM tokens WHERE token = ?2)
+            "#,
+            id,
+            token_str
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        Ok(rows > 0)
+    }
+
+    pub async fn get_stream_subscribers(&self, stream_id: &str) -> Result<Vec<StreamSubscriber>> {
+        let _timer = DbTimer::start("get_stream_subscribers");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT tokens.token, subscriptions.schedule
+                FROM subscriptions JOIN tokens ON subscriptions.token_id = tokens.id
+                WHERE subscriptions.stream_id = ?1
+            "#,
+            stream_id
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        recs.into_iter()
+            .map(|rec| -> Result<StreamSubscriber> {
+                Ok(StreamSubscriber {
+                    token: rec.token,
+                    schedule: rec.schedule.map(|s| s.parse()).transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    pub async fn has_subscribers(&self, stream_id: &str) -> Result<bool> {
+        let _timer = DbTimer::start("has_subscribers");
+        let mut conn = self.pool.acquire().await?;
+        let rec = sqlx::query!(
+            r#"
+                SELECT COUNT(*) AS count FROM subscriptions WHERE stream_id = ?1
+            "#,
+            stream_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(rec.count > 0)
+    }
+
+    /// Returns the number of subscriptions of every stream with at least one.
+    pub async fn get_subscriber_counts(&self) -> Result<HashMap<String, i64>> {
+        let _timer = DbTimer::start("get_subscriber_counts");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT stream_id, COUNT(*) AS "count!: i64" FROM subscriptions GROUP BY stream_id
+            "#
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| (rec.stream_id, rec.count))
+            .collect())
+    }
+
+    /// Returns the id and url of every stream with at least one subscription.
+    pub async fn get_subscribed_streams(&self) -> Result<Vec<(String, String)>> {
+        let _timer = DbTimer::start("get_subscribed_streams");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT stream_id AS "stream_id!", MIN(stream_url) AS "stream_url!: String"
+                FROM subscriptions GROUP BY stream_id
+            "#
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| (rec.stream_id, rec.stream_url))
+            .collect())
+    }
+
+    /// Issues a new refresh token for the given token.
+    pub async fn create_refresh_token(&self, token_str: &str) -> Result<String> {
+        let _timer = DbTimer::start("create_refresh_token");
+        let mut conn = self.pool.acquire().await?;
+        let token = sqlx::query!(
+            r#"
+                SELECT id FROM tokens WHERE token = ?1
+            "#,
+            token_str
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let refresh_token = Uuid::new_v4()
+            .hyphenated()
+            .encode_lower(&mut Uuid::encode_buffer())
+            .to_owned();
+        let expires_at = chrono::Utc::now().timestamp() + REFRESH_TOKEN_DURATION_SECS;
+        sqlx::query!(
+            r#"
+                INSERT INTO refresh_tokens ( token_id, refresh_token, expires_at )
+                VALUES ( ?1, ?2, ?3 )
+            "#,
+            token.id,
+            refresh_token,
+            expires_at
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(refresh_token)
+    }
+
+    /// Consumes the refresh token and returns its token along with a new refresh token.
+    /// Returns `None` if the refresh token is unknown or expired.
+    pub async fn rotate_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<Option<(String, String)>> {
+        let _timer = DbTimer::start("rotate_refresh_token");
+        let mut conn = self.pool.acquire().await?;
+        let now = chrono::Utc::now().timestamp();
+        let optional_rec = sqlx::query!(
+            r#"
+                SELECT refresh_tokens.id, refresh_tokens.expires_at, tokens.token
+                FROM refresh_tokens JOIN tokens ON refresh_tokens.token_id = tokens.id
+                WHERE refresh_tokens.refresh_token = ?1 AND NOT tokens.revoked
+            "#,
+            refresh_token
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some(rec) = optional_rec else {
+            return Ok(None);
+        };
+        // only the request which deletes the refresh token may use it
+        let claimed = sqlx::query!(
+            r#"
+                DELETE FROM refresh_tokens WHERE id = ?1
+            "#,
+            rec.id
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        sqlx::query!(
+            r#"
+                DELETE FROM refresh_tokens WHERE expires_at <= ?1
+            "#,
+            now
+        )
+        .execute(&mut *conn)
+        .await?;
+        if claimed != 1 || rec.expires_at <= now {
+            return Ok(None);
+        }
+        let new_refresh_token = self.create_refresh_token(&rec.token).await?;
+        Ok(Some((rec.token, new_refresh_token)))
+    }
+
+    /// Marks the token as used now. Returns `false` if the token is revoked or unknown.
+    pub async fn use_token(&self, token_str: &str) -> Result<bool> {
+        let _timer = DbTimer::start("use_token");
+        let mut conn = self.pool.acquire().await?;
+        let now = chrono::Utc::now().timestamp();
+        let rows = sqlx::query!(
+            r#"
+                UPDATE tokens SET last_used_at = ?1 WHERE token = ?2 AND NOT revoked
+            "#,
+            now,
+            token_str
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        Ok(rows > 0)
+    }
+
+    /// Lists the tokens issued for the same Last.fm or ListenBrainz account as the given token.
+    pub async fn get_tokens(&self, token_str: &str) -> Result<Vec<TokenInfo>> {
+        let _timer = DbTimer::start("get_tokens");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT tokens.id, tokens.label, tokens.created_at, tokens.last_used_at,
+                    tokens.revoked, tokens.id = caller.id AS "current!: bool"
+                FROM tokens JOIN tokens AS caller ON caller.token = ?1
+                WHERE tokens.lastfm_id = caller.lastfm_id
+                    OR tokens.listenbrainz_id = caller.listenbrainz_id
+                ORDER BY tokens.created_at
+            "#,
+            token_str
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| TokenInfo {
+                id: rec.id,
+                label: rec.label,
+                created_at: rec.created_at,
+                last_used_at: rec.last_used_at,
+                revoked: rec.revoked,
+                current: rec.current,
+            })
+            .collect())
+    }
+
+    /// Renames one of the tokens listed by [`TokenDB::get_tokens`].
+    /// Returns `false` if there is no such token.
+    pub async fn rename_token(&self, token_str: &str, id: i64, label: &str) -> Result<bool> {
+        let _timer = DbTimer::start("rename_token");
+        let mut conn = self.pool.acquire().await?;
+        let rows = sqlx::query!(
+            r#"
+                UPDATE tokens SET label = ?1
+                WHERE id = ?2 AND id IN (
+                    SELECT tokens.id FROM tokens JOIN tokens AS caller ON caller.token = ?3
+                    WHERE tokens.lastfm_id = caller.lastfm_id
+                        OR tokens.listenbrainz_id = caller.listenbrainz_id
+                )
+            "#,
+            label,
+            id,
+            token_str
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        Ok(rows > 0)
+    }
+
+    /// Revokes one of the tokens listed by [`TokenDB::get_tokens`], along with its
+    /// refresh tokens and subscriptions. Returns `false` if there is no such token.
+    pub async fn revoke_token(&self, token_str: &str, id: i64) -> Result<bool> {
+        let _timer = DbTimer::start("revoke_token");
+        let mut conn = self.pool.acquire().await?;
+        let owned = sqlx::query!(
+            r#"
+                SELECT tokens.id FROM tokens JOIN tokens AS caller ON caller.token = ?2
+                WHERE tokens.id = ?1 AND (
+                    tokens.lastfm_id = caller.lastfm_id
+                    OR tokens.listenbrainz_id = caller.listenbrainz_id
+                )
+            "#,
+            id,
+            token_str
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+        if owned.is_none() {
+            return Ok(false);
+        }
+        self.revoke_token_by_id(id).await
+    }
+
+    /// Revokes any token, along with its refresh tokens and subscriptions.
+    /// Returns `false` if there is no such token.
+    pub async fn revoke_token_by_id(&self, id: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query!(
+            r#"
+                UPDATE tokens SET revoked = TRUE WHERE id = ?1
+            "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if rows == 0 {
+            return Ok(false);
+        }
+        sqlx::query!(
+            r#"
+                DELETE FROM refresh_tokens WHERE token_id = ?1
+            "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+                DELETE FROM subscriptions WHERE token_id = ?1
+            "#,
+            id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Lists every token, `current` is always `false`.
+    pub async fn get_all_tokens(&self) -> Result<Vec<TokenInfo>> {
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT id, label, created_at, last_used_at, revoked FROM tokens
+                ORDER BY created_at
+            "#
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| TokenInfo {
+                id: rec.id,
+                label: rec.label,
+                created_at: rec.created_at,
+                last_used_at: rec.last_used_at,
+                revoked: rec.revoked,
+                current: false,
+            })
+            .collect())
+    }
+
+    /// Records the outcome of a scrobble of the given token.
+    pub async fn record_scrobble(
+        &self,
+        token_str: &str,
+        action: Action,
+        track: &Track,
+        stream_id: Option<&str>,
+        report: &SendReport,
+    ) -> Result<()> {
+        let _timer = DbTimer::start("record_scrobble");
+        let mut conn = self.pool.acquire().await?;
+        let now = chrono::Utc::now().timestamp();
+        let action = action.as_str();
+        let (lastfm_status, lastfm_error) = outcome_columns(report.lastfm.as_ref());
+        let (listenbrainz_status, listenbrainz_error) =
+            outcome_columns(report.listenbrainz.as_ref());
+        sqlx::query!(
+            r#"
+                INSERT INTO scrobbles ( token_id, action, artist, song, stream_id, lastfm_status,
+                    lastfm_error, listenbrainz_status, listenbrainz_error, created_at )
+                SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10 FROM tokens WHERE token = ?1
+            "#,
+            token_str,
+            action,
+            track.artist,
+            track.song,
+            stream_id,
+            lastfm_status,
+            lastfm_error,
+            listenbrainz_status,
+            listenbrainz_error,
+            now
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns a page of the scrobbles of the given token, newest first.
+    pub async fn get_history(&self, token_str: &str, query: &HistoryQuery) -> Result<HistoryPage> {
+        let _timer = DbTimer::start("get_history");
+        let mut conn = self.pool.acquire().await?;
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .clamp(1, MAX_HISTORY_LIMIT);
+        // one more than the limit to know if there is a next page
+        let fetch_limit = i64::from(limit) + 1;
+        let action = query.action.map(|a| a.as_str());
+        let recs = sqlx::query!(
+            r#"
+                SELECT scrobbles.id, scrobbles.action, scrobbles.artist, scrobbles.song,
+                    scrobbles.stream_id, scrobbles.lastfm_status, scrobbles.lastfm_error,
+                    scrobbles.listenbrainz_status, scrobbles.listenbrainz_error,
+                    scrobbles.created_at
+                FROM scrobbles JOIN tokens ON scrobbles.token_id = tokens.id
+                WHERE tokens.token = ?1
+                    AND (?2 IS NULL OR scrobbles.id < ?2)
+                    AND (?3 IS NULL OR scrobbles.action = ?3)
+                    AND (?4 IS NULL OR scrobbles.stream_id = ?4)
+                    AND (NOT ?5 OR scrobbles.lastfm_status = 'failed'
+                        OR scrobbles.listenbrainz_status = 'failed')
+                    AND (?6 IS NULL OR scrobbles.created_at >= ?6)
+                    AND (?7 IS NULL OR scrobbles.created_at < ?7)
+                ORDER BY scrobbles.id DESC
+                LIMIT ?8
+            "#,
+            token_str,
+            query.cursor,
+            action,
+            query.stream_id,
+            query.failed,
+            query.since,
+            query.until,
+            fetch_limit
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let has_next = recs.len() > limit as usize;
+        let scrobbles = recs
+            .into_iter()
+            .take(limit as usize)
+            .map(|rec| -> Result<ScrobbleRecord> {
+                Ok(ScrobbleRecord {
+                    id: rec.id,
+                    action: rec.action.parse()?,
+                    track: Track {
+                        artist: rec.artist,
+                        song: rec.song,
+                    },
+                    stream_id: rec.stream_id,
+                    lastfm: outcome_from_columns(rec.lastfm_status, rec.lastfm_error),
+                    listenbrainz: outcome_from_columns(
+                        rec.listenbrainz_status,
+                        rec.listenbrainz_error,
+                    ),
+                    created_at: rec.created_at,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let next_cursor = scrobbles.last().filter(|_| has_next).map(|s| s.id);
+        Ok(HistoryPage {
+            scrobbles,
+            next_cursor,
         })
     }
+
+    /// Ends the track on air on the stream, if any, and puts the given track on air.
+    pub async fn record_stream_track(&self, stream_id: &str, track: &Track, at: i64) -> Result<()> {
+        let _timer = DbTimer::start("record_stream_track");
+        let mut tx = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+                UPDATE stream_tracks SET ended_at = ?2 WHERE stream_id = ?1 AND ended_at IS NULL
+            "#,
+            stream_id,
+            at
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+                INSERT INTO stream_tracks ( stream_id, artist, song, started_at )
+                VALUES ( ?1, ?2, ?3, ?4 )
+            "#,
+            stream_id,
+            track.artist,
+            track.song,
+            at
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Queues a listen which has failed on the service, to be retried after a while.
+    pub async fn queue_listen(
+        &self,
+        token_str: &str,
+        service: Service,
+        listen: &PendingListen,
+        error: &str,
+    ) -> Result<()> {
+        let _timer = DbTimer::start("queue_listen");
+        let mut conn = self.pool.acquire().await?;
+        let service = service.as_str();
+        let next_attempt_at = chrono::Utc::now().timestamp() + retry_delay(1).as_secs() as i64;
+        sqlx::query!(
+            r#"
+                INSERT INTO outbox ( token_id, service, artist, song, listened_at, attempts,
+                    next_attempt_at, last_error )
+                SELECT id, ?2, ?3, ?4, ?5, 1, ?6, ?7 FROM tokens WHERE token = ?1
+            "#,
+            token_str,
+            service,
+            listen.track.artist,
+            listen.track.song,
+            listen.listened_at,
+            next_attempt_at,
+            error
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the listens due for a retry, oldest first, grouped into
+    /// batches by token and service. Listens of revoked tokens are left out.
+    pub async fn get_due_listens(&self, now: i64) -> Result<Vec<OutboxBatch>> {
+        let _timer = DbTimer::start("get_due_listens");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT outbox.id, tokens.token, outbox.service, outbox.artist, outbox.song,
+                    outbox.listened_at, outbox.attempts
+                FROM outbox JOIN tokens ON outbox.token_id = tokens.id
+                WHERE outbox.next_attempt_at <= ?1 AND NOT tokens.revoked
+                ORDER BY outbox.token_id, outbox.service, outbox.listened_at
+                LIMIT ?2
+            "#,
+            now,
+            MAX_DUE_LISTENS
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        let mut batches: Vec<OutboxBatch> = Vec::new();
+        for rec in recs {
+            let service = rec.service.parse::<Service>()?;
+            let entry = OutboxEntry {
+                id: rec.id,
+                listen: PendingListen {
+                    track: Track {
+                        artist: rec.artist,
+                        song: rec.song,
+                    },
+                    listened_at: rec.listened_at,
+                },
+                attempts: rec.attempts,
+            };
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.token == rec.token
+                        && batch.service == service
+                        && batch.entries.len() < OUTBOX_BATCH_SIZE =>
+                {
+                    batch.entries.push(entry)
+                }
+                _ => batches.push(OutboxBatch {
+                    token: rec.token,
+                    service,
+                    entries: vec![entry],
+                }),
+            }
+        }
+        Ok(batches)
+    }
+
+    /// Removes the sent listens of a batch, and makes the other listens of
+    /// its token for the service due, as the service works again.
+    pub async fn complete_listens(
+        &self,
+        batch: &OutboxBatch,
+        sent: &[OutboxEntry],
+        now: i64,
+    ) -> Result<()> {
+        let _timer = DbTimer::start("complete_listens");
+        let mut tx = self.pool.begin().await?;
+        for entry in sent {
+            sqlx::query!(
+                r#"
+                    DELETE FROM outbox WHERE id = ?1
+                "#,
+                entry.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let service = batch.service.as_str();
+        sqlx::query!(
+            r#"
+                UPDATE outbox SET next_attempt_at = ?3
+                WHERE service = ?2 AND next_attempt_at > ?3
+                    AND token_id IN (SELECT id FROM tokens WHERE token = ?1)
+            "#,
+            batch.token,
+            service,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Schedules the next retry of the listens of a batch after another failure.
+    pub async fn postpone_listens(&self, failed: &[OutboxEntry], error: &str) -> Result<()> {
+        let _timer = DbTimer::start("postpone_listens");
+        let mut tx = self.pool.begin().await?;
+        let now = chrono::Utc::now().timestamp();
+        for entry in failed {
+            let attempts = entry.attempts + 1;
+            let next_attempt_at =
+                now + retry_delay(u32::try_from(attempts).unwrap_or(u32::MAX)).as_secs() as i64;
+            sqlx::query!(
+                r#"
+                    UPDATE outbox SET attempts = ?2, next_attempt_at = ?3, last_error = ?4
+                    WHERE id = ?1
+                "#,
+                entry.id,
+                attempts,
+                next_attempt_at,
+                error
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Drops the listens which happened before the given time, returns their number.
+    pub async fn drop_listens_before(&self, before: i64) -> Result<u64> {
+        let _timer = DbTimer::start("drop_listens_before");
+        let mut conn = self.pool.acquire().await?;
+        let rows = sqlx::query!(
+            r#"
+                DELETE FROM outbox WHERE listened_at < ?1
+            "#,
+            before
+        )
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+        Ok(rows)
+    }
+
+    /// Returns the number of listens of the token waiting in the outbox.
+    pub async fn get_outbox_status(&self, token_str: &str) -> Result<OutboxStatus> {
+        let _timer = DbTimer::start("get_outbox_status");
+        let mut conn = self.pool.acquire().await?;
+        let rec = sqlx::query!(
+            r#"
+                SELECT COUNT(*) AS "pending!: i64",
+                    MIN(outbox.next_attempt_at) AS "next_attempt_at: i64"
+                FROM outbox JOIN tokens ON outbox.token_id = tokens.id
+                WHERE tokens.token = ?1
+            "#,
+            token_str
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let last_error = sqlx::query!(
+            r#"
+                SELECT outbox.last_error
+                FROM outbox JOIN tokens ON outbox.token_id = tokens.id
+                WHERE tokens.token = ?1
+                ORDER BY outbox.id DESC
+                LIMIT 1
+            "#,
+            token_str
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .and_then(|rec| rec.last_error);
+        Ok(OutboxStatus {
+            pending: rec.pending,
+            next_attempt_at: rec.next_attempt_at,
+            last_error,
+        })
+    }
+
+    /// Ends the tracks left on air by a server which did not stop cleanly,
+    /// returning how many there were.
+    pub async fn end_dangling_stream_tracks(&self, at: i64) -> Result<u64> {
+        let _timer = DbTimer::start("end_dangling_stream_tracks");
+        let mut conn = self.pool.acquire().await?;
+        let result = sqlx::query!(
+            r#"
+                UPDATE stream_tracks SET ended_at = ?1 WHERE ended_at IS NULL
+            "#,
+            at
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Ends the track on air on the stream, if any.
+    pub async fn end_stream_track(&self, stream_id: &str, at: i64) -> Result<()> {
+        let _timer = DbTimer::start("end_stream_track");
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query!(
+            r#"
+                UPDATE stream_tracks SET ended_at = ?2 WHERE stream_id = ?1 AND ended_at IS NULL
+            "#,
+            stream_id,
+            at
+        )
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the tracks of the stream matching the query, in the order of the query.
+    pub async fn get_stream_timeline(
+        &self,
+        stream_id: &str,
+        query: &TimelineQuery,
+    ) -> Result<Vec<TimelineEntry>> {
+        let _timer = DbTimer::start("get_stream_timeline");
+        let mut conn = self.pool.acquire().await?;
+        let limit = timeline_limit(query) as i64;
+        let recs = sqlx::query!(
+            r#"
+                SELECT artist, song, started_at, ended_at FROM stream_tracks
+                WHERE stream_id = ?1
+                    AND (?2 IS NULL OR ended_at IS NULL OR ended_at > ?2)
+                    AND (?3 IS NULL OR started_at < ?3)
+                ORDER BY CASE WHEN ?5 THEN started_at ELSE -started_at END
+                LIMIT ?4
+            "#,
+            stream_id,
+            query.since,
+            query.until,
+            limit,
+            query.oldest_first
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| TimelineEntry {
+                track: Track {
+                    artist: rec.artist,
+                    song: rec.song,
+                },
+                started_at: rec.started_at,
+                ended_at: rec.ended_at,
+            })
+            .collect())
+    }
+
+    /// Returns the tracks of the stream recorded after the one with the given
+    /// row id, oldest first, along with their row ids.
+    pub async fn get_stream_tracks_after(
+        &self,
+        stream_id: &str,
+        after_id: i64,
+        limit: u32,
+    ) -> Result<Vec<(i64, Track)>> {
+        let _timer = DbTimer::start("get_stream_tracks_after");
+        let mut conn = self.pool.acquire().await?;
+        let recs = sqlx::query!(
+            r#"
+                SELECT id, artist, song FROM stream_tracks
+                WHERE stream_id = ?1 AND id > ?2
+                ORDER BY id
+                LIMIT ?3
+            "#,
+            stream_id,
+            after_id,
+            limit
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        Ok(recs
+            .into_iter()
+            .map(|rec| {
+                let track = Track {
+                    artist: rec.artist,
+                    song: rec.song,
+                };
+                (rec.id, track)
+            })
+            .collect())
+    }
+
+    /// Returns the row id of the last track of the stream which has ended, if any.
+    pub async fn get_last_ended_stream_track_id(&self, stream_id: &str) -> Result<Option<i64>> {
+        let _timer = DbTimer::start("get_last_ended_stream_track_id");
+        let mut conn = self.pool.acquire().await?;
+        let rec = sqlx::query!(
+            r#"
+                SELECT MAX(id) AS "id?: i64" FROM stream_tracks
+                WHERE stream_id = ?1 AND ended_at IS NOT NULL
+            "#,
+            stream_id
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(rec.id)
+    }
+
+    /// Checks that the database is reachable and writable, without changing it.
+    pub async fn check_writable(&self) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await?;
+        sqlx::query("ROLLBACK").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    pub async fn vacuum(&self) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("VACUUM").execute(&mut *conn).await?;
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the database to the given file, which should not exist.
+    pub async fn backup(&self, filename: &str) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("VACUUM INTO ?1")
+            .bind(filename)
+            .execute(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+fn outcome_columns(result: Option<&Result<()>>) -> (Option<&'static str>, Option<String>) {
+    match result {
+        None => (None, None),
+        Some(Ok(())) => (Some(STATUS_SENT), None),
+        Some(Err(e)) => (Some(STATUS_FAILED), Some(e.to_string())),
+    }
+}
+
+fn outcome_from_columns(status: Option<String>, error: Option<String>) -> Option<ServiceOutcome> {
+    match status?.as_str() {
+        STATUS_SENT => Some(ServiceOutcome::Sent),
+        _ => Some(ServiceOutcome::Failed {
+            error: error.unwrap_or_default(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// Schema of the databases created before the migrations were embedded.
+    const BASELINE_SCHEMA: &str = include_str!("../../migrations/20210525000135_table.sql");
+
+    async fn token_db() -> TokenDB {
+        // the in-memory database is shared by the connections of the pool, and
+        // is gone once the last of them is closed
+        let pool = SqlitePoolOptions::new()
+            .min_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+            .await
+            .unwrap();
+        TokenDB {
+            pool,
+            data_key: DataKey::new(&[1; 32]).unwrap(),
+        }
+    }
+
+    fn known_version() -> i64 {
+        MIGRATOR.iter().map(|m| m.version).max().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn migrates_a_baseline_database_keeping_its_rows() {
+        let token_db = token_db().await;
+        sqlx::raw_sql(BASELINE_SCHEMA)
+            .execute(&token_db.pool)
+            .await
+            .unwrap();
+        sqlx::raw_sql(
+            r#"
+                INSERT INTO lastfm (id, session_key) VALUES (1, 'session key');
+                INSERT INTO listenbrainz (id, token) VALUES (1, 'listenbrainz token');
+                INSERT INTO tokens (id, token, lastfm_id, listenbrainz_id) VALUES (1, 'token', 1, 1);
+            "#,
+        )
+        .execute(&token_db.pool)
+        .await
+        .unwrap();
+        assert!(matches!(
+            token_db.check_migrated().await.unwrap_err().downcast_ref(),
+            Some(SchemaError::PendingMigrations { .. })
+        ));
+
+        token_db.migrate().await.unwrap();
+        token_db.check_migrated().await.unwrap();
+        assert_eq!(
+            token_db.applied_version().await.unwrap(),
+            Some(known_version())
+        );
+        let (lastfm, listenbrainz) = token_db.get_info_from_token("token").await.unwrap();
+        assert_eq!(lastfm.unwrap().session_key, "session key");
+        assert_eq!(listenbrainz.unwrap().token, "listenbrainz token");
+        // only the encrypted credentials are left
+        let plaintext: (String, String) = sqlx::query_as(
+            "SELECT lastfm.session_key, listenbrainz.token FROM lastfm, listenbrainz",
+        )
+        .fetch_one(&token_db.pool)
+        .await
+        .unwrap();
+        assert_eq!(plaintext, (String::new(), String::new()));
+        assert_eq!(
+            token_db
+                .get_or_generate_token(
+                    Some(&"session key".to_owned()),
+                    Some(&"listenbrainz token".to_owned()),
+                    None
+                )
+                .await
+                .unwrap(),
+            "token"
+        );
+
+        // migrating again changes nothing
+        token_db.migrate().await.unwrap();
+        let (lastfm, _) = token_db.get_info_from_token("token").await.unwrap();
+        assert_eq!(lastfm.unwrap().session_key, "session key");
+    }
+
+    #[actix_rt::test]
+    async fn refuses_a_schema_newer_than_known() {
+        let token_db = token_db().await;
+        token_db.migrate().await.unwrap();
+        let newer = known_version() + 1;
+        sqlx::query(
+            r#"
+                INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+                VALUES (?1, 'newer', TRUE, x'', 0)
+            "#,
+        )
+        .bind(newer)
+        .execute(&token_db.pool)
+        .await
+        .unwrap();
+        for refused in [token_db.migrate().await, token_db.check_migrated().await] {
+            assert!(matches!(
+                refused.unwrap_err().downcast_ref(),
+                Some(SchemaError::NewerSchema { applied, known })
+                    if *applied == newer && *known == known_version()
+            ));
+        }
+    }
 }
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use actix_web::web;
+use lofigirl_shared_common::STREAM_GRACE_PERIOD;
+use parking_lot::Mutex;
+
+use crate::webserver::AppState;
+
+struct Subscribers {
+    count: usize,
+    /// Since when the stream has no subscribers, or when it was last polled.
+    idle_since: Instant,
+    /// Worker the subscribers count for, so that the guards of a stopped
+    /// worker do not count for the next one.
+    worker: u64,
+}
+
+/// Clients listening to each stream. A worker is kept running while its
+/// stream has subscribers, and for a grace period after the last one leaves.
+/// Only the streams which have a worker are tracked.
+#[derive(Default)]
+pub struct Subscriptions {
+    streams: Mutex<HashMap<String, Subscribers>>,
+    workers: AtomicU64,
+}
+
+impl Subscriptions {
+    /// Adds a subscriber to the stream until the returned guard is dropped.
+    /// The worker of the stream must have been started beforehand.
+    pub fn subscribe(state: &web::Data<AppState>, stream_id: &str) -> StreamSubscription {
+        StreamSubscription {
+            state: state.clone(),
+            stream_id: stream_id.to_owned(),
+            worker: state.subscriptions.pin(stream_id),
+        }
+    }
+
+    /// Tracks the stream as its worker is started or reused, the stream is
+    /// wanted for the grace period from then on.
+    pub fn started(&self, stream_id: &str) {
+        self.streams
+            .lock()
+            .entry(stream_id.to_owned())
+            .or_insert_with(|| Subscribers {
+                count: 0,
+                idle_since: Instant::now(),
+                worker: self.workers.fetch_add(1, Ordering::Relaxed),
+            })
+            .idle_since = Instant::now();
+    }
+
+    /// Keeps the stream wanted for the grace period, for clients which poll
+    /// instead of subscribing.
+    pub fn touch(&self, stream_id: &str) {
+        if let Some(subscribers) = self.streams.lock().get_mut(stream_id) {
+            subscribers.idle_since = Instant::now();
+        }
+    }
+
+    /// Adds a subscriber which never leaves, the stream is kept wanted for as
+    /// long as its worker runs. Returns the worker it counts for.
+    pub fn pin(&self, stream_id: &str) -> Option<u64> {
+        let mut streams = self.streams.lock();
+        let subscribers = streams.get_mut(stream_id)?;
+        subscribers.count += 1;
+        Some(subscribers.worker)
+    }
+
+    fn unsubscribe(&self, stream_id: &str, worker: u64) {
+        if let Some(subscribers) = self
+            .streams
+            .lock()
+            .get_mut(stream_id)
+            .filter(|subscribers| subscribers.worker == worker)
+        {
+            subscribers.count = subscribers.count.saturating_sub(1);
+            if subscribers.count == 0 {
+                subscribers.idle_since = Instant::now();
+            }
+        }
+    }
+
+    /// Whether the stream has subscribers or is still in its grace period.
+    pub fn is_wanted(&self, stream_id: &str) -> bool {
+        self.streams
+            .lock()
+            .get(stream_id)
+            .is_some_and(|subscribers| {
+                subscribers.count > 0 || subscribers.idle_since.elapsed() <= *STREAM_GRACE_PERIOD
+            })
+    }
+
+    /// Forgets the stream once its worker has stopped.
+    pub fn remove(&self, stream_id: &str) {
+        self.streams.lock().remove(stream_id);
+    }
+}
+
+/// A client listening to a stream, unsubscribes when dropped.
+pub struct StreamSubscription {
+    state: web::Data<AppState>,
+    stream_id: String,
+    /// Worker the subscription counts for, none if it had stopped already.
+    worker: Option<u64>,
+}
+
+impl Drop for StreamSubscription {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker {
+            self.state
+                .subscriptions
+                .unsubscribe(&self.stream_id, worker);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use actix_rt::task::JoinHandle;
+use lofigirl_shared_common::track::Track;
+use lofigirl_sys::image::ImageProcessor;
+use parking_lot::Mutex;
+use tokio::sync::watch::Sender;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::worker::ServerWorker;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A run lasting this long resets the backoff and the restart count.
+const STABLE_RUN: Duration = Duration::from_secs(600);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct SupervisedWorker {
+    cancel: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// Owns the tasks of the workers. Restarts the workers which fail or panic
+/// with an exponential backoff capped at [`MAX_BACKOFF`] while their stream
+/// is wanted, and stops all of them on shutdown.
+#[derive(Default)]
+pub struct Supervisor {
+    shutdown: CancellationToken,
+    workers: Mutex<HashMap<String, SupervisedWorker>>,
+}
+
+impl Supervisor {
+    /// Starts supervising the worker of the given stream, whose first run uses
+    /// the already opened `image_proc`, or is retried after the backoff if the
+    /// stream could not be opened. The worker stops on its own when it is not
+    /// wanted anymore, or on [`Supervisor::shutdown`].
+    pub fn spawn(
+        &self,
+        stream_id: &str,
+        worker: ServerWorker,
+        image_proc: anyhow::Result<ImageProcessor>,
+        track_tx: Sender<Track>,
+    ) {
+        let cancel = self.shutdown.child_token();
+        let handle = actix_rt::spawn(supervise(worker, image_proc, track_tx, cancel.clone()));
+        self.workers
+            .lock()
+            .insert(stream_id.to_owned(), SupervisedWorker { cancel, handle });
+    }
+
+    /// Forgets the worker of the given stream once it has stopped.
+    pub fn remove(&self, stream_id: &str) {
+        self.workers.lock().remove(stream_id);
+    }
+
+    /// Stops every worker and waits for them to clean up.
+    pub async fn shutdown(&self) {
+        self.shutdown.cancel();
+        let workers: Vec<(String, SupervisedWorker)> = self.workers.lock().drain().collect();
+        info!("Stopping {} worker(s)", workers.len());
+        for (stream_id, worker) in workers {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, worker.handle)
+                .await
+                .is_err()
+            {
+                warn!("Worker for {} did not stop in time", stream_id);
+            }
+        }
+    }
+}
+
+async fn supervise(
+    worker: ServerWorker,
+    image_proc: anyhow::Result<ImageProcessor>,
+    track_tx: Sender<Track>,
+    cancel: CancellationToken,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    let mut restarts = 0;
+    let mut released = false;
+    let mut opened = Some(image_proc);
+    loop {
+        let started = Instant::now();
+        let error = match opened.take() {
+            Some(Err(e)) => format!("Cannot open the stream: {}", e),
+            opened => {
+                // run on its own task so that a panic is caught by the join handle
+                let run = actix_rt::spawn({
+                    let worker = worker.clone();
+                    let track_tx = track_tx.clone();
+                    let cancel = cancel.clone();
+                    async move {
+                        let image_proc = match opened {
+                            Some(image_proc) => image_proc?,
+                            None => worker.open()?,
+                        };
+                        worker.run(image_proc, &track_tx, &cancel).await;
+                        anyhow::Ok(())
+                    }
+                });
+                match run.await {
+                    Ok(Ok(())) if cancel.is_cancelled() => break,
+                    Ok(Ok(())) => {
+                        if worker.release() {
+                            released = true;
+                            break;
+                        }
+                        info!("{} is wanted again, keeping the worker", worker.video_url);
+                        continue;
+                    }
+                    Ok(Err(e)) => e.to_string(),
+                    Err(e) => format!("worker panicked: {}", e),
+                }
+            }
+        };
+        if cancel.is_cancelled() {
+            break;
+        }
+        if started.elapsed() > STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+            restarts = 0;
+        }
+        restarts += 1;
+        worker.record_crash(&error);
+        // a wanted stream is retried for as long as it is wanted
+        warn!(
+            "{} has failed {} time(s) in a row, restarting in {:?}: {}",
+            worker.video_url, restarts, backoff, error
+        );
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        if !worker.is_wanted().await {
+            info!("{} is not wanted anymore, not restarting", worker.video_url);
+            break;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    if !released {
+        worker.stopped().await;
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::{HashMap, VecDeque};
+
+use lofigirl_shared_common::api::{TimelineEntry, TimelineQuery};
+use lofigirl_shared_common::track::Track;
+use parking_lot::RwLock;
+
+/// Tracks kept in memory for each stream, older ones are only in the database.
+const MAX_TIMELINE_ENTRIES: usize = 200;
+pub const DEFAULT_TIMELINE_LIMIT: u32 = 50;
+pub const MAX_TIMELINE_LIMIT: u32 = 500;
+pub const DEFAULT_PLAYING_AT_CONTEXT: u32 = 2;
+pub const MAX_PLAYING_AT_CONTEXT: u32 = 10;
+
+/// Recent tracks of every stream with a worker, oldest first.
+#[derive(Default)]
+pub struct Timelines {
+    streams: RwLock<HashMap<String, VecDeque<TimelineEntry>>>,
+}
+
+impl Timelines {
+    /// Ends the track on air, if any, and puts the given track on air.
+    pub fn start(&self, stream_id: &str, track: &Track, at: i64) {
+        let mut streams = self.streams.write();
+        let timeline = streams.entry(stream_id.to_owned()).or_default();
+        if let Some(last) = timeline.back_mut().filter(|last| last.ended_at.is_none()) {
+            last.ended_at = Some(at);
+        }
+        if timeline.len() == MAX_TIMELINE_ENTRIES {
+            timeline.pop_front();
+        }
+        timeline.push_back(TimelineEntry {
+            track: track.clone(),
+            started_at: at,
+            ended_at: None,
+        });
+    }
+
+    /// Ends the track on air, if any, as the worker of the stream stops.
+    pub fn end(&self, stream_id: &str, at: i64) {
+        if let Some(last) = self
+            .streams
+            .write()
+            .get_mut(stream_id)
+            .and_then(|timeline| timeline.back_mut())
+            .filter(|last| last.ended_at.is_none())
+        {
+            last.ended_at = Some(at);
+        }
+    }
+
+    /// Forgets the tracks of the stream once its worker has stopped, the
+    /// database still has them.
+    pub fn remove(&self, stream_id: &str) {
+        self.streams.write().remove(stream_id);
+    }
+
+    /// Returns the track on air on the stream, if any.
+    pub fn on_air(&self, stream_id: &str) -> Option<TimelineEntry> {
+        self.streams
+            .read()
+            .get(stream_id)
+            .and_then(|timeline| timeline.back())
+            .filter(|last| last.ended_at.is_none())
+            .cloned()
+    }
+
+    /// Returns the matching entries in the order of the query, or `None` if the entries in
+    /// memory might not cover the query and the database should be asked.
+    pub fn query(&self, stream_id: &str, query: &TimelineQuery) -> Option<Vec<TimelineEntry>> {
+        let streams = self.streams.read();
+        let timeline = streams.get(stream_id)?;
+        let oldest = timeline.front()?;
+        let limit = timeline_limit(query);
+        let matching = timeline.iter().filter(|entry| overlaps(entry, query));
+        let entries: Vec<TimelineEntry> = if query.oldest_first {
+            matching.take(limit).cloned().collect()
+        } else {
+            matching.rev().take(limit).cloned().collect()
+        };
+        let covered = match query.since {
+            Some(since) => since >= oldest.started_at,
+            // older tracks than the ones in memory might be in the database
+            None => !query.oldest_first && entries.len() == limit,
+        };
+        covered.then_some(entries)
+    }
+}
+
+pub fn timeline_limit(query: &TimelineQuery) -> usize {
+    query
+        .limit
+        .unwrap_or(DEFAULT_TIMELINE_LIMIT)
+        .clamp(1, MAX_TIMELINE_LIMIT) as usize
+}
+
+fn overlaps(entry: &TimelineEntry, query: &TimelineQuery) -> bool {
+    query.until.is_none_or(|until| entry.started_at < until)
+        && query
+            .since
+            .is_none_or(|since| entry.ended_at.is_none_or(|ended_at| ended_at > since))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &str = "jfKfPfyJRdk";
+
+    fn track(song: &str) -> Track {
+        Track {
+            artist: "artist".to_owned(),
+            song: song.to_owned(),
+        }
+    }
+
+    /// The lookup of the playing at endpoint, the second starting at `time`.
+    fn playing_at(timelines: &Timelines, time: i64) -> Option<Track> {
+        let query = TimelineQuery {
+            since: Some(time),
+            until: Some(time + 1),
+            limit: Some(1),
+            oldest_first: false,
+        };
+        timelines
+            .query(STREAM, &query)
+            .unwrap()
+            .pop()
+            .map(|entry| entry.track)
+    }
+
+    fn timelines() -> Timelines {
+        let timelines = Timelines::default();
+        timelines.start(STREAM, &track("first"), 100);
+        timelines.start(STREAM, &track("second"), 200);
+        timelines.end(STREAM, 300);
+        timelines
+    }
+
+    #[test]
+    fn finds_the_track_starting_at_the_time() {
+        let timelines = timelines();
+        assert_eq!(playing_at(&timelines, 100), Some(track("first")));
+        assert_eq!(playing_at(&timelines, 200), Some(track("second")));
+    }
+
+    #[test]
+    fn finds_the_next_track_when_the_time_is_the_end() {
+        let timelines = timelines();
+        assert_eq!(playing_at(&timelines, 199), Some(track("first")));
+        assert_eq!(playing_at(&timelines, 300), None);
+    }
+
+    #[test]
+    fn forgets_a_removed_stream() {
+        let timelines = timelines();
+        timelines.remove(STREAM);
+        assert!(timelines.on_air(STREAM).is_none());
+        assert!(timelines.query(STREAM, &TimelineQuery::default()).is_none());
+    }
+}
@@ -1,252 +1,328 @@
-use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 
-use actix_web::http::header::Header;
+use actix_web::http::header::{Header, RETRY_AFTER};
 use actix_web::http::StatusCode;
 
-use actix_web::{web, HttpRequest, Responder};
+use actix_web::{web, HttpRequest};
 use actix_web::{HttpResponse, Result};
 use actix_web_httpauth::headers::authorization::{Authorization, Bearer};
-use actix_ws::Message;
-use futures_util::StreamExt as _;
 use lofigirl_shared_common::api::{
-    ScrobbleRequest, SessionRequest, SessionResponse, TokenRequest, TokenResponse,
+    Action, EncryptionKeyResponse, HistoryQuery, PlayingAtQuery, RefreshRequest,
+    RenameTokenRequest, ScrobbleRequest, SessionRequest, SessionResponse, Subscription,
+    SubscriptionRequest, TimelineQuery, TokenRequest, TokenResponse, TrackQuery,
 };
 use lofigirl_shared_common::config::LastFMClientConfig;
-use lofigirl_shared_common::jwt::JWTClaims;
-use lofigirl_shared_common::track::Track;
-use lofigirl_shared_common::{REGULAR_INTERVAL, SERVER_PING_TIMEOUT_INTERVAL};
+use lofigirl_shared_common::encrypt::{server_public_key, SecureString};
+use lofigirl_shared_common::stream::StreamId;
 use lofigirl_shared_listen::listener::Listener;
-use parking_lot::RwLock;
 use serde::Serialize;
 use thiserror::Error;
-use tracing::{info, warn};
-use url::Url;
+use tracing::warn;
 
-use crate::util::YoutubeIdExtractor;
+use crate::health;
+use crate::jwt::ACCESS_TOKEN_DURATION;
+use crate::metrics::{self, ScrobbleSource};
+use crate::outbox::queue_failed_listen;
+use crate::policy::PolicyError;
+use crate::ratelimit::{retry_after_secs, WORKERS_RETRY_AFTER};
+use crate::timeline::{DEFAULT_PLAYING_AT_CONTEXT, MAX_PLAYING_AT_CONTEXT};
+use crate::worker::{get_or_start_worker, StartWorkerError};
 
 use super::AppState;
 
+/// Parses any form of a youtube link, or a bare video id.
+fn parse_stream_id(input: &str) -> Result<StreamId> {
+    input.parse().map_err(|_| {
+        actix_web::error::InternalError::new(
+            ServerResponseError::InvalidYoutubeLink,
+            StatusCode::BAD_REQUEST,
+        )
+        .into()
+    })
+}
+
+/// Rejects the request with `429`, telling the client when to retry.
+fn too_many_requests(error: ServerResponseError, retry_after: Duration) -> actix_web::Error {
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, retry_after_secs(retry_after)))
+        .json(&error);
+    actix_web::error::InternalError::from_response(error, response).into()
+}
+
+/// Takes a request from the budget of the client address, returns the address.
+pub(super) fn limit_ip(req: &HttpRequest, data: &AppState) -> Result<String> {
+    let ip = data.limits.client_ip(req);
+    data.limits
+        .ip
+        .take(&ip)
+        .map_err(|retry_after| too_many_requests(ServerResponseError::RateLimited, retry_after))?;
+    Ok(ip)
+}
+
+/// Takes a request from the budget of the token.
+fn limit_token(token: &str, data: &AppState) -> Result<()> {
+    data.limits
+        .token
+        .take(token)
+        .map_err(|retry_after| too_many_requests(ServerResponseError::RateLimited, retry_after))?;
+    Ok(())
+}
+
+/// Rejects a stream outside the policy with `403`, or with `503` if its
+/// channel cannot be looked up at the moment.
+fn stream_not_allowed(stream_id: &StreamId, e: PolicyError) -> actix_web::Error {
+    let (error, mut response) = match e {
+        PolicyError::NotAllowed => (
+            ServerResponseError::StreamNotAllowed {
+                stream_id: stream_id.to_string(),
+                reason: e.to_string(),
+            },
+            HttpResponse::Forbidden(),
+        ),
+        PolicyError::UnknownChannel(_) => (
+            ServerResponseError::ChannelUnavailable {
+                stream_id: stream_id.to_string(),
+                reason: e.to_string(),
+            },
+            HttpResponse::ServiceUnavailable(),
+        ),
+    };
+    let response = response.json(&error);
+    actix_web::error::InternalError::from_response(error, response).into()
+}
+
+/// Maps the failure to start a worker for the stream to its response.
+pub(super) fn start_worker_error(stream_id: &StreamId, e: StartWorkerError) -> actix_web::Error {
+    match e {
+        StartWorkerError::NotAllowed(e) => stream_not_allowed(stream_id, e),
+        StartWorkerError::TooManyWorkers => {
+            too_many_requests(ServerResponseError::TooManyWorkers, WORKERS_RETRY_AFTER)
+        }
+        StartWorkerError::TooManyNewStreams { retry_after } => {
+            too_many_requests(ServerResponseError::RateLimited, retry_after)
+        }
+        StartWorkerError::MissingStatus | StartWorkerError::Unavailable(_) => {
+            actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR).into()
+        }
+    }
+}
+
+fn bearer_token(req: &HttpRequest, data: &AppState) -> Result<String> {
+    let auth = Authorization::<Bearer>::parse(req)?;
+    let token = data
+        .jwt
+        .decode(auth.as_ref().token())
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::UNAUTHORIZED))?;
+    Ok(token)
+}
+
+/// Returns the token of the bearer and marks it as used, rejecting revoked tokens.
+async fn active_token(req: &HttpRequest, data: &AppState) -> Result<String> {
+    let token = bearer_token(req, data)?;
+    let active =
+        data.token_db.use_token(&token).await.map_err(|e| {
+            actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    if !active {
+        return Err(actix_web::error::InternalError::from_response(
+            ServerResponseError::TokenRevoked,
+            HttpResponse::Forbidden().json(ServerResponseError::TokenRevoked),
+        )
+        .into());
+    }
+    Ok(token)
+}
+
+/// Signs an access token and issues a refresh token for the given token.
+/// The refresh token is encrypted for the sender of `reply_to`.
+async fn token_response(
+    data: &AppState,
+    token: &str,
+    reply_to: &SecureString,
+) -> anyhow::Result<TokenResponse> {
+    let access_token = data.jwt.encode(token)?;
+    let refresh_token = data.token_db.create_refresh_token(token).await?;
+    Ok(TokenResponse {
+        access_token,
+        expires_in: ACCESS_TOKEN_DURATION.num_seconds(),
+        secure_refresh_token: reply_to.reply(refresh_token),
+    })
+}
+
 pub(crate) async fn send(
     req: HttpRequest,
     data: web::Data<AppState>,
     info: web::Json<ScrobbleRequest>,
 ) -> Result<HttpResponse> {
-    let auth = Authorization::<Bearer>::parse(&req)?;
-    let bearer_token_jwt = auth.as_ref().token().to_owned();
-    let token = JWTClaims::decode(bearer_token_jwt)
-        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::UNAUTHORIZED))?;
+    limit_ip(&req, &data)?;
+    let token = active_token(&req, &data).await?;
+    limit_token(&token, &data)?;
     let info = info.into_inner();
-    let mut listener = Listener::default();
-    let (lfm, lb) = data
-        .token_db
-        .get_info_from_token(&token)
+    let listener = data
+        .listener_for_token(&token)
         .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
-    if let Some(lastfm_client_session) = lfm {
-        if let Some(api) = &data.lastfm_api {
+    let stream_id = info
+        .stream_url
+        .as_deref()
+        .and_then(|url| url.parse::<StreamId>().ok());
+    // a listen keeps its time when it is sent again, so that the services
+    // can recognize it if it got through although its request failed
+    let listened_at = info
+        .listened_at
+        .unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let report = match info.action {
+        Action::Listened => {
             listener
-                .set_lastfm_listener(api, &LastFMClientConfig::SessionAuth(lastfm_client_session))
-                .map_err(|e| {
-                    actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
-                })?;
+                .send_listen_report_at(&info.track, listened_at)
+                .await
         }
+        action => listener.send_action_report(action, &info.track).await,
+    };
+    metrics::observe_scrobble(ScrobbleSource::Client, &report);
+    if let Err(e) = data
+        .token_db
+        .record_scrobble(
+            &token,
+            info.action,
+            &info.track,
+            stream_id.as_ref().map(StreamId::as_str),
+            &report,
+        )
+        .await
+    {
+        warn!("Cannot record scrobble: {}", e);
     }
-    if let Some(listenbrainz) = lb {
-        listener
-            .set_listenbrainz_listener(&listenbrainz)
-            .map_err(|e| {
-                actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
-            })?;
-    }
-    match info.action {
-        lofigirl_shared_common::api::Action::PlayingNow => {
-            listener.send_now_playing(&info.track).map_err(|e| {
-                actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
-            })?;
-        }
-        lofigirl_shared_common::api::Action::Listened => {
-            listener.send_listen(&info.track).map_err(|e| {
-                actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
-            })?;
-        }
+    // failed listens are sent again later, now playing is only useful now
+    if matches!(info.action, Action::Listened)
+        && queue_failed_listen(&data, &token, &info.track, listened_at, &report).await
+    {
+        return Ok(HttpResponse::Accepted().finish());
     }
+    report
+        .into_result()
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
     Ok(HttpResponse::Ok().finish())
 }
 
-pub(crate) async fn dynamic_track(
+pub(crate) async fn history(
+    req: HttpRequest,
     data: web::Data<AppState>,
-    url: web::Path<String>,
+    query: web::Query<HistoryQuery>,
 ) -> Result<HttpResponse> {
-    let youtube_url_string = url.into_inner();
-    let youtube_url = Url::parse(&youtube_url_string)
+    let token = active_token(&req, &data).await?;
+    let page = data
+        .token_db
+        .get_history(&token, &query)
+        .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
-    let youtube_id = youtube_url
-        .get_video_id()
-        .ok_or(actix_web::error::InternalError::new(
-            ServerResponseError::InvalidYoutubeLink,
-            StatusCode::INTERNAL_SERVER_ERROR,
-        ))?;
-    // modify the last requested time
-    // even with explicit drop, clippy still complains about it so wrap it in a block
-    {
-        let mut last_requested = data.last_requested.write();
-        last_requested.insert(youtube_id.clone(), Instant::now());
-    }
+    Ok(HttpResponse::Ok().json(page))
+}
 
-    // Check if there is a working image processor
-    if let Some(track) = data.tracks.read().get(&youtube_id) {
-        // return track
-        return Ok(HttpResponse::Ok().json(track));
-    }
-    // Create the new worker and send accepted response
-    let state = data.clone();
-    // Rest API cannot use event based two-way communication, so we ignore tx,rx but we create it anyway for future connections
-    let (tx, _rx) = tokio::sync::watch::channel(Track::default());
-    let mut worker = crate::worker::ServerWorker::new(youtube_url, state.clone())
+pub(crate) async fn outbox_status(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let status =
+        data.token_db.get_outbox_status(&token).await.map_err(|e| {
+            actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+pub(crate) async fn list_streams(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let streams = data
+        .streams()
+        .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
-    worker
-        .work(tx)
+    Ok(HttpResponse::Ok().json(streams))
+}
+
+pub(crate) async fn stream_history(
+    data: web::Data<AppState>,
+    stream_id: web::Path<String>,
+    query: web::Query<TimelineQuery>,
+) -> Result<HttpResponse> {
+    let stream_id = parse_stream_id(&stream_id)?;
+    let entries = data
+        .stream_timeline(stream_id.as_str(), &query)
         .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
-    Ok(HttpResponse::Accepted().finish())
+    Ok(HttpResponse::Ok().json(entries))
 }
 
-pub(crate) async fn track_socket(
+pub(crate) async fn playing_at(
     data: web::Data<AppState>,
+    stream_id: web::Path<String>,
+    query: web::Query<PlayingAtQuery>,
+) -> Result<HttpResponse> {
+    let stream_id = parse_stream_id(&stream_id)?;
+    let time = query.time.resolve(chrono::Utc::now());
+    // the track is looked up in the second starting at the time
+    if time.checked_add(1).is_none() {
+        return Err(actix_web::error::InternalError::new(
+            ServerResponseError::TimeOutOfRange,
+            StatusCode::BAD_REQUEST,
+        )
+        .into());
+    }
+    let context = query
+        .context
+        .unwrap_or(DEFAULT_PLAYING_AT_CONTEXT)
+        .min(MAX_PLAYING_AT_CONTEXT);
+    let response = data
+        .playing_at(stream_id.as_str(), time, context)
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub(crate) async fn dynamic_track(
     req: HttpRequest,
-    body: web::Payload,
-) -> actix_web::Result<impl Responder> {
-    let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
-    actix_web::rt::spawn(async move {
-        let last_ping = Arc::new(RwLock::new(Instant::now()));
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(msg) => match Url::parse(&msg) {
-                    Ok(youtube_url) => {
-                        info!("Server received {youtube_url} from socket");
-                        // Update last requested state
-                        let state = data.clone();
-                        let youtube_id = youtube_url.get_video_id();
-                        match youtube_id {
-                            Some(youtube_id) => {
-                                let youtube_id_clone = youtube_id.clone();
-                                actix_rt::spawn(async move {
-                                    // periodic update
-                                    loop {
-                                        {
-                                            let mut last_requested = state.last_requested.write();
-                                            last_requested
-                                                .insert(youtube_id_clone.clone(), Instant::now());
-                                        }
-                                        tokio::time::sleep(*REGULAR_INTERVAL).await;
-                                    }
-                                });
-                                // Check if there is a worker already find its rx channel otherwise create worker and bring its rx channel
-                                let state = data.clone();
-                                let should_reuse =
-                                    data.track_channels.read().contains_key(&youtube_id);
-                                let mut rx = if should_reuse {
-                                    info!("Found existing worker for given video, reuse worker");
-                                    if let Some(rx) = data.track_channels.read().get(&youtube_id) {
-                                        rx.clone()
-                                    } else {
-                                        warn!("reuse failure");
-                                        break;
-                                    }
-                                } else {
-                                    let (tx, rx) = tokio::sync::watch::channel(Track::default());
-                                    let mut worker = match crate::worker::ServerWorker::new(
-                                        youtube_url.clone(),
-                                        state.clone(),
-                                    ) {
-                                        Ok(worker) => worker,
-                                        Err(_) => {
-                                            warn!("creation failure");
-                                            break;
-                                        }
-                                    };
-                                    match worker.work(tx).await {
-                                        Ok(_) => {
-                                            data.track_channels
-                                                .write()
-                                                .insert(youtube_id.clone(), rx.clone());
-                                            rx
-                                        }
-                                        Err(_) => {
-                                            warn!("work failure");
-                                            break;
-                                        }
-                                    }
-                                };
-
-                                // send new message on channel update
-                                let mut session_clone = session.clone();
-                                actix_rt::spawn(async move {
-                                    loop {
-                                        if rx.changed().await.is_err() {
-                                            break;
-                                        }
-                                        let track = rx.borrow_and_update().clone();
-                                        // if track is empty, we might have created the channel with default empty track
-                                        // so skip this and wait for the next one
-                                        if !track.is_empty() {
-                                            let ser_track = serde_json::to_string(&track).unwrap();
-                                            if session_clone.text(ser_track).await.is_err() {
-                                                break;
-                                            }
-                                        }
-                                    }
-                                });
-
-                                // if client does not ping for some time - close socket
-                                let last_ping = last_ping.clone();
-                                let session_clone = session.clone();
-                                actix_rt::spawn(async move {
-                                    loop {
-                                        if last_ping.read().elapsed()
-                                            > *SERVER_PING_TIMEOUT_INTERVAL
-                                        {
-                                            break;
-                                        }
-                                        tokio::time::sleep(*REGULAR_INTERVAL).await;
-                                    }
-                                    warn!("Did not receive ping from socket for a while, closing");
-                                    let _ = session_clone.close(None).await;
-                                });
-                            }
-                            None => {
-                                warn!("Cannot parse url into youtube id");
-                                break;
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        warn!("Cannot parse the string into url");
-                        break;
-                    }
-                },
-                Message::Ping(bytes) | Message::Binary(bytes) => {
-                    info!("Server received ping from socket");
-                    // client ping updates last ping and also last requested for server worker
-                    *last_ping.write() = Instant::now();
-                    session.pong(&bytes).await.unwrap();
-                }
-                _ => break,
-            }
-        }
-        let _ = session.close(None).await;
-    });
+    data: web::Data<AppState>,
+    url: web::Path<String>,
+) -> Result<HttpResponse> {
+    let ip = limit_ip(&req, &data)?;
+    track_response(&data, &url.into_inner(), &ip).await
+}
+
+pub(crate) async fn track_by_query(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    query: web::Query<TrackQuery>,
+) -> Result<HttpResponse> {
+    let ip = limit_ip(&req, &data)?;
+    track_response(&data, &query.url, &ip).await
+}
 
-    Ok(response)
+async fn track_response(data: &web::Data<AppState>, url: &str, ip: &str) -> Result<HttpResponse> {
+    let youtube_id = parse_stream_id(url)?;
+    // polling keeps a running worker for the grace period, starting one does as well
+    data.subscriptions.touch(youtube_id.as_str());
+
+    // Check if there is a working image processor
+    if let Some(track) = data.tracks.read().get(youtube_id.as_str()) {
+        // return track
+        return Ok(HttpResponse::Ok().json(track));
+    }
+    // Create the new worker if needed and send accepted response
+    // Rest API cannot use event based two-way communication, so we ignore rx but the worker keeps it for future connections
+    get_or_start_worker(data, &youtube_id, Some(ip))
+        .await
+        .map_err(|e| start_worker_error(&youtube_id, e))?;
+    Ok(HttpResponse::Accepted().finish())
 }
 
 pub(crate) async fn session(
+    req: HttpRequest,
     data: web::Data<AppState>,
     info: web::Json<SessionRequest>,
 ) -> Result<HttpResponse> {
+    limit_ip(&req, &data)?;
     let info = info.into_inner();
     if let Some(api) = &data.lastfm_api {
+        let reply_to = info.secure_password.clone();
         let session_config = Listener::convert_client_to_session(
             api,
             &LastFMClientConfig::PasswordAuth(
@@ -256,9 +332,10 @@ pub(crate) async fn session(
                 },
             ),
         )
+        .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
         Ok(HttpResponse::Ok().json(SessionResponse {
-            secure_session_key: session_config.session_key.into(),
+            secure_session_key: reply_to.reply(session_config.session_key),
         }))
     } else {
         Ok(HttpResponse::NotFound().json(ServerResponseError::APINotAvailable))
@@ -266,31 +343,223 @@ pub(crate) async fn session(
 }
 
 pub(crate) async fn token(
+    req: HttpRequest,
     data: web::Data<AppState>,
     info: web::Json<TokenRequest>,
 ) -> Result<HttpResponse> {
+    limit_ip(&req, &data)?;
     let info = info.into_inner();
+    let Some(reply_to) = info
+        .secure_lastfm_session_key
+        .clone()
+        .or_else(|| info.secure_listenbrainz_token.clone())
+    else {
+        return Ok(HttpResponse::BadRequest().json(ServerResponseError::MissingCredentials));
+    };
     let token = data
         .token_db
         .get_or_generate_token(
             info.secure_lastfm_session_key.map(|s| s.into()).as_ref(),
             info.secure_listenbrainz_token.map(|s| s.into()).as_ref(),
+            info.label.as_deref(),
         )
         .await
         .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let response = token_response(&data, &token, &reply_to)
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub(crate) async fn refresh_token(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    info: web::Json<RefreshRequest>,
+) -> Result<HttpResponse> {
+    limit_ip(&req, &data)?;
+    let reply_to = info.into_inner().secure_refresh_token;
+    let refresh_token: String = reply_to.clone().into();
+    let rotated = data
+        .token_db
+        .rotate_refresh_token(&refresh_token)
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let Some((token, new_refresh_token)) = rotated else {
+        return Ok(HttpResponse::Unauthorized().json(ServerResponseError::InvalidRefreshToken));
+    };
+    let access_token = data
+        .jwt
+        .encode(&token)
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
     Ok(HttpResponse::Ok().json(TokenResponse {
-        secure_token: token.into(),
+        access_token,
+        expires_in: ACCESS_TOKEN_DURATION.num_seconds(),
+        secure_refresh_token: reply_to.reply(new_refresh_token),
     }))
 }
 
+pub(crate) async fn encryption_key() -> Result<HttpResponse> {
+    let (key_id, public_key_base64) = server_public_key()
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(HttpResponse::Ok().json(EncryptionKeyResponse {
+        key_id,
+        public_key_base64,
+    }))
+}
+
+pub(crate) async fn subscribe(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    info: web::Json<SubscriptionRequest>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let info = info.into_inner();
+    let stream_id = parse_stream_id(&info.stream_url)?;
+    data.policy
+        .check(&stream_id)
+        .await
+        .map_err(|e| stream_not_allowed(&stream_id, e))?;
+    // the subscription is stored only once its worker runs within the limits,
+    // the new worker is kept for the grace period until it sees the subscription
+    let ip = data.limits.client_ip(&req);
+    get_or_start_worker(&data, &stream_id, Some(&ip))
+        .await
+        .map_err(|e| start_worker_error(&stream_id, e))?;
+    let stream_url = stream_id.url();
+    let id = data
+        .token_db
+        .add_subscription(
+            &token,
+            stream_id.as_str(),
+            stream_url.as_str(),
+            info.schedule.as_ref(),
+        )
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    Ok(HttpResponse::Ok().json(Subscription {
+        id,
+        stream_url: stream_url.into(),
+        schedule: info.schedule,
+    }))
+}
+
+pub(crate) async fn list_subscriptions(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let subscriptions =
+        data.token_db.get_subscriptions(&token).await.map_err(|e| {
+            actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    Ok(HttpResponse::Ok().json(subscriptions))
+}
+
+pub(crate) async fn unsubscribe(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let removed = data
+        .token_db
+        .remove_subscription(&token, id.into_inner())
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    if removed {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::NotFound().json(ServerResponseError::SubscriptionNotFound))
+    }
+}
+
+pub(crate) async fn list_tokens(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let tokens =
+        data.token_db.get_tokens(&token).await.map_err(|e| {
+            actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
+        })?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub(crate) async fn rename_token(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+    info: web::Json<RenameTokenRequest>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let renamed = data
+        .token_db
+        .rename_token(&token, id.into_inner(), &info.label)
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    if renamed {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::NotFound().json(ServerResponseError::TokenNotFound))
+    }
+}
+
+pub(crate) async fn revoke_token(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse> {
+    let token = active_token(&req, &data).await?;
+    let revoked = data
+        .token_db
+        .revoke_token(&token, id.into_inner())
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    if revoked {
+        Ok(HttpResponse::Ok().finish())
+    } else {
+        Ok(HttpResponse::NotFound().json(ServerResponseError::TokenNotFound))
+    }
+}
+
+/// Liveness, only tells that the server responds.
 pub(crate) async fn health() -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().finish())
 }
 
+pub(crate) async fn readiness(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let response = health::readiness(&data).await;
+    if response.ready {
+        Ok(HttpResponse::Ok().json(response))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(response))
+    }
+}
+
 #[derive(Error, Debug, Serialize)]
 pub enum ServerResponseError {
     #[error("LastFM API is not available")]
     APINotAvailable,
     #[error("Youtube link is not valid")]
     InvalidYoutubeLink,
+    #[error("Subscription is not found")]
+    SubscriptionNotFound,
+    #[error("Refresh token is not valid")]
+    InvalidRefreshToken,
+    #[error("Either Last.fm session key or ListenBrainz token is required")]
+    MissingCredentials,
+    #[error("Token has been revoked")]
+    TokenRevoked,
+    #[error("Token is not found")]
+    TokenNotFound,
+    #[error("Too many requests, retry later")]
+    RateLimited,
+    #[error("Too many streams are followed, retry later")]
+    TooManyWorkers,
+    #[error("Stream {stream_id} is not allowed: {reason}")]
+    StreamNotAllowed { stream_id: String, reason: String },
+    #[error("Stream {stream_id} cannot be checked at the moment: {reason}")]
+    ChannelUnavailable { stream_id: String, reason: String },
+    #[error("Time is out of range")]
+    TimeOutOfRange,
 }
@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use lofigirl_shared_common::api::{SocketError, SocketMessage};
+use lofigirl_shared_common::stream::StreamId;
+use lofigirl_shared_common::track::Track;
+use lofigirl_shared_common::REGULAR_INTERVAL;
+use tokio::time::Interval;
+use tracing::warn;
+
+use crate::subscription::{StreamSubscription, Subscriptions};
+use crate::timeline::MAX_TIMELINE_LIMIT;
+use crate::worker::{get_or_start_worker, StreamFeed};
+
+use super::endpoints::{limit_ip, start_worker_error};
+use super::socket::status_message;
+use super::AppState;
+
+/// Server-Sent Events of a stream. Track events carry the row id of the
+/// track in the stream history as their id, which clients resume from.
+struct EventFeed {
+    data: web::Data<AppState>,
+    stream_id: String,
+    feed: StreamFeed,
+    /// Events to send before the live ones.
+    pending: VecDeque<web::Bytes>,
+    /// Row id of the last track sent, the tracks after it are sent next.
+    last_id: i64,
+    last_track: Track,
+    keep_alive: Interval,
+    stopped: bool,
+    _subscription: StreamSubscription,
+}
+
+pub(crate) async fn stream_events(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    stream_id: web::Path<String>,
+) -> Result<HttpResponse> {
+    let stream_id = stream_id
+        .into_inner()
+        .parse::<StreamId>()
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::BAD_REQUEST))?;
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<i64>().ok());
+    let ip = limit_ip(&req, &data)?;
+    let mut feed = get_or_start_worker(&data, &stream_id, Some(&ip))
+        .await
+        .map_err(|e| start_worker_error(&stream_id, e))?;
+    let subscription = Subscriptions::subscribe(&data, stream_id.as_str());
+    let stream_id = String::from(stream_id);
+    // a new client starts with the track on air
+    let last_id = match last_event_id {
+        Some(last_id) => last_id,
+        None => data
+            .token_db
+            .get_last_ended_stream_track_id(&stream_id)
+            .await
+            .map_err(|e| {
+                actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR)
+            })?
+            .unwrap_or_default(),
+    };
+    let mut pending = VecDeque::new();
+    let state = *feed.states.borrow_and_update();
+    pending.push_back(event(
+        "status",
+        None,
+        &status_message(&data, &stream_id, state),
+    ));
+    let mut events = EventFeed {
+        data,
+        stream_id,
+        feed,
+        pending,
+        last_id,
+        last_track: Track::default(),
+        keep_alive: tokio::time::interval(*REGULAR_INTERVAL),
+        stopped: false,
+        _subscription: subscription,
+    };
+    // replay the tracks started after the last event the client has seen
+    events
+        .queue_new_tracks()
+        .await
+        .map_err(|e| actix_web::error::InternalError::new(e, StatusCode::INTERNAL_SERVER_ERROR))?;
+    let stream = futures_util::stream::unfold(events, |mut events| async move {
+        let event = events.next_event().await?;
+        Some((Ok::<_, actix_web::Error>(event), events))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
+
+impl EventFeed {
+    /// Waits for the next event, `None` once the worker has stopped.
+    async fn next_event(&mut self) -> Option<web::Bytes> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        if self.stopped {
+            return None;
+        }
+        loop {
+            tokio::select! {
+                changed = self.feed.tracks.changed() => {
+                    if changed.is_err() {
+                        return Some(self.stop());
+                    }
+                    let track = self.feed.tracks.borrow_and_update().clone();
+                    // the channel starts with an empty track, and the track
+                    // might have been replayed from the history already
+                    if track.is_empty() || track == self.last_track {
+                        continue;
+                    }
+                    // the worker records the track in the history before
+                    // publishing it
+                    match self.queue_new_tracks().await {
+                        Ok(0) => {}
+                        Ok(_) => return self.pending.pop_front(),
+                        Err(e) => warn!("Cannot read the history of {}: {}", self.stream_id, e),
+                    }
+                    // not in the history, so it cannot be resumed from
+                    return Some(self.track_event(None, track));
+                }
+                changed = self.feed.states.changed() => {
+                    if changed.is_err() {
+                        return Some(self.stop());
+                    }
+                    let state = *self.feed.states.borrow_and_update();
+                    let message = status_message(&self.data, &self.stream_id, state);
+                    return Some(event("status", None, &message));
+                }
+                // keeps proxies from closing an idle connection
+                _ = self.keep_alive.tick() => {
+                    return Some(web::Bytes::from_static(b": keep-alive\n\n"));
+                }
+            }
+        }
+    }
+
+    /// Queues the tracks recorded after the last track sent, returns how many.
+    async fn queue_new_tracks(&mut self) -> anyhow::Result<usize> {
+        let tracks = self
+            .data
+            .token_db
+            .get_stream_tracks_after(&self.stream_id, self.last_id, MAX_TIMELINE_LIMIT)
+            .await?;
+        let count = tracks.len();
+        for (id, track) in tracks {
+            self.last_id = id;
+            let event = self.track_event(Some(id), track);
+            self.pending.push_back(event);
+        }
+        Ok(count)
+    }
+
+    fn track_event(&mut self, id: Option<i64>, track: Track) -> web::Bytes {
+        self.last_track = track.clone();
+        let message = SocketMessage::Track {
+            stream_id: self.stream_id.clone(),
+            track,
+        };
+        event("track", id, &message)
+    }
+
+    fn stop(&mut self) -> web::Bytes {
+        self.stopped = true;
+        let message = SocketMessage::Error {
+            stream_id: Some(self.stream_id.clone()),
+            error: SocketError::StreamStopped,
+        };
+        event("error", None, &message)
+    }
+}
+
+fn event(name: &str, id: Option<i64>, message: &SocketMessage) -> web::Bytes {
+    let data = serde_json::to_string(message).unwrap_or_default();
+    let event = match id {
+        Some(id) => format!("id: {}\nevent: {}\ndata: {}\n\n", id, name, data),
+        None => format!("event: {}\ndata: {}\n\n", name, data),
+    };
+    web::Bytes::from(event)
+}
@@ -1,43 +1,201 @@
 mod endpoints;
+mod events;
+mod socket;
 
 use std::collections::HashMap;
-use std::time::Instant;
 
+use crate::jwt::JWTSigner;
+use crate::metrics;
+use crate::policy::StreamPolicy;
+use crate::ratelimit::RateLimits;
 use crate::session::TokenDB;
+use crate::subscription::Subscriptions;
+use crate::supervisor::Supervisor;
+use crate::timeline::Timelines;
+use crate::worker::WorkerStatus;
 use actix_cors::Cors;
 
 use actix_web::{web, App, HttpServer};
-use endpoints::{dynamic_track, health, send, session, token, track_socket};
-use lofigirl_shared_common::config::LastFMApiConfig;
+use endpoints::{
+    dynamic_track, encryption_key, health, history, list_streams, list_subscriptions, list_tokens,
+    outbox_status, playing_at, readiness, refresh_token, rename_token, revoke_token, send, session,
+    stream_history, subscribe, token, track_by_query, unsubscribe,
+};
+use events::stream_events;
+use lofigirl_shared_common::api::{
+    DependencyStatus, PlayingAtResponse, StreamInfo, TimelineEntry, TimelineQuery,
+};
+use lofigirl_shared_common::config::{LastFMApiConfig, LastFMClientConfig, OverlayConfig};
 use lofigirl_shared_common::track::Track;
 use lofigirl_shared_common::{
-    HEALTH_END_POINT, LASTFM_SESSION_END_POINT, SEND_END_POINT, TOKEN_END_POINT, TRACK_END_POINT,
-    TRACK_SOCKET_END_POINT,
+    ENCRYPTION_KEY_END_POINT, HEALTH_END_POINT, HISTORY_END_POINT, LASTFM_SESSION_END_POINT,
+    LIVENESS_END_POINT, OUTBOX_END_POINT, READINESS_END_POINT, SEND_END_POINT, STREAMS_END_POINT,
+    SUBSCRIPTION_END_POINT, TOKENS_END_POINT, TOKEN_END_POINT, TOKEN_REFRESH_END_POINT,
+    TRACK_END_POINT, TRACK_SOCKET_END_POINT,
 };
+use lofigirl_shared_listen::listener::Listener;
 use parking_lot::RwLock;
+use socket::track_socket;
 use tokio::sync::watch::Receiver;
 
 pub struct AppState {
     pub lastfm_api: Option<LastFMApiConfig>,
+    pub overlay: OverlayConfig,
     pub tracks: RwLock<HashMap<String, Track>>,
-    pub last_requested: RwLock<HashMap<String, Instant>>,
+    pub subscriptions: Subscriptions,
     pub track_channels: RwLock<HashMap<String, Receiver<Track>>>,
+    pub timelines: Timelines,
+    pub workers: RwLock<HashMap<String, WorkerStatus>>,
+    pub supervisor: Supervisor,
     pub token_db: TokenDB,
+    pub jwt: JWTSigner,
+    pub limits: RateLimits,
+    pub policy: StreamPolicy,
+    /// Last results of the OCR and capture dependency checks.
+    pub dependencies: RwLock<Vec<DependencyStatus>>,
 }
 
 impl AppState {
-    pub async fn new(
+    pub fn new(
         api: Option<LastFMApiConfig>,
-        token_db_file: &str,
-    ) -> anyhow::Result<AppState> {
-        Ok(AppState {
+        overlay: OverlayConfig,
+        token_db: TokenDB,
+        jwt: JWTSigner,
+        limits: RateLimits,
+        policy: StreamPolicy,
+    ) -> AppState {
+        AppState {
             lastfm_api: api,
-            token_db: TokenDB::new(token_db_file).await?,
+            overlay,
+            token_db,
+            jwt,
+            limits,
+            policy,
             tracks: RwLock::new(HashMap::new()),
             track_channels: RwLock::new(HashMap::new()),
-            last_requested: RwLock::new(HashMap::new()),
+            subscriptions: Subscriptions::default(),
+            timelines: Timelines::default(),
+            workers: RwLock::new(HashMap::new()),
+            supervisor: Supervisor::default(),
+            dependencies: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Lists every stream with a running worker.
+    pub async fn streams(&self) -> anyhow::Result<Vec<StreamInfo>> {
+        let subscriber_counts = self.token_db.get_subscriber_counts().await?;
+        let mut streams: Vec<StreamInfo> = self
+            .workers
+            .read()
+            .iter()
+            .map(|(stream_id, status)| {
+                let on_air = self.timelines.on_air(stream_id);
+                StreamInfo {
+                    stream_id: stream_id.clone(),
+                    stream_url: status.stream_url.clone(),
+                    track_started_at: on_air.as_ref().map(|entry| entry.started_at),
+                    track: on_air.map(|entry| entry.track),
+                    subscribers: subscriber_counts.get(stream_id).copied().unwrap_or(0),
+                    state: status.state,
+                    last_error: status.last_error.clone(),
+                    last_error_at: status.last_error_at,
+                }
+            })
+            .collect();
+        streams.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
+        Ok(streams)
+    }
+
+    /// Returns the tracks of the stream matching the query, in the order of the query.
+    pub async fn stream_timeline(
+        &self,
+        stream_id: &str,
+        query: &TimelineQuery,
+    ) -> anyhow::Result<Vec<TimelineEntry>> {
+        match self.timelines.query(stream_id, query) {
+            Some(entries) => Ok(entries),
+            None => self.token_db.get_stream_timeline(stream_id, query).await,
+        }
+    }
+
+    /// Looks up the track on air on the stream at the given time, along with
+    /// `context` tracks on each side of it.
+    pub async fn playing_at(
+        &self,
+        stream_id: &str,
+        time: i64,
+        context: u32,
+    ) -> anyhow::Result<PlayingAtResponse> {
+        let track = self
+            .stream_timeline(
+                stream_id,
+                &TimelineQuery {
+                    since: Some(time),
+                    until: Some(time.saturating_add(1)),
+                    limit: Some(1),
+                    oldest_first: false,
+                },
+            )
+            .await?
+            .pop();
+        let mut before = if context == 0 {
+            Vec::new()
+        } else {
+            self.stream_timeline(
+                stream_id,
+                &TimelineQuery {
+                    since: None,
+                    until: Some(track.as_ref().map_or(time, |t| t.started_at)),
+                    limit: Some(context),
+                    oldest_first: false,
+                },
+            )
+            .await?
+        };
+        before.reverse();
+        let after_since = match &track {
+            Some(track) => track.ended_at,
+            None => Some(time),
+        };
+        let after = match after_since.filter(|_| context > 0) {
+            Some(since) => {
+                self.stream_timeline(
+                    stream_id,
+                    &TimelineQuery {
+                        since: Some(since),
+                        until: None,
+                        limit: Some(context),
+                        oldest_first: true,
+                    },
+                )
+                .await?
+            }
+            None => Vec::new(),
+        };
+        Ok(PlayingAtResponse {
+            time,
+            track,
+            before,
+            after,
         })
     }
+
+    /// Builds a listener with the services stored for the given token.
+    pub async fn listener_for_token(&self, token: &str) -> anyhow::Result<Listener> {
+        let mut listener = Listener::new()?;
+        let (lfm, lb) = self.token_db.get_info_from_token(token).await?;
+        if let Some(lastfm_client_session) = lfm
+            && let Some(api) = &self.lastfm_api
+        {
+            listener
+                .set_lastfm_listener(api, &LastFMClientConfig::SessionAuth(lastfm_client_session))
+                .await?;
+        }
+        if let Some(listenbrainz) = lb {
+            listener.set_listenbrainz_listener(&listenbrainz).await?;
+        }
+        Ok(listener)
+    }
 }
 pub struct LofiServer;
 
@@ -49,7 +207,9 @@ impl LofiServer {
             App::new()
                 .wrap(cors)
                 .app_data(data.clone())
+                .configure(metrics::configure)
                 // dynamic track endpoint
+                .route(TRACK_END_POINT, web::get().to(track_by_query))
                 .route(
                     &format!("{}/{{url}}", TRACK_END_POINT),
                     web::get().to(dynamic_track),
@@ -57,9 +217,43 @@ impl LofiServer {
                 // event based track socket endpoint
                 .route(TRACK_SOCKET_END_POINT, web::get().to(track_socket))
                 .route(SEND_END_POINT, web::post().to(send))
+                .route(HISTORY_END_POINT, web::get().to(history))
+                .route(OUTBOX_END_POINT, web::get().to(outbox_status))
+                .route(STREAMS_END_POINT, web::get().to(list_streams))
+                .route(
+                    &format!("{}/{{id}}/history", STREAMS_END_POINT),
+                    web::get().to(stream_history),
+                )
+                .route(
+                    &format!("{}/{{id}}/at", STREAMS_END_POINT),
+                    web::get().to(playing_at),
+                )
+                .route(
+                    &format!("{}/{{id}}/events", STREAMS_END_POINT),
+                    web::get().to(stream_events),
+                )
                 .route(LASTFM_SESSION_END_POINT, web::post().to(session))
                 .route(TOKEN_END_POINT, web::post().to(token))
+                .route(ENCRYPTION_KEY_END_POINT, web::get().to(encryption_key))
+                .route(TOKEN_REFRESH_END_POINT, web::post().to(refresh_token))
+                .route(TOKENS_END_POINT, web::get().to(list_tokens))
+                .route(
+                    &format!("{}/{{id}}", TOKENS_END_POINT),
+                    web::patch().to(rename_token),
+                )
+                .route(
+                    &format!("{}/{{id}}", TOKENS_END_POINT),
+                    web::delete().to(revoke_token),
+                )
                 .route(HEALTH_END_POINT, web::get().to(health))
+                .route(LIVENESS_END_POINT, web::get().to(health))
+                .route(READINESS_END_POINT, web::get().to(readiness))
+                .route(SUBSCRIPTION_END_POINT, web::get().to(list_subscriptions))
+                .route(SUBSCRIPTION_END_POINT, web::post().to(subscribe))
+                .route(
+                    &format!("{}/{{id}}", SUBSCRIPTION_END_POINT),
+                    web::delete().to(unsubscribe),
+                )
         })
         .bind(format!("0.0.0.0:{}", port))?
         // .bind(format!("127.0.0.1:{}", port))?
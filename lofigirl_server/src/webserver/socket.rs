@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use actix_rt::task::JoinHandle;
+use actix_web::{web, HttpRequest, Responder};
+use actix_ws::{Message, Session};
+use futures_util::StreamExt as _;
+use lofigirl_shared_common::api::{
+    SocketError, SocketMessage, WorkerState, SOCKET_PROTOCOL_VERSION,
+};
+use lofigirl_shared_common::stream::StreamId;
+use lofigirl_shared_common::{REGULAR_INTERVAL, SERVER_PING_TIMEOUT_INTERVAL};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{info, warn};
+
+use crate::metrics;
+use crate::policy::PolicyError;
+use crate::ratelimit::{retry_after_secs, WORKERS_RETRY_AFTER};
+use crate::subscription::{StreamSubscription, Subscriptions};
+use crate::worker::{get_or_start_worker, StartWorkerError, StreamFeed};
+
+use super::endpoints::limit_ip;
+use super::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    /// Clients before the versioned protocol send a bare stream url and
+    /// receive bare tracks, for a single stream.
+    Legacy,
+    Versioned,
+}
+
+/// A stream followed by a socket. Unsubscribes and stops forwarding when dropped.
+struct FollowedStream {
+    _subscription: StreamSubscription,
+    forward: JoinHandle<()>,
+}
+
+impl FollowedStream {
+    /// Whether the updates of the stream are still forwarded, they are not
+    /// once its worker has stopped.
+    fn is_alive(&self) -> bool {
+        !self.forward.is_finished()
+    }
+}
+
+impl Drop for FollowedStream {
+    fn drop(&mut self) {
+        self.forward.abort();
+    }
+}
+
+struct TrackSocket {
+    data: web::Data<AppState>,
+    session: Session,
+    protocol: Option<Protocol>,
+    streams: HashMap<String, FollowedStream>,
+    /// Address of the client, new streams count against its budget.
+    client_ip: String,
+    /// Streams whose forwarding has ended, to be forgotten.
+    stopped_tx: UnboundedSender<String>,
+}
+
+pub(crate) async fn track_socket(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> actix_web::Result<impl Responder> {
+    let client_ip = limit_ip(&req, &data)?;
+    let (response, session, mut msg_stream) = actix_ws::handle(&req, body)?;
+    actix_web::rt::spawn(async move {
+        metrics::socket_opened();
+        let (stopped_tx, mut stopped_rx) = mpsc::unbounded_channel();
+        let mut socket = TrackSocket {
+            data,
+            session: session.clone(),
+            protocol: None,
+            streams: HashMap::new(),
+            client_ip,
+            stopped_tx,
+        };
+        let mut last_ping = Instant::now();
+        let mut ping_check = tokio::time::interval(*REGULAR_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                msg = msg_stream.next() => msg,
+                _ = ping_check.tick() => {
+                    // if client does not ping for some time - close socket
+                    if last_ping.elapsed() > *SERVER_PING_TIMEOUT_INTERVAL {
+                        warn!("Did not receive ping from socket for a while, closing");
+                        break;
+                    }
+                    continue;
+                }
+                Some(stream_id) = stopped_rx.recv() => {
+                    // unsubscribes from the stream of the stopped worker
+                    if socket.streams.get(&stream_id).is_some_and(|s| !s.is_alive()) {
+                        socket.streams.remove(&stream_id);
+                    }
+                    continue;
+                }
+            };
+            match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if !socket.handle_text(&text).await {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Ping(bytes) | Message::Binary(bytes))) => {
+                    info!("Server received ping from socket");
+                    // client ping updates last ping, the socket is closed without it
+                    last_ping = Instant::now();
+                    if socket.session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        // unsubscribes from every stream
+        drop(socket);
+        let _ = session.close(None).await;
+        metrics::socket_closed();
+    });
+
+    Ok(response)
+}
+
+impl TrackSocket {
+    /// Handles a text message, returns `false` if the socket should be closed.
+    async fn handle_text(&mut self, text: &str) -> bool {
+        let message = match serde_json::from_str::<SocketMessage>(text) {
+            Ok(message) => message,
+            Err(e) => {
+                if self.protocol != Some(Protocol::Versioned)
+                    && let Ok(stream_id) = text.parse::<StreamId>()
+                {
+                    self.protocol = Some(Protocol::Legacy);
+                    return self.follow_legacy(stream_id).await;
+                }
+                let error = SocketError::InvalidMessage {
+                    reason: e.to_string(),
+                };
+                self.send_error(None, error).await;
+                return true;
+            }
+        };
+        match (self.protocol, message) {
+            (None, SocketMessage::Hello { version }) => {
+                if version == SOCKET_PROTOCOL_VERSION {
+                    self.protocol = Some(Protocol::Versioned);
+                    self.send(&SocketMessage::Hello {
+                        version: SOCKET_PROTOCOL_VERSION,
+                    })
+                    .await;
+                } else {
+                    let error = SocketError::UnsupportedVersion {
+                        version,
+                        supported: SOCKET_PROTOCOL_VERSION,
+                    };
+                    self.send_error(None, error).await;
+                }
+            }
+            (Some(Protocol::Versioned), SocketMessage::Subscribe { stream_url }) => {
+                self.subscribe(&stream_url).await;
+            }
+            (Some(Protocol::Versioned), SocketMessage::Unsubscribe { stream_id }) => {
+                // the link of the stream is accepted as well
+                let stream_id = stream_id
+                    .parse::<StreamId>()
+                    .map_or(stream_id, String::from);
+                if self.streams.remove(&stream_id).is_some() {
+                    self.send(&SocketMessage::Ack { stream_id }).await;
+                } else {
+                    self.send_error(Some(stream_id), SocketError::NotSubscribed)
+                        .await;
+                }
+            }
+            (Some(Protocol::Versioned), _) => {
+                let error = SocketError::InvalidMessage {
+                    reason: "unexpected message from client".to_owned(),
+                };
+                self.send_error(None, error).await;
+            }
+            _ => self.send_error(None, SocketError::HelloExpected).await,
+        }
+        true
+    }
+
+    async fn subscribe(&mut self, stream_url: &str) {
+        let Ok(stream_id) = stream_url.parse::<StreamId>() else {
+            self.send_error(None, SocketError::InvalidStreamUrl).await;
+            return;
+        };
+        info!("Server received {stream_url} from socket");
+        if self
+            .streams
+            .get(stream_id.as_str())
+            .is_some_and(FollowedStream::is_alive)
+        {
+            self.send(&SocketMessage::Ack {
+                stream_id: stream_id.into(),
+            })
+            .await;
+            return;
+        }
+        let (subscription, feed) = match self.open(&stream_id).await {
+            Ok(opened) => opened,
+            Err(error) => {
+                self.send_error(Some(stream_id.into()), error).await;
+                return;
+            }
+        };
+        let stream_id = String::from(stream_id);
+        // acknowledge before the first update of the stream
+        self.send(&SocketMessage::Ack {
+            stream_id: stream_id.clone(),
+        })
+        .await;
+        let followed = self.forward(subscription, feed, &stream_id, Protocol::Versioned);
+        self.streams.insert(stream_id, followed);
+    }
+
+    /// Follows the only stream of a legacy socket, returns `false` on failure.
+    async fn follow_legacy(&mut self, stream_id: StreamId) -> bool {
+        info!("Server received {stream_id} from legacy socket");
+        self.streams.clear();
+        let Ok((subscription, feed)) = self.open(&stream_id).await else {
+            return false;
+        };
+        let stream_id = String::from(stream_id);
+        let followed = self.forward(subscription, feed, &stream_id, Protocol::Legacy);
+        self.streams.insert(stream_id, followed);
+        true
+    }
+
+    /// Subscribes to the stream, starting its worker if needed.
+    async fn open(
+        &self,
+        stream_id: &StreamId,
+    ) -> Result<(StreamSubscription, StreamFeed), SocketError> {
+        // Check if there is a worker already find its feed otherwise create worker and bring its feed
+        match get_or_start_worker(&self.data, stream_id, Some(&self.client_ip)).await {
+            Ok(feed) => Ok((
+                Subscriptions::subscribe(&self.data, stream_id.as_str()),
+                feed,
+            )),
+            Err(StartWorkerError::NotAllowed(e @ PolicyError::NotAllowed)) => {
+                Err(SocketError::StreamNotAllowed {
+                    reason: e.to_string(),
+                })
+            }
+            Err(StartWorkerError::TooManyWorkers) => Err(SocketError::RateLimited {
+                retry_after: retry_after_secs(WORKERS_RETRY_AFTER),
+            }),
+            Err(StartWorkerError::TooManyNewStreams { retry_after }) => {
+                Err(SocketError::RateLimited {
+                    retry_after: retry_after_secs(retry_after),
+                })
+            }
+            Err(e) => {
+                warn!("work failure: {}", e);
+                Err(SocketError::WorkerFailure)
+            }
+        }
+    }
+
+    /// Forwards the updates of the stream to the socket.
+    fn forward(
+        &self,
+        subscription: StreamSubscription,
+        feed: StreamFeed,
+        stream_id: &str,
+        protocol: Protocol,
+    ) -> FollowedStream {
+        let forward = actix_rt::spawn(forward_stream(
+            self.data.clone(),
+            self.session.clone(),
+            protocol,
+            stream_id.to_owned(),
+            feed,
+            self.stopped_tx.clone(),
+        ));
+        FollowedStream {
+            _subscription: subscription,
+            forward,
+        }
+    }
+
+    async fn send(&mut self, message: &SocketMessage) {
+        send(&mut self.session, Protocol::Versioned, message).await;
+    }
+
+    async fn send_error(&mut self, stream_id: Option<String>, error: SocketError) {
+        self.send(&SocketMessage::Error { stream_id, error }).await;
+    }
+}
+
+/// Sends the state and the track changes of a stream until the worker stops,
+/// then reports the stream on `stopped_tx`.
+async fn forward_stream(
+    data: web::Data<AppState>,
+    mut session: Session,
+    protocol: Protocol,
+    stream_id: String,
+    mut feed: StreamFeed,
+    stopped_tx: UnboundedSender<String>,
+) {
+    let state = *feed.states.borrow_and_update();
+    let status = status_message(&data, &stream_id, state);
+    if !send(&mut session, protocol, &status).await {
+        return;
+    }
+    loop {
+        let message = tokio::select! {
+            changed = feed.tracks.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let track = feed.tracks.borrow_and_update().clone();
+                // if track is empty, we might have created the channel with default empty track
+                // so skip this and wait for the next one
+                if track.is_empty() {
+                    continue;
+                }
+                SocketMessage::Track {
+                    stream_id: stream_id.clone(),
+                    track,
+                }
+            }
+            changed = feed.states.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let state = *feed.states.borrow_and_update();
+                status_message(&data, &stream_id, state)
+            }
+        };
+        if !send(&mut session, protocol, &message).await {
+            return;
+        }
+    }
+    let stopped = SocketMessage::Error {
+        stream_id: Some(stream_id.clone()),
+        error: SocketError::StreamStopped,
+    };
+    send(&mut session, protocol, &stopped).await;
+    let _ = stopped_tx.send(stream_id);
+}
+
+/// The state of the stream along with the last error of its worker.
+pub(super) fn status_message(
+    data: &AppState,
+    stream_id: &str,
+    state: WorkerState,
+) -> SocketMessage {
+    let last_error = data
+        .workers
+        .read()
+        .get(stream_id)
+        .and_then(|status| status.last_error.clone());
+    SocketMessage::StreamStatus {
+        stream_id: stream_id.to_owned(),
+        state,
+        last_error,
+    }
+}
+
+/// Sends a message in the format of the protocol, legacy sockets only receive
+/// the tracks. Returns `false` if the socket is closed.
+async fn send(session: &mut Session, protocol: Protocol, message: &SocketMessage) -> bool {
+    let text = match (protocol, message) {
+        (Protocol::Versioned, message) => serde_json::to_string(message),
+        (Protocol::Legacy, SocketMessage::Track { track, .. }) => serde_json::to_string(track),
+        (Protocol::Legacy, _) => return true,
+    };
+    match text {
+        Ok(text) => session.text(text).await.is_ok(),
+        Err(e) => {
+            warn!("Cannot serialize socket message: {}", e);
+            true
+        }
+    }
+}
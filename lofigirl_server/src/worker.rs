@@ -1,77 +1,472 @@
-use crate::{util::YoutubeIdExtractor, webserver::AppState};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::{
+    metrics::{self, ScrobbleSource},
+    outbox::queue_failed_listen,
+    policy::PolicyError,
+    webserver::AppState,
+};
 use actix_web::web;
-use anyhow::Context;
 use lofigirl_shared_common::{
-    track::Track, FAST_TRY_INTERVAL, REGULAR_INTERVAL, STREAM_LAST_READ_TIMEOUT,
+    api::{Action, TimelineEntry, WorkerState},
+    stream::StreamId,
+    track::Track,
+    FAST_TRY_INTERVAL, REGULAR_INTERVAL,
 };
+use lofigirl_shared_listen::listener::SendReport;
 use lofigirl_sys::image::ImageProcessor;
-use tokio::sync::watch::Sender;
+use thiserror::Error;
+use tokio::sync::watch::{Receiver, Sender};
+use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 use url::Url;
 
+/// Number of the reads the failure rate of a worker is computed over.
+const RECENT_READS: usize = 20;
+
+/// State of a running worker, kept in [`AppState::workers`] until it stops.
+pub struct WorkerStatus {
+    pub stream_url: String,
+    pub state: WorkerState,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+    /// Results of the last reads, `true` for the successful ones.
+    recent_reads: VecDeque<bool>,
+    /// Publishes the changes of `state`, closed once the worker stops.
+    state_tx: Sender<WorkerState>,
+}
+
+/// Receivers for the tracks and the state changes of a stream's worker.
+pub struct StreamFeed {
+    pub tracks: Receiver<Track>,
+    pub states: Receiver<WorkerState>,
+}
+
+impl WorkerStatus {
+    pub fn new(stream_url: String) -> WorkerStatus {
+        WorkerStatus {
+            stream_url,
+            state: WorkerState::Starting,
+            last_error: None,
+            last_error_at: None,
+            recent_reads: VecDeque::with_capacity(RECENT_READS),
+            state_tx: Sender::new(WorkerState::Starting),
+        }
+    }
+
+    fn set_state(&mut self, state: WorkerState) {
+        self.state = state;
+        self.state_tx.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+
+    /// Marks the worker as failing with the given error.
+    fn set_error(&mut self, error: String) {
+        self.last_error = Some(error);
+        self.last_error_at = Some(chrono::Utc::now().timestamp());
+        self.set_state(WorkerState::Failing);
+    }
+
+    fn record_read(&mut self, result: &anyhow::Result<Track>) {
+        if self.recent_reads.len() == RECENT_READS {
+            self.recent_reads.pop_front();
+        }
+        self.recent_reads.push_back(result.is_ok());
+        match result {
+            Ok(_) => self.set_state(WorkerState::Running),
+            Err(e) => self.set_error(e.to_string()),
+        }
+    }
+
+    /// Ratio of the failed reads among the recent reads, `None` before any read.
+    pub fn failure_rate(&self) -> Option<f64> {
+        let failed = self.recent_reads.iter().filter(|ok| !**ok).count();
+        (!self.recent_reads.is_empty()).then(|| failed as f64 / self.recent_reads.len() as f64)
+    }
+}
+
+#[derive(Clone)]
 pub struct ServerWorker {
     pub state: web::Data<AppState>,
-    video_url: Url,
+    pub video_url: Url,
+    stream_id: String,
 }
 
 impl ServerWorker {
-    pub fn new(video_url: Url, state: web::Data<AppState>) -> anyhow::Result<ServerWorker> {
-        Ok(ServerWorker { state, video_url })
+    pub fn new(stream_id: &StreamId, state: web::Data<AppState>) -> ServerWorker {
+        ServerWorker {
+            state,
+            video_url: stream_id.url(),
+            stream_id: stream_id.to_string(),
+        }
     }
 
-    pub async fn work(&mut self, track_tx: Sender<Track>) -> anyhow::Result<()> {
-        let state_clone = self.state.clone();
-        let mut image_proc = ImageProcessor::new(self.video_url.clone())?;
+    /// Sets up the OCR and the capture of the stream for a run.
+    pub fn open(&self) -> anyhow::Result<ImageProcessor> {
+        let profile = self.state.overlay.profile_for(&self.stream_id);
+        ImageProcessor::new(self.video_url.clone(), &profile)
+    }
+
+    /// Whether the stream has subscriptions, which keep it alive regardless of
+    /// clients, or clients listening to it.
+    pub async fn is_wanted(&self) -> bool {
+        let subscribed = match self.state.token_db.has_subscribers(&self.stream_id).await {
+            Ok(subscribed) => subscribed,
+            Err(e) => {
+                // rather read a stream for nothing than drop a subscription
+                warn!("Cannot check subscriptions, keeping the stream: {}", e);
+                true
+            }
+        };
+        subscribed || self.state.subscriptions.is_wanted(&self.stream_id)
+    }
+
+    /// Reads the tracks of the stream until it is not wanted anymore or the
+    /// token is cancelled.
+    pub async fn run(
+        &self,
+        mut image_proc: ImageProcessor,
+        track_tx: &Sender<Track>,
+        cancel: &CancellationToken,
+    ) {
+        let state = &self.state;
+        let youtube_video_id = &self.stream_id;
         info!("New ServerWorker starting for {}", &image_proc.video_url);
-        let youtube_video_id = self
-            .video_url
-            .get_video_id()
-            .context("invalid youtube url")?;
-        actix_rt::spawn(async move {
-            loop {
-                // Check last read to check if we should stop
-                match state_clone.last_requested.read().get(&youtube_video_id) {
-                    Some(instant) => {
-                        if instant.elapsed() > *STREAM_LAST_READ_TIMEOUT {
-                            info!(
-                                "{} is not wanted by any client anymore, stopping",
-                                image_proc.video_url
-                            );
-                            break;
+        loop {
+            if !self.is_wanted().await {
+                info!(
+                    "{} is not wanted by any client anymore, stopping",
+                    image_proc.video_url
+                );
+                update_timeline(state, youtube_video_id, None).await;
+                return;
+            }
+            // Snap an image and fetch track info
+            // If the track has changed, update state for REST endpoints and update channel for socket
+            let read_start = Instant::now();
+            let next_track = tokio::select! {
+                _ = cancel.cancelled() => return,
+                next_track = image_proc.next_track() => next_track,
+            };
+            metrics::observe_track_read(read_start.elapsed(), next_track.is_ok());
+            if let Some(status) = state.workers.write().get_mut(youtube_video_id) {
+                status.record_read(&next_track);
+            }
+            let pause = match next_track {
+                Ok(next_track) => {
+                    let track = next_track.clone();
+                    let old_track = state.tracks.write().insert(youtube_video_id.clone(), track);
+                    if old_track
+                        .as_ref()
+                        .filter(|old| **old == next_track)
+                        .is_none()
+                    {
+                        // the previous track is scrobbled with the time it started playing
+                        let previous = state.timelines.on_air(youtube_video_id);
+                        // on the timeline first, so that listeners find when it started
+                        update_timeline(state, youtube_video_id, Some(&next_track)).await;
+                        if track_tx.send(next_track.clone()).is_err() {
+                            warn!("Channel problem")
                         }
-                    }
-                    None => {
-                        warn!(
-                            "{} is not available in the last requested list, stopping",
-                            image_proc.video_url
-                        );
-                        break;
-                    }
-                }
-                // Snap an image and fetch track info
-                // If the track has changed, update state for REST endpoints and update channel for socket
-                let next_track = image_proc.next_track().await;
-                match next_track {
-                    Ok(next_track) => {
-                        let track = next_track.clone();
-                        let old_track = state_clone
-                            .tracks
-                            .write()
-                            .insert(youtube_video_id.clone(), track);
-                        if old_track.filter(|old| *old == next_track).is_none()
-                            && track_tx.send(next_track.clone()).is_err()
+                        if let Err(e) = scrobble_for_subscribers(
+                            state,
+                            youtube_video_id,
+                            previous.as_ref(),
+                            &next_track,
+                        )
+                        .await
                         {
-                            warn!("Channel problem")
+                            warn!("Problem with subscriptions: {}", e);
                         }
-                        tokio::time::sleep(*REGULAR_INTERVAL).await;
-                    }
-                    Err(e) => {
-                        warn!("Problem with: {}", e);
-                        tokio::time::sleep(*FAST_TRY_INTERVAL).await;
                     }
+                    *REGULAR_INTERVAL
                 }
+                Err(e) => {
+                    warn!("Problem with: {}", e);
+                    *FAST_TRY_INTERVAL
+                }
+            };
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(pause) => {}
             }
+        }
+    }
+
+    /// Marks the worker as failing after a failed or panicked run.
+    pub fn record_crash(&self, error: &str) {
+        if let Some(status) = self.state.workers.write().get_mut(&self.stream_id) {
+            status.set_error(error.to_owned());
+        }
+    }
+
+    /// Removes the stream from the registries as the worker stops, unless a
+    /// client has subscribed again since the worker decided to stop.
+    pub fn release(&self) -> bool {
+        let mut track_channels = self.state.track_channels.write();
+        if self.state.subscriptions.is_wanted(&self.stream_id) {
+            return false;
+        }
+        self.remove_entries(&mut track_channels);
+        true
+    }
+
+    /// Ends the track on air and removes the stream from the registries
+    /// after the worker was stopped.
+    pub async fn stopped(&self) {
+        update_timeline(&self.state, &self.stream_id, None).await;
+        self.remove_entries(&mut self.state.track_channels.write());
+    }
+
+    fn remove_entries(&self, track_channels: &mut HashMap<String, Receiver<Track>>) {
+        track_channels.remove(&self.stream_id);
+        self.state.tracks.write().remove(&self.stream_id);
+        self.state.timelines.remove(&self.stream_id);
+        self.state.workers.write().remove(&self.stream_id);
+        self.state.subscriptions.remove(&self.stream_id);
+        self.state.supervisor.remove(&self.stream_id);
+        metrics::worker_stopped();
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StartWorkerError {
+    #[error(transparent)]
+    NotAllowed(#[from] PolicyError),
+    #[error("Too many workers are running")]
+    TooManyWorkers,
+    #[error("Too many new streams from the client")]
+    TooManyNewStreams { retry_after: Duration },
+    #[error("Worker of the stream has no status")]
+    MissingStatus,
+    #[error("Cannot open the stream: {0}")]
+    Unavailable(anyhow::Error),
+}
+
+/// Returns the feed of the worker for the given stream, starting a new worker if there is none.
+/// Clients only get the streams allowed by the policy, and a new worker for a client counts
+/// against its new stream budget and the worker cap. The workers started by the server itself
+/// are not limited.
+pub async fn get_or_start_worker(
+    state: &web::Data<AppState>,
+    stream_id: &StreamId,
+    client: Option<&str>,
+) -> Result<StreamFeed, StartWorkerError> {
+    if client.is_some() {
+        state.policy.check(stream_id).await?;
+    }
+    let youtube_id = stream_id.as_str();
+    // hold the lock until the worker is registered, so that a stopping
+    // worker cannot remove the new one
+    let mut track_channels = state.track_channels.write();
+    if let Some(rx) = track_channels.get(youtube_id) {
+        info!("Found existing worker for given video, reuse worker");
+        state.subscriptions.started(youtube_id);
+        let states = state
+            .workers
+            .read()
+            .get(youtube_id)
+            .map(|status| status.state_tx.subscribe())
+            .ok_or(StartWorkerError::MissingStatus)?;
+        return Ok(StreamFeed {
+            tracks: rx.clone(),
+            states,
         });
-        Ok(())
+    }
+    if let Some(client) = client {
+        if track_channels.len() >= state.limits.max_workers {
+            return Err(StartWorkerError::TooManyWorkers);
+        }
+        state
+            .limits
+            .new_streams
+            .take(client)
+            .map_err(|retry_after| StartWorkerError::TooManyNewStreams { retry_after })?;
+    }
+    let worker = ServerWorker::new(stream_id, state.clone());
+    let (tx, rx) = tokio::sync::watch::channel(Track::default());
+    let status = WorkerStatus::new(worker.video_url.to_string());
+    let states = status.state_tx.subscribe();
+    state.workers.write().insert(youtube_id.to_owned(), status);
+    track_channels.insert(youtube_id.to_owned(), rx.clone());
+    state.subscriptions.started(youtube_id);
+    metrics::worker_started();
+    drop(track_channels);
+    // the stream is reserved, so a client learns if the first open fails,
+    // while the streams of the server itself are retried by the supervisor
+    let image_proc = match worker.open() {
+        Err(e) if client.is_some() => {
+            worker.stopped().await;
+            return Err(StartWorkerError::Unavailable(e));
+        }
+        image_proc => image_proc,
+    };
+    state.supervisor.spawn(youtube_id, worker, image_proc, tx);
+    Ok(StreamFeed { tracks: rx, states })
+}
+
+/// Starts a worker for every stream which has a subscription.
+pub async fn start_subscribed_workers(state: &web::Data<AppState>) -> anyhow::Result<()> {
+    for (stream_id, _) in state.token_db.get_subscribed_streams().await? {
+        let stream_id = match stream_id.parse::<StreamId>() {
+            Ok(stream_id) => stream_id,
+            Err(e) => {
+                warn!(
+                    "Cannot start worker for subscribed stream {}: {}",
+                    stream_id, e
+                );
+                continue;
+            }
+        };
+        if let Err(e) = state.policy.check(&stream_id).await {
+            warn!(
+                "Not starting worker for subscribed stream {}: {}",
+                stream_id, e
+            );
+            continue;
+        }
+        info!("Starting worker for subscribed stream {}", stream_id);
+        if let Err(e) = get_or_start_worker(state, &stream_id, None).await {
+            warn!(
+                "Cannot start worker for subscribed stream {}: {}",
+                stream_id, e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Starts a worker for every always-on stream, which keeps running without clients.
+pub async fn start_always_on_workers(state: &web::Data<AppState>) {
+    for stream_id in state.policy.always_on() {
+        info!("Starting worker for always-on stream {}", stream_id);
+        if let Err(e) = get_or_start_worker(state, stream_id, None).await {
+            warn!(
+                "Cannot start worker for always-on stream {}: {}",
+                stream_id, e
+            );
+            continue;
+        }
+        state.subscriptions.pin(stream_id.as_str());
+    }
+}
+
+/// Puts the track on air on the timeline of the stream, or only ends the
+/// track on air if there is no track.
+async fn update_timeline(state: &AppState, stream_id: &str, track: Option<&Track>) {
+    let now = chrono::Utc::now().timestamp();
+    let result = match track {
+        Some(track) => {
+            state.timelines.start(stream_id, track, now);
+            state
+                .token_db
+                .record_stream_track(stream_id, track, now)
+                .await
+        }
+        None => {
+            state.timelines.end(stream_id, now);
+            state.token_db.end_stream_track(stream_id, now).await
+        }
+    };
+    if let Err(e) = result {
+        warn!("Cannot update the timeline of {}: {}", stream_id, e);
+    }
+}
+
+/// Sends the listen of the previous track for every subscription of the stream
+/// that was active when the track started, and the now playing of the new track
+/// for every subscription that is active at the moment.
+async fn scrobble_for_subscribers(
+    state: &AppState,
+    stream_id: &str,
+    previous: Option<&TimelineEntry>,
+    track: &Track,
+) -> anyhow::Result<()> {
+    let now = chrono::Utc::now();
+    let previous = previous
+        .filter(|entry| !entry.track.is_empty())
+        .and_then(|entry| {
+            chrono::DateTime::from_timestamp(entry.started_at, 0)
+                .map(|played_at| (&entry.track, played_at))
+        });
+    let subscribers = state.token_db.get_stream_subscribers(stream_id).await?;
+    for subscriber in subscribers {
+        let active_at = |at| {
+            subscriber
+                .schedule
+                .is_none_or(|schedule| schedule.is_active(at))
+        };
+        let previous = previous.filter(|(_, played_at)| active_at(*played_at));
+        if previous.is_none() && !active_at(now) {
+            continue;
+        }
+        let listener = match state.listener_for_token(&subscriber.token).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Cannot create listener for subscription: {}", e);
+                continue;
+            }
+        };
+        if let Some((previous_track, played_at)) = previous {
+            let report = listener
+                .send_listen_report_at(previous_track, played_at.timestamp())
+                .await;
+            queue_failed_listen(
+                state,
+                &subscriber.token,
+                previous_track,
+                played_at.timestamp(),
+                &report,
+            )
+            .await;
+            record_subscription_scrobble(
+                state,
+                &subscriber.token,
+                Action::Listened,
+                previous_track,
+                stream_id,
+                report,
+            )
+            .await;
+        }
+        if !active_at(now) {
+            continue;
+        }
+        let report = listener.send_action_report(Action::PlayingNow, track).await;
+        record_subscription_scrobble(
+            state,
+            &subscriber.token,
+            Action::PlayingNow,
+            track,
+            stream_id,
+            report,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+async fn record_subscription_scrobble(
+    state: &AppState,
+    token: &str,
+    action: Action,
+    track: &Track,
+    stream_id: &str,
+    report: SendReport,
+) {
+    if let Err(e) = state
+        .token_db
+        .record_scrobble(token, action, track, Some(stream_id), &report)
+        .await
+    {
+        warn!("Cannot record scrobble for subscription: {}", e);
+    }
+    metrics::observe_scrobble(ScrobbleSource::Subscription, &report);
+    if let Err(e) = report.into_result() {
+        warn!("Cannot send {} for subscription: {}", action, e);
     }
 }
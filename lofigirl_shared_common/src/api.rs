@@ -1,12 +1,81 @@
 use std::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use crate::{encrypt::SecureString, track::Track};
+use crate::{encrypt::SecureString, moment::Moment, schedule::Schedule, track::Track};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ScrobbleRequest {
     pub action: Action,
     pub track: Track,
+    /// Stream the track was detected on, recorded in the scrobble history.
+    #[serde(default)]
+    pub stream_url: Option<String>,
+    /// Unix second the track was listened at, for listens sent late. Now if not set.
+    #[serde(default)]
+    pub listened_at: Option<i64>,
+}
+
+/// Query parameters of the track endpoint, an alternative to putting the
+/// percent-encoded link into the path.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackQuery {
+    /// Any form of a youtube link, or a bare video id.
+    pub url: String,
+}
+
+/// Outcome of a scrobble on a single service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServiceOutcome {
+    Sent,
+    Failed { error: String },
+}
+
+/// A scrobble sent by or on behalf of a token. The outcome of a service is
+/// `None` if the token has no credentials for it. Timestamps are unix seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScrobbleRecord {
+    pub id: i64,
+    pub action: Action,
+    pub track: Track,
+    pub stream_id: Option<String>,
+    pub lastfm: Option<ServiceOutcome>,
+    pub listenbrainz: Option<ServiceOutcome>,
+    pub created_at: i64,
+}
+
+/// Query parameters of the history endpoint, every filter is optional.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HistoryQuery {
+    /// Only the records older than this id, taken from `next_cursor` of the previous page.
+    pub cursor: Option<i64>,
+    pub limit: Option<u32>,
+    pub action: Option<Action>,
+    pub stream_id: Option<String>,
+    /// Only the records which failed on at least one service.
+    #[serde(default)]
+    pub failed: bool,
+    /// Unix seconds, inclusive.
+    pub since: Option<i64>,
+    /// Unix seconds, exclusive.
+    pub until: Option<i64>,
+}
+
+/// Records newest first. `next_cursor` is `None` on the last page.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub scrobbles: Vec<ScrobbleRecord>,
+    pub next_cursor: Option<i64>,
+}
+
+/// Listens of a token waiting to be sent again after a service has failed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OutboxStatus {
+    pub pending: i64,
+    /// Unix second of the next retry.
+    pub next_attempt_at: Option<i64>,
+    pub last_error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -24,19 +93,242 @@ pub struct SessionResponse {
 pub struct TokenRequest {
     pub secure_lastfm_session_key: Option<SecureString>,
     pub secure_listenbrainz_token: Option<SecureString>,
+    /// Name of the client, to tell the tokens apart.
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenResponse {
-    pub secure_token: SecureString,
+    /// Server signed JWT to be used as the bearer token.
+    pub access_token: String,
+    /// Seconds until the access token expires.
+    pub expires_in: i64,
+    pub secure_refresh_token: SecureString,
+}
+
+/// Current public key of the server, to agree on the `SecureString` encryption key.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EncryptionKeyResponse {
+    pub key_id: String,
+    pub public_key_base64: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub secure_refresh_token: SecureString,
 }
 
+/// A token issued for the same Last.fm or ListenBrainz account as the caller.
+/// Timestamps are unix seconds.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub id: i64,
+    pub label: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub revoked: bool,
+    /// Whether this is the token of the caller.
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameTokenRequest {
+    pub label: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriptionRequest {
+    pub stream_url: String,
+    pub schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: i64,
+    pub stream_url: String,
+    pub schedule: Option<Schedule>,
+}
+
+/// A track detected on a stream. `ended_at` is `None` while the track is on
+/// air. Timestamps are unix seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub track: Track,
+    pub started_at: i64,
+    pub ended_at: Option<i64>,
+}
+
+/// Query parameters of the stream history endpoint, every filter is optional.
+/// Returns the tracks which were on air at any time between `since` and `until`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TimelineQuery {
+    /// Unix seconds, inclusive.
+    pub since: Option<i64>,
+    /// Unix seconds, exclusive.
+    pub until: Option<i64>,
+    pub limit: Option<u32>,
+    /// Oldest tracks first instead of the newest.
+    #[serde(default)]
+    pub oldest_first: bool,
+}
+
+/// Query parameters of the endpoint looking up the track on air at a moment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayingAtQuery {
+    pub time: Moment,
+    /// Number of the neighboring tracks on each side.
+    pub context: Option<u32>,
+}
+
+/// The track on air at `time`, `None` if nothing was detected then, with the
+/// tracks before and after it. Neighbors are ordered by time, oldest first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayingAtResponse {
+    pub time: i64,
+    pub track: Option<TimelineEntry>,
+    pub before: Vec<TimelineEntry>,
+    pub after: Vec<TimelineEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WorkerState {
+    /// Started but has not read a track yet.
+    Starting,
+    /// The last read was successful.
+    Running,
+    /// The last read has failed, see `last_error`.
+    Failing,
+}
+
+/// A stream with a running worker on the server. Timestamps are unix seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub stream_id: String,
+    pub stream_url: String,
+    pub track: Option<Track>,
+    pub track_started_at: Option<i64>,
+    pub subscribers: i64,
+    pub state: WorkerState,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+}
+
+/// Version of the `/track_ws` protocol, exchanged in [`SocketMessage::Hello`].
+pub const SOCKET_PROTOCOL_VERSION: u32 = 1;
+
+/// A message on the `/track_ws` socket, in either direction. The client starts
+/// with `Hello`, then follows any number of streams. The server answers each
+/// request in order with `Ack` or `Error`; errors do not close the socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SocketMessage {
+    Hello {
+        version: u32,
+    },
+    /// Follows a stream, answered with the id of the stream.
+    Subscribe {
+        stream_url: String,
+    },
+    Unsubscribe {
+        stream_id: String,
+    },
+    /// The track on air changed, also sent after subscribing if a track is known.
+    Track {
+        stream_id: String,
+        track: Track,
+    },
+    /// The state of the worker changed, also sent after subscribing.
+    StreamStatus {
+        stream_id: String,
+        state: WorkerState,
+        last_error: Option<String>,
+    },
+    Error {
+        stream_id: Option<String>,
+        error: SocketError,
+    },
+    Ack {
+        stream_id: String,
+    },
+}
+
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "code")]
+pub enum SocketError {
+    #[error("Protocol version {version} is not supported, the server speaks {supported}")]
+    UnsupportedVersion { version: u32, supported: u32 },
+    #[error("Hello is expected first")]
+    HelloExpected,
+    #[error("Invalid message: {reason}")]
+    InvalidMessage { reason: String },
+    #[error("Invalid youtube link")]
+    InvalidStreamUrl,
+    #[error("Not subscribed to the stream")]
+    NotSubscribed,
+    #[error("Cannot start a worker for the stream")]
+    WorkerFailure,
+    /// Too many streams are followed, or too many were started by the client.
+    #[error("Too many streams, retry in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    /// The stream is outside the policy of the server.
+    #[error("Stream is not allowed: {reason}")]
+    StreamNotAllowed { reason: String },
+    /// The worker was stopped or given up, the stream is not followed anymore.
+    #[error("The stream has stopped")]
+    StreamStopped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CheckStatus {
+    Ok,
+    /// Works, but not fully. Does not make the server unready.
+    Degraded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+/// The server is ready if none of the checks has failed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub checks: Vec<DependencyStatus>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Action {
     PlayingNow,
     Listened,
 }
 
+impl Action {
+    /// Name of the variant, as serialized.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Listened => "Listened",
+            Action::PlayingNow => "PlayingNow",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = ActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Listened" => Ok(Action::Listened),
+            "PlayingNow" => Ok(Action::PlayingNow),
+            _ => Err(ActionError::Unknown(s.to_owned())),
+        }
+    }
+}
+
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -45,3 +337,9 @@ impl fmt::Display for Action {
         }
     }
 }
+
+#[derive(Error, Debug)]
+pub enum ActionError {
+    #[error("Unknown action: {0}")]
+    Unknown(String),
+}
@@ -1,4 +1,5 @@
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -46,6 +47,200 @@ pub struct ServerConfig {
 pub struct ServerSettingsConfig {
     pub port: u32,
     pub token_db: String,
+    /// Secret for signing access tokens. Falls back to the `LOFIGIRL_JWT_SECRET`
+    /// environment variable, then to a random secret for each run.
+    pub jwt_secret: Option<String>,
+    /// Keys for `SecureString` encryption, the first one is advertised to the
+    /// clients and the rest are kept for rotation. Falls back to the
+    /// `LOFIGIRL_ENCRYPTION_KEYS` environment variable, then to a random key for each run.
+    pub encryption_keys: Option<Vec<EncryptionKeyConfig>>,
+    /// Base64 encoded 32 byte key to encrypt the stored credentials with.
+    /// Falls back to the `LOFIGIRL_DATA_KEY` environment variable.
+    pub data_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EncryptionKeyConfig {
+    pub id: String,
+    /// Base64 encoded 32 byte X25519 secret.
+    pub secret: String,
+}
+
+pub const DEFAULT_OVERLAY_PROFILE: &str = "default";
+
+/// Tesseract settings. Every field is optional so that a profile only needs to
+/// list the values it overrides.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OcrConfig {
+    pub tessdata_path: Option<String>,
+    pub languages: Option<Vec<String>>,
+    pub page_seg_mode: Option<u32>,
+    pub whitelist: Option<String>,
+    pub blacklist: Option<String>,
+    pub user_words_file: Option<String>,
+}
+
+impl OcrConfig {
+    /// Returns a copy of `self` where every field set on `other` wins.
+    pub fn merged_with(&self, other: &OcrConfig) -> OcrConfig {
+        OcrConfig {
+            tessdata_path: other
+                .tessdata_path
+                .clone()
+                .or_else(|| self.tessdata_path.clone()),
+            languages: other.languages.clone().or_else(|| self.languages.clone()),
+            page_seg_mode: other.page_seg_mode.or(self.page_seg_mode),
+            whitelist: other.whitelist.clone().or_else(|| self.whitelist.clone()),
+            blacklist: other.blacklist.clone().or_else(|| self.blacklist.clone()),
+            user_words_file: other
+                .user_words_file
+                .clone()
+                .or_else(|| self.user_words_file.clone()),
+        }
+    }
+}
+
+/// A single image preprocessing step, applied in the listed order before OCR.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum PreprocessStage {
+    /// Scales the image to the given width, keeping the aspect ratio.
+    Resize {
+        #[serde(deserialize_with = "positive")]
+        width: i32,
+    },
+    Grayscale,
+    /// Requires a grayscale image.
+    AdaptiveThreshold {
+        #[serde(deserialize_with = "odd_above_one")]
+        block_size: i32,
+        c: f64,
+    },
+    /// Requires a grayscale image.
+    OtsuThreshold,
+    Dilate {
+        #[serde(deserialize_with = "positive")]
+        kernel: i32,
+        iterations: i32,
+    },
+    Erode {
+        #[serde(deserialize_with = "positive")]
+        kernel: i32,
+        iterations: i32,
+    },
+    Invert,
+    /// Median blur with the given odd kernel size.
+    Denoise {
+        #[serde(deserialize_with = "odd_above_one")]
+        kernel: i32,
+    },
+    /// Keeps the pixels within the bounds, in BGR order.
+    ColorRange {
+        low: [i32; 3],
+        high: [i32; 3],
+    },
+}
+
+/// Rejects sizes OpenCV would only fail on once the first frame is processed.
+fn positive<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    let value = i32::deserialize(deserializer)?;
+    if value > 0 {
+        Ok(value)
+    } else {
+        Err(D::Error::custom(format!(
+            "expected a positive size, got {}",
+            value
+        )))
+    }
+}
+
+fn odd_above_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    let value = i32::deserialize(deserializer)?;
+    if value > 1 && value % 2 == 1 {
+        Ok(value)
+    } else {
+        Err(D::Error::custom(format!(
+            "expected an odd size above 1, got {}",
+            value
+        )))
+    }
+}
+
+/// Describes how the track overlay of a group of streams should be read.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OverlayProfile {
+    pub name: String,
+    /// Youtube video ids this profile applies to.
+    #[serde(default)]
+    pub streams: Vec<String>,
+    #[serde(default)]
+    pub ocr: OcrConfig,
+    pub preprocess: Option<Vec<PreprocessStage>>,
+}
+
+impl Default for OverlayProfile {
+    fn default() -> Self {
+        OverlayProfile {
+            name: DEFAULT_OVERLAY_PROFILE.to_owned(),
+            streams: Vec::new(),
+            ocr: OcrConfig::default(),
+            preprocess: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct OverlayConfig {
+    /// Base settings shared by every profile.
+    #[serde(default)]
+    pub ocr: OcrConfig,
+    pub preprocess: Option<Vec<PreprocessStage>>,
+    #[serde(default)]
+    pub profiles: Vec<OverlayProfile>,
+}
+
+impl OverlayConfig {
+    /// Resolves the profile for the given stream, with the base settings
+    /// merged under the profile's own overrides.
+    pub fn profile_for(&self, stream_id: &str) -> OverlayProfile {
+        self.resolve(
+            self.profiles
+                .iter()
+                .find(|p| p.streams.iter().any(|s| s == stream_id)),
+        )
+    }
+
+    /// Resolves the profile with the given name, if there is one.
+    pub fn profile_named(&self, name: &str) -> Option<OverlayProfile> {
+        self.profiles
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| self.resolve(Some(p)))
+    }
+
+    /// The settings used for streams that do not match any profile.
+    pub fn base_profile(&self) -> OverlayProfile {
+        self.resolve(None)
+    }
+
+    fn resolve(&self, profile: Option<&OverlayProfile>) -> OverlayProfile {
+        match profile {
+            Some(profile) => OverlayProfile {
+                name: profile.name.clone(),
+                streams: profile.streams.clone(),
+                ocr: self.ocr.merged_with(&profile.ocr),
+                preprocess: profile
+                    .preprocess
+                    .clone()
+                    .or_else(|| self.preprocess.clone()),
+            },
+            None => OverlayProfile {
+                ocr: self.ocr.clone(),
+                preprocess: self.preprocess.clone(),
+                ..Default::default()
+            },
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -54,4 +249,8 @@ pub enum ConfigError {
     EmptyListeners,
     #[error("Server config not found.")]
     EmptyServerConfig,
+    #[error("Overlay profile {0} not found.")]
+    UnknownOverlayProfile(String),
+    #[error("Data key for the stored credentials is not configured.")]
+    EmptyDataKey,
 }
@@ -1,85 +1,261 @@
-use std::{fmt, sync::LazyLock};
+use std::{collections::HashMap, sync::RwLock};
 
 use aes_gcm::{
-    aead::{generic_array::GenericArray, Aead, OsRng},
-    aes::Aes256,
-    AeadCore, Aes256Gcm, AesGcm, Key, KeyInit as _,
+    AeadCore, Aes256Gcm, KeyInit as _,
+    aead::{Aead, OsRng, generic_array::GenericArray},
 };
-use base64::{engine::general_purpose, Engine as _};
-use serde::{
-    de::{MapAccess, Visitor},
-    ser::SerializeMap,
-    Deserialize, Serialize, Serializer,
-};
-use typenum::U12;
+use base64::{Engine as _, engine::general_purpose};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize, Serializer};
+use sha2::Sha256;
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
 
-use crate::ENCRYPTION_KEY_BASE64;
+const KEY_DERIVATION_INFO: &[u8] = b"lofigirl secure string";
 
-static AES_CIPHER: LazyLock<AesGcm<Aes256, U12>> = LazyLock::new(|| {
-    let key_bytes = general_purpose::STANDARD
-        .decode(ENCRYPTION_KEY_BASE64)
-        .unwrap();
-    let fixed_bytes: &[u8; 32] = key_bytes[..32].try_into().unwrap();
-    let key: Key<Aes256Gcm> = (*fixed_bytes).into();
-    Aes256Gcm::new(&key)
-});
+/// Keys of this side of the connection. Installed once on start by either
+/// [`install_server_keys`] or [`install_server_public_key`].
+static KEY_RING: RwLock<Option<KeyRing>> = RwLock::new(None);
 
-pub trait Aes256GCMEncryption {
-    fn encrypt(&self) -> anyhow::Result<(Vec<u8>, GenericArray<u8, U12>)>
-    where
-        Self: Sized;
-    fn decrypt(encrypted: Vec<u8>, nonce: &GenericArray<u8, U12>) -> anyhow::Result<Self>
-    where
-        Self: Sized;
+enum KeyRing {
+    /// Every key the server accepts, new payloads are sealed with `current`.
+    Server {
+        current: String,
+        keys: HashMap<String, StaticSecret>,
+    },
+    /// Secret of this client run and the advertised key of the server.
+    Client {
+        secret: StaticSecret,
+        server_key_id: String,
+        server_public: PublicKey,
+    },
 }
 
-impl Aes256GCMEncryption for String {
-    fn encrypt(&self) -> anyhow::Result<(Vec<u8>, GenericArray<u8, U12>)>{
-        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        let ciphertext = AES_CIPHER
-            .encrypt(&nonce, self.as_bytes())
-            .map_err(anyhow::Error::msg)?;
-        // let ciphertext_base64 = general_purpose::STANDARD.encode(&ciphertext);
-        Ok((ciphertext, nonce))
+/// Installs the server keys. The first key is advertised to the clients,
+/// the rest are only kept to decrypt payloads of clients which have not
+/// picked up a rotation yet.
+pub fn install_server_keys(keys: Vec<(String, [u8; 32])>) -> Result<(), EncryptionError> {
+    let current = keys.first().ok_or(EncryptionError::NoKeys)?.0.clone();
+    let keys = keys
+        .into_iter()
+        .map(|(id, secret)| (id, StaticSecret::from(secret)))
+        .collect();
+    *KEY_RING.write().map_err(|_| EncryptionError::Poisoned)? =
+        Some(KeyRing::Server { current, keys });
+    Ok(())
+}
+
+/// Installs the public key fetched from the server along with a fresh secret for this run.
+/// Installing the same key again keeps the secret, so that replies to requests
+/// in flight can still be decrypted.
+pub fn install_server_public_key(key_id: &str, public_key_base64: &str) -> anyhow::Result<()> {
+    let server_public = decode_public_key(public_key_base64)?;
+    let mut key_ring = KEY_RING.write().map_err(|_| EncryptionError::Poisoned)?;
+    if let Some(KeyRing::Client {
+        server_key_id,
+        server_public: installed,
+        ..
+    }) = &*key_ring
+        && server_key_id == key_id
+        && installed.as_bytes() == server_public.as_bytes()
+    {
+        return Ok(());
     }
+    *key_ring = Some(KeyRing::Client {
+        secret: StaticSecret::random_from_rng(OsRng),
+        server_key_id: key_id.to_owned(),
+        server_public,
+    });
+    Ok(())
+}
 
-    fn decrypt(encrypted: Vec<u8>, nonce: &GenericArray<u8, U12>) -> anyhow::Result<Self> {
-        // let ciphertext = general_purpose::STANDARD.decode(self)?;
-        let plaintext = AES_CIPHER
-            .decrypt(nonce, encrypted.as_ref())
-            .map_err(anyhow::Error::msg)?;
-        Ok(String::from_utf8(plaintext)?)
+/// Returns the id and the public key of the current server key.
+pub fn server_public_key() -> Result<(String, String), EncryptionError> {
+    match &*KEY_RING.read().map_err(|_| EncryptionError::Poisoned)? {
+        Some(KeyRing::Server { current, keys }) => {
+            let secret = keys.get(current).ok_or(EncryptionError::NoKeys)?;
+            let public = PublicKey::from(secret);
+            Ok((
+                current.clone(),
+                general_purpose::STANDARD.encode(public.as_bytes()),
+            ))
+        }
+        _ => Err(EncryptionError::NotConfigured),
     }
 }
 
+/// Generates a new random secret key, e.g. to put into the server config.
+pub fn generate_secret_key() -> [u8; 32] {
+    StaticSecret::random_from_rng(OsRng).to_bytes()
+}
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("Encryption keys are not configured")]
+    NotConfigured,
+    #[error("At least one encryption key is required")]
+    NoKeys,
+    #[error("Encryption key {0} is not known")]
+    UnknownKey(String),
+    #[error("Encryption key should be 32 bytes")]
+    InvalidKeyLength,
+    #[error("Secure string has no recipient to be encrypted for")]
+    MissingRecipient,
+    #[error("Encryption key store is poisoned")]
+    Poisoned,
+}
+
+/// Decodes a base64 encoded 32 byte secret key.
+pub fn decode_secret_key(secret_base64: &str) -> anyhow::Result<[u8; 32]> {
+    let bytes = general_purpose::STANDARD.decode(secret_base64)?;
+    let secret: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| EncryptionError::InvalidKeyLength)?;
+    Ok(secret)
+}
+
+fn decode_public_key(public_key_base64: &str) -> anyhow::Result<PublicKey> {
+    Ok(PublicKey::from(decode_secret_key(public_key_base64)?))
+}
+
+fn derive_cipher(secret: &StaticSecret, public: &PublicKey) -> anyhow::Result<Aes256Gcm> {
+    let shared = secret.diffie_hellman(public);
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, shared.as_bytes())
+        .expand(KEY_DERIVATION_INFO, &mut key)
+        .map_err(anyhow::Error::msg)?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
 
+/// The other side of a received secure string, used to encrypt the reply.
 #[derive(Debug, Clone)]
-pub struct SecureString(String);
+struct Recipient {
+    key_id: String,
+    public: PublicKey,
+}
+
+/// A string that is encrypted on the wire with a key agreed between the client
+/// and the server (X25519 with HKDF-SHA256, then AES-256-GCM).
+///
+/// The server can only encrypt for a client it has received a secure string
+/// from, so responses are built with [`SecureString::reply`].
+#[derive(Debug, Clone)]
+pub struct SecureString {
+    value: String,
+    recipient: Option<Recipient>,
+}
+
+impl SecureString {
+    /// Creates a secure string which is encrypted for the sender of this one.
+    pub fn reply(&self, value: String) -> SecureString {
+        SecureString {
+            value,
+            recipient: self.recipient.clone(),
+        }
+    }
+
+    fn seal(&self) -> anyhow::Result<SealedString> {
+        let key_ring = KEY_RING.read().map_err(|_| EncryptionError::Poisoned)?;
+        let (key_id, secret, public) = match (&*key_ring, &self.recipient) {
+            (Some(KeyRing::Server { keys, .. }), Some(recipient)) => (
+                &recipient.key_id,
+                keys.get(&recipient.key_id)
+                    .ok_or_else(|| EncryptionError::UnknownKey(recipient.key_id.clone()))?,
+                &recipient.public,
+            ),
+            (Some(KeyRing::Server { .. }), None) => Err(EncryptionError::MissingRecipient)?,
+            (
+                Some(KeyRing::Client {
+                    secret,
+                    server_key_id,
+                    server_public,
+                }),
+                _,
+            ) => (server_key_id, secret, server_public),
+            (None, _) => Err(EncryptionError::NotConfigured)?,
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = derive_cipher(secret, public)?
+            .encrypt(&nonce, self.value.as_bytes())
+            .map_err(anyhow::Error::msg)?;
+        Ok(SealedString {
+            key_id: key_id.clone(),
+            public_key_base64: general_purpose::STANDARD.encode(PublicKey::from(secret).as_bytes()),
+            nonce_base64: general_purpose::STANDARD.encode(nonce),
+            encrypted_base64: general_purpose::STANDARD.encode(encrypted),
+        })
+    }
+
+    fn open(sealed: SealedString) -> anyhow::Result<SecureString> {
+        let key_ring = KEY_RING.read().map_err(|_| EncryptionError::Poisoned)?;
+        let sender = decode_public_key(&sealed.public_key_base64)?;
+        let (secret, recipient) = match &*key_ring {
+            Some(KeyRing::Server { keys, .. }) => (
+                keys.get(&sealed.key_id)
+                    .ok_or_else(|| EncryptionError::UnknownKey(sealed.key_id.clone()))?,
+                Some(Recipient {
+                    key_id: sealed.key_id.clone(),
+                    public: sender,
+                }),
+            ),
+            Some(KeyRing::Client {
+                secret,
+                server_key_id,
+                ..
+            }) => {
+                (*server_key_id == sealed.key_id)
+                    .then_some(())
+                    .ok_or_else(|| EncryptionError::UnknownKey(sealed.key_id.clone()))?;
+                (secret, None)
+            }
+            None => Err(EncryptionError::NotConfigured)?,
+        };
+        let nonce_bytes = general_purpose::STANDARD.decode(&sealed.nonce_base64)?;
+        let encrypted = general_purpose::STANDARD.decode(&sealed.encrypted_base64)?;
+        let plaintext = derive_cipher(secret, &sender)?
+            .decrypt(GenericArray::from_slice(&nonce_bytes), encrypted.as_ref())
+            .map_err(anyhow::Error::msg)?;
+        Ok(SecureString {
+            value: String::from_utf8(plaintext)?,
+            recipient,
+        })
+    }
+}
 
 impl From<SecureString> for String {
     fn from(value: SecureString) -> Self {
-        value.0
+        value.value
     }
 }
 
 impl From<String> for SecureString {
     fn from(value: String) -> Self {
-        Self(value)
+        SecureString {
+            value,
+            recipient: None,
+        }
     }
 }
 
+/// Wire format of [`SecureString`]. `public_key_base64` is the public key of
+/// the sender, `key_id` names the server key the payload is encrypted with.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SealedString {
+    key_id: String,
+    public_key_base64: String,
+    nonce_base64: String,
+    encrypted_base64: String,
+}
+
 impl Serialize for SecureString {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let (encrypted, nonce) = self.0.encrypt().map_err(serde::ser::Error::custom)?;
-        let encrypted_base64 = general_purpose::STANDARD.encode(encrypted);
-        let nonce_base64 = general_purpose::STANDARD.encode(nonce);
-        let mut seq = serializer.serialize_map(Some(2))?;
-        seq.serialize_entry("encrypted_base64", &encrypted_base64)?;
-        seq.serialize_entry("nonce_base64", &nonce_base64)?;
-        seq.end()
+        self.seal()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
     }
 }
 
@@ -88,47 +264,84 @@ impl<'de> Deserialize<'de> for SecureString {
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer.deserialize_map(CustomVisitor)
+        let sealed = SealedString::deserialize(deserializer)?;
+        SecureString::open(sealed).map_err(serde::de::Error::custom)
     }
 }
 
-struct CustomVisitor;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl<'de> Visitor<'de> for CustomVisitor {
-    type Value = SecureString;
+    fn public_key_base64(secret: &[u8; 32]) -> String {
+        general_purpose::STANDARD.encode(PublicKey::from(&StaticSecret::from(*secret)).as_bytes())
+    }
 
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "a map with keys 'encrypted_base64' and 'nonce_base64'")
+    #[test]
+    fn agrees_on_the_cipher_on_both_sides() {
+        let client = StaticSecret::from(generate_secret_key());
+        let server = StaticSecret::from(generate_secret_key());
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let encrypted = derive_cipher(&client, &PublicKey::from(&server))
+            .unwrap()
+            .encrypt(&nonce, b"secret".as_ref())
+            .unwrap();
+        let decrypted = derive_cipher(&server, &PublicKey::from(&client))
+            .unwrap()
+            .decrypt(&nonce, encrypted.as_ref())
+            .unwrap();
+        assert_eq!(decrypted, b"secret");
+        let other = StaticSecret::from(generate_secret_key());
+        assert!(
+            derive_cipher(&other, &PublicKey::from(&client))
+                .unwrap()
+                .decrypt(&nonce, encrypted.as_ref())
+                .is_err()
+        );
     }
 
-    fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
-    where
-        M: MapAccess<'de>,
-    {
-        let mut encrypted_base64: Option<String> = None;
-        let mut nonce_base64: Option<String> = None;
-
-        while let Some(k) = map.next_key::<&str>()? {
-            if k == "encrypted_base64" {
-                encrypted_base64 = Some(map.next_value()?);
-            } else if k == "nonce_base64" {
-                nonce_base64 = Some(map.next_value()?);
-            } else {
-                return Err(serde::de::Error::custom(&format!("Invalid key: {}", k)));
-            }
-        }
-        if let (Some(encrypted_base64), Some(nonce_base64)) = (encrypted_base64, nonce_base64) {
-            let nonce_array = general_purpose::STANDARD
-                .decode(nonce_base64)
-                .map_err(serde::de::Error::custom)?;
-            let nonce = GenericArray::from_slice(&nonce_array);
-            let encrypted = general_purpose::STANDARD
-                .decode(encrypted_base64)
-                .map_err(serde::de::Error::custom)?;
-            let text = String::decrypt(encrypted, nonce).map_err(serde::de::Error::custom)?;
-            Ok(SecureString(text))
-        } else {
-            Err(serde::de::Error::custom("Missing key(s)"))
-        }
+    #[test]
+    fn rejects_secret_keys_of_the_wrong_length() {
+        let short = general_purpose::STANDARD.encode([0u8; 16]);
+        assert!(decode_secret_key(&short).is_err());
+        let key = generate_secret_key();
+        let encoded = general_purpose::STANDARD.encode(key);
+        assert_eq!(decode_secret_key(&encoded).unwrap(), key);
     }
-}
\ No newline at end of file
+
+    // the only test using the key ring, which is global to the process
+    #[test]
+    fn opens_the_secure_strings_sealed_by_a_client() {
+        let old = generate_secret_key();
+        let new = generate_secret_key();
+        install_server_keys(vec![("old".to_owned(), old)]).unwrap();
+        let (key_id, public_key) = server_public_key().unwrap();
+        assert_eq!(key_id, "old");
+        assert_eq!(public_key, public_key_base64(&old));
+        // the server can only reply to a client
+        assert!(SecureString::from("secret".to_owned()).seal().is_err());
+
+        install_server_public_key(&key_id, &public_key).unwrap();
+        let seal = || SecureString::from("secret".to_owned()).seal().unwrap();
+        let (sealed, mut tampered, dropped) = (seal(), seal(), seal());
+        assert_eq!(sealed.key_id, "old");
+        tampered.encrypted_base64 = general_purpose::STANDARD.encode(b"not the ciphertext");
+
+        // a rotated server still opens the payloads sealed with its old key
+        install_server_keys(vec![("new".to_owned(), new), ("old".to_owned(), old)]).unwrap();
+        let client_key = sealed.public_key_base64.clone();
+        let opened = SecureString::open(sealed).unwrap();
+        let reply = opened.reply("answer".to_owned()).seal().unwrap();
+        assert_eq!(String::from(opened), "secret");
+        assert_eq!(reply.key_id, "old");
+        assert_eq!(reply.public_key_base64, public_key_base64(&old));
+        assert_ne!(reply.public_key_base64, client_key);
+        assert!(SecureString::open(tampered).is_err());
+
+        install_server_keys(vec![("new".to_owned(), new)]).unwrap();
+        assert!(matches!(
+            SecureString::open(dropped).unwrap_err().downcast_ref(),
+            Some(EncryptionError::UnknownKey(id)) if id == "old"
+        ));
+    }
+}
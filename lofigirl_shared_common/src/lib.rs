@@ -1,15 +1,16 @@
 pub mod api;
 pub mod config;
 pub mod track;
-pub mod jwt;
-
-mod encrypt;
+pub mod schedule;
+pub mod encrypt;
+pub mod moment;
+pub mod stream;
 
 use std::{sync::LazyLock, time::Duration};
 
 pub static REGULAR_INTERVAL: LazyLock<Duration> = LazyLock::new(|| Duration::from_secs(15));
 pub static FAST_TRY_INTERVAL: LazyLock<Duration> = LazyLock::new(|| Duration::from_secs(5));
-pub static STREAM_LAST_READ_TIMEOUT: LazyLock<Duration> =
+pub static STREAM_GRACE_PERIOD: LazyLock<Duration> =
     LazyLock::new(|| Duration::from_secs(300));
 pub static CLIENT_PING_INTERVAL: LazyLock<Duration> = LazyLock::new(|| Duration::from_secs(30));
 pub static SERVER_PING_TIMEOUT_INTERVAL: LazyLock<Duration> =
@@ -20,6 +21,14 @@ pub const TRACK_END_POINT: &str = "/track";
 pub const TRACK_SOCKET_END_POINT: &str = "/track_ws";
 pub const LASTFM_SESSION_END_POINT: &str = "/session";
 pub const TOKEN_END_POINT: &str = "/token";
+pub const TOKEN_REFRESH_END_POINT: &str = "/token/refresh";
+pub const TOKENS_END_POINT: &str = "/tokens";
 pub const HEALTH_END_POINT: &str = "/health";
-
-pub const ENCRYPTION_KEY_BASE64: &[u8; 44] = b"MTIzNDU2NzgxMjM0NTY3ODEyMzQ1Njc4MTIzNDU2Nzg=";
\ No newline at end of file
+pub const LIVENESS_END_POINT: &str = "/health/live";
+pub const READINESS_END_POINT: &str = "/health/ready";
+pub const SUBSCRIPTION_END_POINT: &str = "/subscriptions";
+pub const ENCRYPTION_KEY_END_POINT: &str = "/key";
+pub const HISTORY_END_POINT: &str = "/history";
+pub const OUTBOX_END_POINT: &str = "/outbox";
+pub const STREAMS_END_POINT: &str = "/streams";
+pub const METRICS_END_POINT: &str = "/metrics";
\ No newline at end of file
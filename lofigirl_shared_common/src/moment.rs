@@ -0,0 +1,160 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// A point in time, written as unix seconds, RFC 3339, `now` or relative to
+/// now such as `-25m` or `-1h30m`. Relative moments support `d`, `h`, `m` and
+/// `s` units and are resolved by the reader.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Moment {
+    Absolute(i64),
+    /// Seconds before now.
+    Ago(i64),
+}
+
+impl Moment {
+    /// Unix seconds of the moment.
+    pub fn resolve(&self, now: DateTime<Utc>) -> i64 {
+        match self {
+            Moment::Absolute(timestamp) => *timestamp,
+            Moment::Ago(seconds) => now.timestamp() - seconds,
+        }
+    }
+}
+
+impl FromStr for Moment {
+    type Err = MomentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "now" {
+            return Ok(Moment::Ago(0));
+        }
+        if let Some(relative) = s.strip_prefix('-') {
+            return parse_duration(relative).map(Moment::Ago);
+        }
+        if let Ok(timestamp) = s.parse() {
+            return Ok(Moment::Absolute(timestamp));
+        }
+        DateTime::parse_from_rfc3339(s)
+            .map(|time| Moment::Absolute(time.timestamp()))
+            .map_err(|_| MomentError::InvalidFormat)
+    }
+}
+
+/// Parses durations such as `1h30m` into seconds.
+fn parse_duration(s: &str) -> Result<i64, MomentError> {
+    let mut seconds = 0i64;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => 24 * 60 * 60,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(MomentError::InvalidFormat),
+        };
+        let value: i64 = number.parse().map_err(|_| MomentError::InvalidFormat)?;
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .ok_or(MomentError::InvalidFormat)?;
+        number.clear();
+    }
+    (number.is_empty() && !s.is_empty())
+        .then_some(seconds)
+        .ok_or(MomentError::InvalidFormat)
+}
+
+impl TryFrom<String> for Moment {
+    type Error = MomentError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Moment> for String {
+    fn from(value: Moment) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Moment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Moment::Absolute(timestamp) => write!(f, "{}", timestamp),
+            Moment::Ago(0) => write!(f, "now"),
+            Moment::Ago(seconds) => write!(f, "-{}s", seconds),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MomentError {
+    #[error("Time should be unix seconds, RFC 3339, now or relative such as -25m.")]
+    InvalidFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_760_875_200, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_relative_moments() {
+        assert_eq!("now".parse::<Moment>().unwrap(), Moment::Ago(0));
+        assert_eq!("-0s".parse::<Moment>().unwrap(), Moment::Ago(0));
+        assert_eq!("-25m".parse::<Moment>().unwrap(), Moment::Ago(25 * 60));
+        assert_eq!("-1h30m".parse::<Moment>().unwrap(), Moment::Ago(90 * 60));
+        assert_eq!(
+            "-0s".parse::<Moment>().unwrap().resolve(now()),
+            1_760_875_200
+        );
+    }
+
+    #[test]
+    fn rejects_relative_moments_without_a_unit() {
+        assert!("-0".parse::<Moment>().is_err());
+        assert!("-25".parse::<Moment>().is_err());
+        assert!("-".parse::<Moment>().is_err());
+        assert!("-1h30".parse::<Moment>().is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!("-9223372036854775807d".parse::<Moment>().is_err());
+        assert!("-99999999999999999999s".parse::<Moment>().is_err());
+    }
+
+    #[test]
+    fn keeps_absolute_moments_in_the_future() {
+        let future = now().timestamp() + 24 * 60 * 60;
+        let moment = future.to_string().parse::<Moment>().unwrap();
+        assert_eq!(moment, Moment::Absolute(future));
+        assert_eq!(moment.resolve(now()), future);
+        let moment = "2100-01-01T00:00:00Z".parse::<Moment>().unwrap();
+        assert_eq!(moment.resolve(now()), 4_102_444_800);
+    }
+
+    #[test]
+    fn writes_moments_back() {
+        for moment in [
+            Moment::Ago(0),
+            Moment::Ago(90),
+            Moment::Absolute(1_760_875_200),
+        ] {
+            assert_eq!(moment.to_string().parse::<Moment>().unwrap(), moment);
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// Daily time window in UTC, written as `HH:MM-HH:MM`. The end may be earlier
+/// than the start for windows that span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Schedule {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let time = now.time();
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or(ScheduleError::InvalidFormat)?;
+        let start = NaiveTime::parse_from_str(start.trim(), TIME_FORMAT)
+            .map_err(|_| ScheduleError::InvalidFormat)?;
+        let end = NaiveTime::parse_from_str(end.trim(), TIME_FORMAT)
+            .map_err(|_| ScheduleError::InvalidFormat)?;
+        Ok(Schedule { start, end })
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = ScheduleError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(value: Schedule) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("Schedule should be in HH:MM-HH:MM format.")]
+    InvalidFormat,
+}
@@ -0,0 +1,206 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+const ID_LENGTH: usize = 11;
+/// Paths which carry the id as their next segment, e.g. `/live/<id>`.
+const ID_PATHS: [&str; 5] = ["live", "embed", "shorts", "v", "e"];
+
+/// The id of a YouTube stream. Parsed from a bare id, from `youtu.be/<id>`,
+/// or from the `watch?v=<id>`, `live/<id>`, `embed/<id>` and `shorts/<id>`
+/// links on the `www.`, `m.`, `music.` and nocookie hosts. The scheme of a
+/// link is optional.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct StreamId(String);
+
+impl StreamId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The canonical watch link of the stream.
+    pub fn url(&self) -> Url {
+        Url::parse_with_params("https://www.youtube.com/watch", [("v", &self.0)])
+            .expect("a valid youtube link")
+    }
+
+    /// Reads the id from a YouTube link.
+    pub fn from_url(url: &Url) -> Result<StreamId, StreamIdError> {
+        let host = url.host_str().ok_or(StreamIdError::NotYoutube)?;
+        let host = host.strip_prefix("www.").unwrap_or(host);
+        let mut segments = url.path_segments().into_iter().flatten();
+        let id = match host {
+            "youtu.be" => segments.next().map(str::to_owned),
+            "youtube.com" | "m.youtube.com" | "music.youtube.com" | "youtube-nocookie.com" => {
+                match segments.next() {
+                    Some("watch") => url
+                        .query_pairs()
+                        .find_map(|(key, value)| (key == "v").then(|| value.into_owned())),
+                    Some(path) if ID_PATHS.contains(&path) => segments.next().map(str::to_owned),
+                    _ => None,
+                }
+            }
+            _ => return Err(StreamIdError::NotYoutube),
+        };
+        id.filter(|id| is_id(id))
+            .map(StreamId)
+            .ok_or(StreamIdError::MissingId)
+    }
+}
+
+fn is_id(s: &str) -> bool {
+    s.len() == ID_LENGTH
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+impl FromStr for StreamId {
+    type Err = StreamIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if is_id(s) {
+            return Ok(StreamId(s.to_owned()));
+        }
+        let url = match Url::parse(s) {
+            Ok(url) => url,
+            // links without a scheme, such as youtu.be/<id>
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                Url::parse(&format!("https://{}", s)).map_err(|_| StreamIdError::InvalidFormat)?
+            }
+            Err(_) => return Err(StreamIdError::InvalidFormat),
+        };
+        StreamId::from_url(&url)
+    }
+}
+
+impl TryFrom<String> for StreamId {
+    type Error = StreamIdError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<StreamId> for String {
+    fn from(value: StreamId) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StreamIdError {
+    #[error("Neither a youtube link nor a video id")]
+    InvalidFormat,
+    #[error("Not a youtube link")]
+    NotYoutube,
+    #[error("The youtube link has no video id")]
+    MissingId,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "jfKfPfyJRdk";
+
+    fn parse(s: &str) -> Result<String, StreamIdError> {
+        s.parse::<StreamId>().map(String::from)
+    }
+
+    #[test]
+    fn parses_links() {
+        for link in [
+            "https://www.youtube.com/watch?v=jfKfPfyJRdk",
+            "https://www.youtube.com/watch?feature=share&v=jfKfPfyJRdk",
+            "https://youtube.com/watch?v=jfKfPfyJRdk",
+            "https://youtu.be/jfKfPfyJRdk",
+            "https://youtu.be/jfKfPfyJRdk?si=abc",
+            "https://www.youtube.com/live/jfKfPfyJRdk",
+            "https://www.youtube.com/embed/jfKfPfyJRdk",
+            "https://www.youtube.com/shorts/jfKfPfyJRdk",
+            "https://www.youtube-nocookie.com/embed/jfKfPfyJRdk",
+            "https://m.youtube.com/watch?v=jfKfPfyJRdk",
+            "https://music.youtube.com/watch?v=jfKfPfyJRdk",
+        ] {
+            assert_eq!(parse(link).unwrap(), ID, "{}", link);
+        }
+    }
+
+    #[test]
+    fn parses_bare_ids() {
+        assert_eq!(parse(ID).unwrap(), ID);
+        assert_eq!(parse("  jfKfPfyJRdk\n").unwrap(), ID);
+        assert_eq!(parse("a-b_c123456").unwrap(), "a-b_c123456");
+    }
+
+    #[test]
+    fn parses_links_without_scheme() {
+        for link in [
+            "youtu.be/jfKfPfyJRdk",
+            "www.youtube.com/watch?v=jfKfPfyJRdk",
+            "m.youtube.com/live/jfKfPfyJRdk",
+        ] {
+            assert_eq!(parse(link).unwrap(), ID, "{}", link);
+        }
+    }
+
+    #[test]
+    fn rejects_other_hosts() {
+        for link in [
+            "https://example.com/watch?v=jfKfPfyJRdk",
+            "https://youtube.com.example.com/watch?v=jfKfPfyJRdk",
+            "https://vimeo.com/jfKfPfyJRdk",
+        ] {
+            assert!(
+                matches!(parse(link), Err(StreamIdError::NotYoutube)),
+                "{}",
+                link
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_links_without_id() {
+        for link in [
+            "https://www.youtube.com/",
+            "https://www.youtube.com/watch",
+            "https://www.youtube.com/watch?v=short",
+            "https://www.youtube.com/channel/jfKfPfyJRdk",
+            "https://www.youtube.com/live/",
+            "https://youtu.be/",
+            "https://youtu.be/jfKfPfyJRdk1",
+        ] {
+            assert!(
+                matches!(parse(link), Err(StreamIdError::MissingId)),
+                "{}",
+                link
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_input() {
+        for input in ["", "jfKfPfyJRd!", "http://", "not a link"] {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn links_to_the_stream() {
+        let id = ID.parse::<StreamId>().unwrap();
+        assert_eq!(
+            id.url().as_str(),
+            "https://www.youtube.com/watch?v=jfKfPfyJRdk"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use lofigirl_shared_common::track::Track;
+use reqwest::Client;
+use serde::Deserialize;
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+use crate::listener::{PendingListen, Service, ServiceError};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// Client of the Last.fm scrobbling API. Requests are given up after the
+/// timeout of the HTTP client.
+pub struct LastFm {
+    client: Client,
+    api_key: String,
+    api_secret: String,
+    session_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SessionResponse {
+    session: Session,
+}
+
+#[derive(Deserialize)]
+struct Session {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: u32,
+    message: String,
+}
+
+impl LastFm {
+    pub fn new(client: Client, api_key: &str, api_secret: &str) -> LastFm {
+        LastFm {
+            client,
+            api_key: api_key.to_owned(),
+            api_secret: api_secret.to_owned(),
+            session_key: None,
+        }
+    }
+
+    pub fn session_key(&self) -> Option<&str> {
+        self.session_key.as_deref()
+    }
+
+    pub fn authenticate_with_session_key(&mut self, session_key: &str) {
+        self.session_key = Some(session_key.to_owned());
+    }
+
+    pub async fn authenticate_with_password(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> Result<()> {
+        let params = BTreeMap::from([
+            ("username".to_owned(), username.to_owned()),
+            ("password".to_owned(), password.to_owned()),
+        ]);
+        let response: SessionResponse = self.call("auth.getMobileSession", params).await?;
+        self.session_key = Some(response.session.key);
+        Ok(())
+    }
+
+    pub async fn now_playing(&self, track: &Track) -> Result<()> {
+        let params = BTreeMap::from([
+            ("artist".to_owned(), track.artist.clone()),
+            ("track".to_owned(), track.song.clone()),
+        ]);
+        self.call_with_session::<IgnoredAny>("track.updateNowPlaying", params)
+            .await?;
+        Ok(())
+    }
+
+    /// Scrobbles up to 50 listens in a single request.
+    pub async fn scrobble(&self, listens: &[PendingListen]) -> Result<()> {
+        self.call_with_session::<IgnoredAny>("track.scrobble", scrobble_params(listens))
+            .await?;
+        Ok(())
+    }
+
+    async fn call_with_session<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        mut params: BTreeMap<String, String>,
+    ) -> Result<T> {
+        let session_key = self
+            .session_key
+            .clone()
+            .ok_or(ServiceError::NotSet(Service::LastFM))?;
+        params.insert("sk".to_owned(), session_key);
+        self.call(method, params).await
+    }
+
+    /// Calls a write method of the API, signed with the secret.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        mut params: BTreeMap<String, String>,
+    ) -> Result<T> {
+        params.insert("method".to_owned(), method.to_owned());
+        params.insert("api_key".to_owned(), self.api_key.clone());
+        params.insert("api_sig".to_owned(), signature(&params, &self.api_secret));
+        params.insert("format".to_owned(), "json".to_owned());
+        let body = self
+            .client
+            .post(API_URL)
+            .form(&params)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        parse_response(&body)
+    }
+}
+
+/// The parameters of a scrobble of the listens, numbered in their order.
+fn scrobble_params(listens: &[PendingListen]) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    for (i, listen) in listens.iter().enumerate() {
+        params.insert(format!("artist[{i}]"), listen.track.artist.clone());
+        params.insert(format!("track[{i}]"), listen.track.song.clone());
+        params.insert(format!("timestamp[{i}]"), listen.listened_at.to_string());
+    }
+    params
+}
+
+/// Signs the parameters of a call, which are all but the format, in name order.
+fn signature(params: &BTreeMap<String, String>, secret: &str) -> String {
+    let mut signed = String::new();
+    for (name, value) in params {
+        signed.push_str(name);
+        signed.push_str(value);
+    }
+    signed.push_str(secret);
+    format!("{:x}", md5::compute(signed))
+}
+
+/// Reads the response of a call, which is an error object if the call was rejected.
+fn parse_response<T: DeserializeOwned>(body: &[u8]) -> Result<T> {
+    if let Ok(error) = serde_json::from_slice::<ErrorResponse>(body) {
+        return Err(ServiceError::Rejected(
+            Service::LastFM,
+            format!("{} (error {})", error.message, error.error),
+        )
+        .into());
+    }
+    Ok(serde_json::from_slice(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn signs_the_documented_example() {
+        let params = params(&[
+            ("method", "auth.getSession"),
+            ("token", "yyyyyyyyyy"),
+            ("api_key", "xxxxxxxxxx"),
+        ]);
+        assert_eq!(
+            signature(&params, "ilovecher"),
+            "dc8837f76dba853ce9f2b0be643b1505"
+        );
+    }
+
+    #[test]
+    fn signs_a_scrobble_in_name_order() {
+        let listens = [PendingListen {
+            track: Track {
+                artist: "Artist".to_owned(),
+                song: "Song".to_owned(),
+            },
+            listened_at: 1_760_875_200,
+        }];
+        let mut params = scrobble_params(&listens);
+        params.extend(self::params(&[
+            ("sk", "session"),
+            ("method", "track.scrobble"),
+            ("api_key", "key"),
+        ]));
+        assert_eq!(
+            signature(&params, "secret"),
+            "e978d4fbe7fe47e07d40f402cc77210f"
+        );
+    }
+
+    #[test]
+    fn numbers_the_scrobbled_listens() {
+        let listens: Vec<PendingListen> = (0..2)
+            .map(|i| PendingListen {
+                track: Track {
+                    artist: format!("Artist {i}"),
+                    song: format!("Song {i}"),
+                },
+                listened_at: 100 + i,
+            })
+            .collect();
+        assert_eq!(
+            scrobble_params(&listens),
+            params(&[
+                ("artist[0]", "Artist 0"),
+                ("track[0]", "Song 0"),
+                ("timestamp[0]", "100"),
+                ("artist[1]", "Artist 1"),
+                ("track[1]", "Song 1"),
+                ("timestamp[1]", "101"),
+            ])
+        );
+    }
+
+    #[test]
+    fn reads_the_response() {
+        let body = br#"{"session":{"name":"user","key":"abc","subscriber":0}}"#;
+        let response: SessionResponse = parse_response(body).unwrap();
+        assert_eq!(response.session.key, "abc");
+    }
+
+    #[test]
+    fn rejects_on_an_error_response() {
+        let body = br#"{"error":9,"message":"Invalid session key - Please re-authenticate"}"#;
+        let error = parse_response::<IgnoredAny>(body).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ServiceError>(),
+            Some(ServiceError::Rejected(Service::LastFM, _))
+        ));
+        assert_eq!(
+            error.to_string(),
+            ServiceError::Rejected(
+                Service::LastFM,
+                "Invalid session key - Please re-authenticate (error 9)".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn fails_on_an_unexpected_response() {
+        assert!(parse_response::<SessionResponse>(b"<html></html>").is_err());
+    }
+}
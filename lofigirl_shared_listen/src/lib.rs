@@ -1 +1,4 @@
+mod lastfm;
+mod listenbrainz;
 pub mod listener;
+pub mod outbox;
@@ -0,0 +1,239 @@
+use anyhow::Result;
+use lofigirl_shared_common::track::Track;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::listener::{PendingListen, Service, ServiceError};
+
+const API_URL: &str = "https://api.listenbrainz.org/1";
+
+/// Client of the ListenBrainz API. Requests are given up after the timeout of
+/// the HTTP client.
+pub struct ListenBrainz {
+    client: Client,
+    token: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ListenType {
+    Single,
+    PlayingNow,
+    Import,
+}
+
+#[derive(Serialize)]
+struct SubmitListens<'a> {
+    listen_type: ListenType,
+    payload: Vec<Payload<'a>>,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listened_at: Option<i64>,
+    track_metadata: TrackMetadata<'a>,
+}
+
+#[derive(Serialize)]
+struct TrackMetadata<'a> {
+    artist_name: &'a str,
+    track_name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ValidateToken {
+    valid: bool,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl<'a> Payload<'a> {
+    fn new(track: &'a Track, listened_at: Option<i64>) -> Payload<'a> {
+        Payload {
+            listened_at,
+            track_metadata: TrackMetadata {
+                artist_name: &track.artist,
+                track_name: &track.song,
+            },
+        }
+    }
+}
+
+impl ListenBrainz {
+    /// Checks the user token with the service.
+    pub async fn authenticate(client: Client, token: &str) -> Result<ListenBrainz> {
+        let listenbrainz = ListenBrainz {
+            client,
+            token: token.to_owned(),
+        };
+        let response = listenbrainz
+            .client
+            .get(format!("{API_URL}/validate-token"))
+            .header(AUTHORIZATION, listenbrainz.authorization())
+            .send()
+            .await?;
+        let validation: ValidateToken = check(response).await?.json().await?;
+        if !validation.valid {
+            return Err(ServiceError::Rejected(
+                Service::ListenBrainz,
+                "Token is not valid".to_owned(),
+            )
+            .into());
+        }
+        Ok(listenbrainz)
+    }
+
+    pub async fn listen(&self, listen: &PendingListen) -> Result<()> {
+        let payload = vec![Payload::new(&listen.track, Some(listen.listened_at))];
+        self.submit(ListenType::Single, payload).await
+    }
+
+    pub async fn playing_now(&self, track: &Track) -> Result<()> {
+        self.submit(ListenType::PlayingNow, vec![Payload::new(track, None)])
+            .await
+    }
+
+    /// Sends up to 1000 listens in a single request.
+    pub async fn import(&self, listens: &[PendingListen]) -> Result<()> {
+        let payload = listens
+            .iter()
+            .map(|listen| Payload::new(&listen.track, Some(listen.listened_at)))
+            .collect();
+        self.submit(ListenType::Import, payload).await
+    }
+
+    async fn submit(&self, listen_type: ListenType, payload: Vec<Payload<'_>>) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{API_URL}/submit-listens"))
+            .header(AUTHORIZATION, self.authorization())
+            .json(&SubmitListens {
+                listen_type,
+                payload,
+            })
+            .send()
+            .await?;
+        check(response).await?;
+        Ok(())
+    }
+
+    fn authorization(&self) -> String {
+        format!("Token {}", self.token)
+    }
+}
+
+/// Fails with the error of the service if the request was not successful.
+async fn check(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.bytes().await.unwrap_or_default();
+    Err(rejection(status, &body).into())
+}
+
+/// The error of the service, or the status if the body does not tell.
+fn rejection(status: StatusCode, body: &[u8]) -> ServiceError {
+    let reason = match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error) => error.error,
+        Err(_) => status.to_string(),
+    };
+    ServiceError::Rejected(Service::ListenBrainz, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn track(i: i64) -> Track {
+        Track {
+            artist: format!("Artist {i}"),
+            song: format!("Song {i}"),
+        }
+    }
+
+    fn serialize(listen_type: ListenType, payload: Vec<Payload<'_>>) -> serde_json::Value {
+        serde_json::to_value(SubmitListens {
+            listen_type,
+            payload,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn serializes_a_single_listen() {
+        let track = track(0);
+        let payload = vec![Payload::new(&track, Some(1_760_875_200))];
+        assert_eq!(
+            serialize(ListenType::Single, payload),
+            json!({
+                "listen_type": "single",
+                "payload": [{
+                    "listened_at": 1_760_875_200,
+                    "track_metadata": {"artist_name": "Artist 0", "track_name": "Song 0"}
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_playing_now_without_a_time() {
+        let track = track(0);
+        let payload = vec![Payload::new(&track, None)];
+        assert_eq!(
+            serialize(ListenType::PlayingNow, payload),
+            json!({
+                "listen_type": "playing_now",
+                "payload": [{
+                    "track_metadata": {"artist_name": "Artist 0", "track_name": "Song 0"}
+                }]
+            })
+        );
+    }
+
+    #[test]
+    fn serializes_an_import_in_order() {
+        let tracks: Vec<Track> = (0..2).map(track).collect();
+        let payload = tracks
+            .iter()
+            .zip(100..)
+            .map(|(track, at)| Payload::new(track, Some(at)))
+            .collect();
+        let value = serialize(ListenType::Import, payload);
+        assert_eq!(value["listen_type"], "import");
+        assert_eq!(value["payload"][0]["listened_at"], 100);
+        assert_eq!(value["payload"][1]["listened_at"], 101);
+        assert_eq!(
+            value["payload"][1]["track_metadata"]["track_name"],
+            "Song 1"
+        );
+    }
+
+    #[test]
+    fn rejects_with_the_error_of_the_service() {
+        let body = br#"{"code":401,"error":"Invalid authorization token."}"#;
+        assert_eq!(
+            rejection(StatusCode::UNAUTHORIZED, body).to_string(),
+            ServiceError::Rejected(
+                Service::ListenBrainz,
+                "Invalid authorization token.".to_owned()
+            )
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn rejects_with_the_status_without_an_error() {
+        assert_eq!(
+            rejection(StatusCode::BAD_GATEWAY, b"<html></html>").to_string(),
+            ServiceError::Rejected(Service::ListenBrainz, "502 Bad Gateway".to_owned()).to_string()
+        );
+    }
+}
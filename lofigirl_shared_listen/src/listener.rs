@@ -1,78 +1,290 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::Result;
-use listenbrainz::ListenBrainz;
 use lofigirl_shared_common::api::Action;
 use lofigirl_shared_common::config::{
     LastFMApiConfig, LastFMClientConfig, LastFMClientSessionConfig, ListenBrainzConfig,
 };
 use lofigirl_shared_common::track::Track;
-use rustfm_scrobble_proxy::{Scrobble, Scrobbler};
+use reqwest::Client;
 use thiserror::Error;
 use tracing::info;
 
+use crate::lastfm::LastFm;
+use crate::listenbrainz::ListenBrainz;
+
+/// Most scrobbles Last.fm accepts in a single batch.
+const LASTFM_BATCH_SIZE: usize = 50;
+/// Most listens ListenBrainz accepts in a single import.
+const LISTENBRAINZ_BATCH_SIZE: usize = 1_000;
+/// Longest a call to a service may take, unless set with [`Listener::with_timeout`].
+pub const DEFAULT_SERVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A scrobbling service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Service {
+    LastFM,
+    ListenBrainz,
+}
+
+impl Service {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Service::LastFM => "lastfm",
+            Service::ListenBrainz => "listenbrainz",
+        }
+    }
+}
+
+impl std::str::FromStr for Service {
+    type Err = ServiceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lastfm" => Ok(Service::LastFM),
+            "listenbrainz" => Ok(Service::ListenBrainz),
+            _ => Err(ServiceError::UnknownService(s.to_owned())),
+        }
+    }
+}
+
+/// A listen which is sent later than it happened, with the unix second it
+/// happened at.
+#[derive(Debug, Clone)]
+pub struct PendingListen {
+    pub track: Track,
+    pub listened_at: i64,
+}
+
+/// Outcome of an action on each service, `None` for the services which are not set.
 #[derive(Default)]
+pub struct SendReport {
+    pub lastfm: Option<Result<()>>,
+    pub listenbrainz: Option<Result<()>>,
+}
+
+impl SendReport {
+    /// Services the action has failed on, with their errors.
+    pub fn failures(&self) -> Vec<(Service, &anyhow::Error)> {
+        [
+            (Service::LastFM, &self.lastfm),
+            (Service::ListenBrainz, &self.listenbrainz),
+        ]
+        .into_iter()
+        .filter_map(|(service, result)| match result {
+            Some(Err(e)) => Some((service, e)),
+            _ => None,
+        })
+        .collect()
+    }
+
+    /// Fails with the first failure, if any.
+    pub fn into_result(self) -> Result<()> {
+        self.lastfm.transpose()?;
+        self.listenbrainz.transpose()?;
+        Ok(())
+    }
+}
+
+/// Sends the actions to Last.fm and ListenBrainz. Every request to a service
+/// is given up after the timeout. Cheap to clone.
+#[derive(Clone)]
 pub struct Listener {
-    lastfm_listener: Option<Scrobbler>,
-    listenbrainz_listener: Option<ListenBrainz>,
+    lastfm_listener: Option<Arc<LastFm>>,
+    listenbrainz_listener: Option<Arc<ListenBrainz>>,
+    client: Client,
 }
 
 impl Listener {
-    pub fn set_lastfm_listener(
+    /// A listener without any service, whose requests time out after
+    /// [`DEFAULT_SERVICE_TIMEOUT`].
+    pub fn new() -> Result<Listener> {
+        Ok(Listener {
+            lastfm_listener: None,
+            listenbrainz_listener: None,
+            client: http_client(DEFAULT_SERVICE_TIMEOUT)?,
+        })
+    }
+
+    /// Sets the longest time a request to a service may take, for the
+    /// services set afterwards.
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.client = http_client(timeout)?;
+        Ok(self)
+    }
+
+    pub async fn set_lastfm_listener(
         &mut self,
         lastfm_api: &LastFMApiConfig,
         lastfm_client: &LastFMClientConfig,
     ) -> Result<()> {
-        let mut lastfm_listener = Scrobbler::new(&lastfm_api.api_key, &lastfm_api.api_secret);
-        match &lastfm_client {
+        let mut lastfm_listener = LastFm::new(
+            self.client.clone(),
+            &lastfm_api.api_key,
+            &lastfm_api.api_secret,
+        );
+        match lastfm_client {
             LastFMClientConfig::PasswordAuth(pass_config) => {
                 lastfm_listener
-                    .authenticate_with_password(&pass_config.username, &pass_config.password)?;
+                    .authenticate_with_password(&pass_config.username, &pass_config.password)
+                    .await?;
             }
             LastFMClientConfig::SessionAuth(session_config) => {
                 lastfm_listener.authenticate_with_session_key(&session_config.session_key);
             }
         }
-        self.lastfm_listener = Some(lastfm_listener);
+        self.lastfm_listener = Some(Arc::new(lastfm_listener));
         Ok(())
     }
 
-    pub fn set_listenbrainz_listener(&mut self, listenbrainz: &ListenBrainzConfig) -> Result<()> {
-        let mut listenbrainz_listener = ListenBrainz::new();
-        listenbrainz_listener.authenticate(&listenbrainz.token)?;
-        self.listenbrainz_listener = Some(listenbrainz_listener);
+    pub async fn set_listenbrainz_listener(
+        &mut self,
+        listenbrainz: &ListenBrainzConfig,
+    ) -> Result<()> {
+        let listenbrainz_listener =
+            ListenBrainz::authenticate(self.client.clone(), &listenbrainz.token).await?;
+        self.listenbrainz_listener = Some(Arc::new(listenbrainz_listener));
         Ok(())
     }
 
-    pub fn send_listen(&self, track: &Track) -> Result<()> {
-        self.send_action(Action::Listened, track)
+    pub async fn send_listen(&self, track: &Track) -> Result<()> {
+        self.send_action(Action::Listened, track).await
     }
 
-    pub fn send_now_playing(&self, track: &Track) -> Result<()> {
-        self.send_action(Action::PlayingNow, track)
+    pub async fn send_now_playing(&self, track: &Track) -> Result<()> {
+        self.send_action(Action::PlayingNow, track).await
     }
 
-    fn send_action(&self, action: Action, track: &Track) -> Result<()> {
-        if let Some(l) = &self.lastfm_listener {
-            let scrobble = Scrobble::new(&track.artist, &track.song, None);
-            action.act_for_lastfm(l, &scrobble)?;
+    async fn send_action(&self, action: Action, track: &Track) -> Result<()> {
+        self.send_action_report(action, track).await.into_result()
+    }
+
+    /// Sends the action to every service at once, even if one of them fails.
+    /// A listen is sent with the current time.
+    pub async fn send_action_report(&self, action: Action, track: &Track) -> SendReport {
+        let listen = PendingListen {
+            track: track.clone(),
+            listened_at: unix_now(),
+        };
+        let lastfm = async {
+            let listener = self.lastfm_listener.as_ref()?;
+            Some(match action {
+                Action::Listened => listener.scrobble(std::slice::from_ref(&listen)).await,
+                Action::PlayingNow => listener.now_playing(track).await,
+            })
+        };
+        let listenbrainz = async {
+            let listener = self.listenbrainz_listener.as_ref()?;
+            Some(match action {
+                Action::Listened => listener.listen(&listen).await,
+                Action::PlayingNow => listener.playing_now(track).await,
+            })
+        };
+        let (lastfm, listenbrainz) = tokio::join!(lastfm, listenbrainz);
+        if lastfm.iter().chain(listenbrainz.iter()).all(|r| r.is_ok()) {
+            info!(
+                "Track \"{}\" has been marked {} for a listener",
+                track, action
+            );
+        }
+        SendReport {
+            lastfm,
+            listenbrainz,
+        }
+    }
+
+    /// Sends a listen to every service with the time it happened at.
+    pub async fn send_listen_report_at(&self, track: &Track, listened_at: i64) -> SendReport {
+        let listen = [PendingListen {
+            track: track.clone(),
+            listened_at,
+        }];
+        let lastfm = async {
+            self.lastfm_listener.as_ref()?;
+            Some(
+                self.send_listens(Service::LastFM, &listen)
+                    .await
+                    .map_err(|e| e.error),
+            )
+        };
+        let listenbrainz = async {
+            self.listenbrainz_listener.as_ref()?;
+            Some(
+                self.send_listens(Service::ListenBrainz, &listen)
+                    .await
+                    .map_err(|e| e.error),
+            )
+        };
+        let (lastfm, listenbrainz) = tokio::join!(lastfm, listenbrainz);
+        SendReport {
+            lastfm,
+            listenbrainz,
         }
-        if let Some(l) = &self.listenbrainz_listener {
-            action.act_for_listenbrainz(l, track)?;
+    }
+
+    /// Sends the listens to the service with their original times, in as
+    /// few requests as the service allows. The timeout applies to each request.
+    /// On failure, the listens sent by the requests before are counted in the
+    /// error, so that they are not sent again.
+    pub async fn send_listens(
+        &self,
+        service: Service,
+        listens: &[PendingListen],
+    ) -> Result<(), SendListensError> {
+        let batch_size = match service {
+            Service::LastFM => LASTFM_BATCH_SIZE,
+            Service::ListenBrainz => LISTENBRAINZ_BATCH_SIZE,
+        };
+        let mut sent = 0;
+        for chunk in listens.chunks(batch_size) {
+            self.send_batch(service, chunk)
+                .await
+                .map_err(|error| SendListensError { sent, error })?;
+            sent += chunk.len();
         }
         info!(
-            "Track \"{}\" has been marked {} for a listener",
-            track, action
+            "{} listens have been sent to {} for a listener",
+            listens.len(),
+            service.as_str()
         );
         Ok(())
     }
 
-    pub fn convert_client_to_session(
+    /// Sends the listens to the service in a single request.
+    async fn send_batch(&self, service: Service, listens: &[PendingListen]) -> Result<()> {
+        match service {
+            Service::LastFM => {
+                self.lastfm_listener
+                    .as_ref()
+                    .ok_or(ServiceError::NotSet(service))?
+                    .scrobble(listens)
+                    .await
+            }
+            Service::ListenBrainz => {
+                self.listenbrainz_listener
+                    .as_ref()
+                    .ok_or(ServiceError::NotSet(service))?
+                    .import(listens)
+                    .await
+            }
+        }
+    }
+
+    pub async fn convert_client_to_session(
         lastfm_api: &LastFMApiConfig,
         lastfm_client: &LastFMClientConfig,
     ) -> Result<LastFMClientSessionConfig> {
-        let mut lastfm_listener = Scrobbler::new(&lastfm_api.api_key, &lastfm_api.api_secret);
-        match &lastfm_client {
+        match lastfm_client {
             LastFMClientConfig::PasswordAuth(client) => {
-                lastfm_listener.authenticate_with_password(&client.username, &client.password)?;
+                let mut lastfm_listener = LastFm::new(
+                    http_client(DEFAULT_SERVICE_TIMEOUT)?,
+                    &lastfm_api.api_key,
+                    &lastfm_api.api_secret,
+                );
+                lastfm_listener
+                    .authenticate_with_password(&client.username, &client.password)
+                    .await?;
                 Ok(LastFMClientSessionConfig {
                     session_key: lastfm_listener
                         .session_key()
@@ -85,35 +297,39 @@ impl Listener {
     }
 }
 
+/// The HTTP client of the services, which gives up a request after the
+/// timeout, including reading its response.
+fn http_client(timeout: Duration) -> Result<Client> {
+    Ok(Client::builder().timeout(timeout).build()?)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
 #[derive(Error, Debug)]
 pub enum LastFMError {
     #[error("LastFM is not auth")]
     NoAuth,
 }
 
-trait ActForListener {
-    fn act_for_lastfm(&self, listener: &Scrobbler, scrobble: &Scrobble) -> Result<()>;
-    fn act_for_listenbrainz(&self, listener: &ListenBrainz, track: &Track) -> Result<()>;
+#[derive(Error, Debug)]
+pub enum ServiceError {
+    #[error("Unknown service {0}")]
+    UnknownService(String),
+    #[error("Listener for {} is not set", .0.as_str())]
+    NotSet(Service),
+    #[error("{} rejected the request: {}", .0.as_str(), .1)]
+    Rejected(Service, String),
 }
 
-impl ActForListener for Action {
-    fn act_for_lastfm(&self, listener: &Scrobbler, scrobble: &Scrobble) -> Result<()> {
-        match self {
-            Action::Listened => {
-                let _r = listener.scrobble(scrobble)?;
-            }
-            Action::PlayingNow => {
-                let _r = listener.now_playing(scrobble)?;
-            }
-        }
-        Ok(())
-    }
-
-    fn act_for_listenbrainz(&self, listener: &ListenBrainz, track: &Track) -> Result<()> {
-        match self {
-            Action::Listened => listener.listen(&track.artist, &track.song, None)?,
-            Action::PlayingNow => listener.playing_now(&track.artist, &track.song, None)?,
-        }
-        Ok(())
-    }
+/// Failure of [`Listener::send_listens`], after the service has accepted the
+/// first `sent` listens.
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct SendListensError {
+    pub sent: usize,
+    pub error: anyhow::Error,
 }
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+/// Wait before the first retry of a failed listen.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Longest wait between two retries.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 60 * 60);
+/// Last.fm rejects listens older than two weeks, so they are not retried after.
+pub const MAX_LISTEN_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// Listens sent to a service at once on recovery.
+pub const OUTBOX_BATCH_SIZE: usize = 50;
+
+/// Wait before the next retry of a listen which has failed `attempts` times,
+/// doubled after each failure.
+pub fn retry_delay(attempts: u32) -> Duration {
+    INITIAL_RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Oldest unix second a listen can still be sent with at `now`, the older
+/// listens are dropped.
+pub fn oldest_listen_at(now: i64) -> i64 {
+    now - MAX_LISTEN_AGE.as_secs() as i64
+}
+
+/// Whether a listen is too old to be sent at `now`.
+pub fn is_too_old(listened_at: i64, now: i64) -> bool {
+    listened_at < oldest_listen_at(now)
+}
+
+/// Splits a batch at the first listen a service has not accepted, into the
+/// sent listens and the ones to retry.
+pub fn split_sent<T>(batch: &[T], sent: usize) -> (&[T], &[T]) {
+    batch.split_at(sent.min(batch.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_the_retry_delay() {
+        assert_eq!(retry_delay(0), INITIAL_RETRY_DELAY);
+        assert_eq!(retry_delay(1), INITIAL_RETRY_DELAY);
+        assert_eq!(retry_delay(2), INITIAL_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(5), INITIAL_RETRY_DELAY * 16);
+    }
+
+    #[test]
+    fn caps_the_retry_delay() {
+        assert!(retry_delay(9) < MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn keeps_listens_until_the_max_age() {
+        let now = 2_000_000_000;
+        let max_age = 14 * 24 * 60 * 60;
+        assert_eq!(oldest_listen_at(now), now - max_age);
+        assert!(!is_too_old(now, now));
+        // a listen exactly at the max age is still sent, one second older is not
+        assert!(!is_too_old(now - max_age, now));
+        assert!(is_too_old(now - max_age - 1, now));
+    }
+
+    #[test]
+    fn keeps_only_the_unsent_listens_of_a_failed_batch() {
+        let batch = [1, 2, 3, 4, 5];
+        assert_eq!(split_sent(&batch, 2), (&batch[..2], &batch[2..]));
+        assert_eq!(split_sent(&batch, 0), (&[][..], &batch[..]));
+        assert_eq!(split_sent(&batch, 5), (&batch[..], &[][..]));
+    }
+
+    #[test]
+    fn ignores_more_sent_listens_than_the_batch() {
+        let batch = [1, 2, 3];
+        assert_eq!(split_sent(&batch, 7), (&batch[..], &[][..]));
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::Result;
+use url::Url;
+
+/// How long yt-dlp may take to look up a channel before it is killed.
+#[cfg(feature = "native_yt_dlp")]
+const LOOKUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The channel a video is published on.
+#[derive(Debug, Clone)]
+pub struct VideoChannel {
+    /// Channel id, such as `UCSJ4gkVC6NrvII8umztf0Ow`.
+    pub id: String,
+    /// Handle of the channel, such as `@LofiGirl`, if it has one.
+    pub handle: Option<String>,
+}
+
+#[cfg(feature = "native_yt_dlp")]
+pub async fn video_channel(url: &Url) -> Result<VideoChannel> {
+    let output = tokio::process::Command::new("yt-dlp")
+        .arg("--skip-download")
+        .arg("--print")
+        .arg("channel_id")
+        .arg("--print")
+        .arg("uploader_id")
+        .arg(url.as_str())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(LOOKUP_TIMEOUT, output)
+        .await
+        .map_err(|_| anyhow::anyhow!("yt-dlp did not find the channel of {} in time", url))??;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("yt-dlp command failed: {}", stderr));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim);
+    let id = lines
+        .next()
+        .filter(|id| !id.is_empty() && *id != "NA")
+        .ok_or_else(|| anyhow::anyhow!("yt-dlp did not print the channel of {}", url))?;
+    let handle = lines
+        .next()
+        .filter(|handle| handle.starts_with('@'))
+        .map(str::to_owned);
+    Ok(VideoChannel {
+        id: id.to_owned(),
+        handle,
+    })
+}
+
+#[cfg(feature = "rusty_ytdl_backend")]
+pub async fn video_channel(url: &Url) -> Result<VideoChannel> {
+    let info = rusty_ytdl::Video::new(url.as_str())?
+        .get_basic_info()
+        .await?;
+    let author = info
+        .video_details
+        .author
+        .ok_or_else(|| anyhow::anyhow!("No channel is found for {}", url))?;
+    let handle = author
+        .user_url
+        .rsplit('/')
+        .next()
+        .filter(|handle| handle.starts_with('@'))
+        .map(str::to_owned);
+    Ok(VideoChannel {
+        id: author.id,
+        handle,
+    })
+}
+
+#[cfg(feature = "rustube_backend")]
+pub async fn video_channel(url: &Url) -> Result<VideoChannel> {
+    let descrambler = rustube::VideoFetcher::from_url(url)?.fetch().await?;
+    Ok(VideoChannel {
+        id: descrambler.video_details().channel_id.clone(),
+        handle: None,
+    })
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use lofigirl_shared_common::config::OverlayConfig;
+
+use crate::ocr::OcrEngine;
+
+/// Outcome of checking a single runtime dependency.
+pub struct DependencyCheck {
+    pub name: String,
+    /// Details on success, the reason on failure.
+    pub result: Result<String>,
+}
+
+/// Checks that tesseract can be initialized with the tessdata and languages
+/// of every overlay profile, and that the capture backend is usable.
+pub fn check_dependencies(overlay: &OverlayConfig) -> Vec<DependencyCheck> {
+    let profiles = std::iter::once(overlay.base_profile()).chain(
+        overlay
+            .profiles
+            .iter()
+            .filter_map(|p| overlay.profile_named(&p.name)),
+    );
+    let mut checks: Vec<DependencyCheck> = profiles
+        .map(|profile| DependencyCheck {
+            name: format!("tesseract for profile \"{}\"", profile.name),
+            result: OcrEngine::new(&profile.ocr).map(|_| {
+                format!(
+                    "tessdata at {}",
+                    profile
+                        .ocr
+                        .tessdata_path
+                        .as_deref()
+                        .unwrap_or("default path")
+                )
+            }),
+        })
+        .collect();
+    checks.push(DependencyCheck {
+        name: "capture backend".to_owned(),
+        result: check_capture_backend(),
+    });
+    checks
+}
+
+#[cfg(feature = "native_yt_dlp")]
+fn check_capture_backend() -> Result<String> {
+    let output = std::process::Command::new("yt-dlp")
+        .arg("--version")
+        .output()?;
+    output
+        .status
+        .success()
+        .then_some(())
+        .ok_or_else(|| anyhow::anyhow!("yt-dlp --version has failed"))?;
+    Ok(format!(
+        "yt-dlp {}",
+        String::from_utf8_lossy(&output.stdout).trim()
+    ))
+}
+
+#[cfg(not(feature = "native_yt_dlp"))]
+fn check_capture_backend() -> Result<String> {
+    Ok("built-in, no external program needed".to_owned())
+}
@@ -1,8 +1,7 @@
-use std::sync::LazyLock;
+use std::path::Path;
 
 use anyhow::Result;
-use leptess::LepTess;
-use opencv::boxed_ref::BoxedRef;
+use lofigirl_shared_common::config::{OverlayProfile, PreprocessStage};
 use opencv::core::Vector;
 use opencv::core::{Mat, MatTraitConst, Rect_};
 use opencv::videoio::VideoCapture;
@@ -12,82 +11,131 @@ use tracing::info;
 use url::Url;
 
 use crate::capture::YoutubeLinkCapturer;
+use crate::ocr::OcrEngine;
+use crate::preprocess::{default_stages, ApplyStage as _};
 
 use lofigirl_shared_common::track::Track;
 
 const DPI: i32 = 70;
-static LOW_BOUNDS: LazyLock<BoxedRef<Mat>> =
-    LazyLock::new(|| Mat::from_slice(&[200, 200, 200]).unwrap());
-static HIGH_BOUNDS: LazyLock<BoxedRef<Mat>> =
-    LazyLock::new(|| Mat::from_slice(&[255, 255, 255]).unwrap());
 
 pub struct ImageProcessor {
     link_capturer: YoutubeLinkCapturer,
     pub video_url: Url,
-    ocr: LepTess,
+    reader: FrameReader,
 }
 
 impl ImageProcessor {
-    pub fn new(video_url: Url) -> Result<ImageProcessor> {
-        let ocr = LepTess::new(None, "eng")?;
+    pub fn new(video_url: Url, profile: &OverlayProfile) -> Result<ImageProcessor> {
+        let reader = FrameReader::new(profile)?;
+        info!(
+            "Using overlay profile \"{}\" for {}",
+            profile.name, video_url
+        );
         let link_capturer = YoutubeLinkCapturer::new()?;
         Ok(ImageProcessor {
             link_capturer,
             video_url,
-            ocr,
+            reader,
         })
     }
 
     pub async fn next_track(&mut self) -> Result<Track> {
-        // CAPTURE
+        let full_image = self.capture_frame().await?;
+        let debug_dir = cfg!(debug_assertions).then(|| Path::new("."));
+        let reading = self.read_frame(&full_image, debug_dir)?;
+        info!("Track read using Tesseract OCR: {}", reading.text);
+        let track = Track::from_ocr_text(&reading.text)?;
+        Ok(track)
+    }
+
+    /// Grabs a single frame from the stream.
+    pub async fn capture_frame(&self) -> Result<Mat> {
         let raw_link = self.link_capturer.get_raw_link(&self.video_url).await?;
-        let mut capturer = VideoCapture::from_file(&raw_link, opencv::videoio::CAP_FFMPEG)?;
-        let mut full_image = Mat::default();
+        read_video_frame(&raw_link)
+    }
+
+    pub fn read_frame(&mut self, frame: &Mat, debug_dir: Option<&Path>) -> Result<OcrReading> {
+        self.reader.read(frame, debug_dir)
+    }
+}
+
+/// Runs the crop, preprocessing and OCR steps on an already captured frame.
+pub struct FrameReader {
+    ocr: OcrEngine,
+    stages: Vec<PreprocessStage>,
+}
+
+impl FrameReader {
+    pub fn new(profile: &OverlayProfile) -> Result<FrameReader> {
+        let ocr = OcrEngine::new(&profile.ocr)?;
+        let stages = profile.preprocess.clone().unwrap_or_else(default_stages);
+        Ok(FrameReader { ocr, stages })
+    }
+
+    /// Reads the overlay text of the frame. If `debug_dir` is given, the full,
+    /// cropped and every preprocessed image are written there as well.
+    pub fn read(&mut self, full_image: &Mat, debug_dir: Option<&Path>) -> Result<OcrReading> {
         let params = Vector::new();
-        capturer
-            .read(&mut full_image)?
-            .then_some(())
-            .ok_or(ImageProcessingError::ImageReadError)?;
-        #[cfg(debug_assertions)]
-        opencv::imgcodecs::imwrite("debug_full.jpg", &full_image, &params)?
-            .then_some(())
-            .ok_or(ImageProcessingError::ImageWriteError)?;
+        let write_debug = |name: &str, image: &Mat| -> Result<()> {
+            if let Some(dir) = debug_dir {
+                let path = dir.join(name);
+                let path = path.to_str().ok_or(ImageProcessingError::ImageWriteError)?;
+                opencv::imgcodecs::imwrite(path, image, &params)?
+                    .then_some(())
+                    .ok_or(ImageProcessingError::ImageWriteError)?;
+            }
+            Ok(())
+        };
+        write_debug("debug_full.jpg", full_image)?;
         // CROP
         let image_dimensions = full_image.mat_size();
         (image_dimensions.len() == 2)
             .then_some(())
             .ok_or(ImageProcessingError::ImageDimensionsError)?;
         let roi = Rect_::new(0, 0, image_dimensions[1], image_dimensions[0] / 10);
-        let cropped_image = Mat::roi(&full_image, roi)?;
-        #[cfg(debug_assertions)]
-        opencv::imgcodecs::imwrite("debug_cropped.jpg", &cropped_image, &params)?
-            .then_some(())
-            .ok_or(ImageProcessingError::ImageWriteError)?;
-        // MASK
-        let mut masked_image = Mat::default();
-        opencv::core::in_range(
-            &cropped_image,
-            &*LOW_BOUNDS,
-            &*HIGH_BOUNDS,
-            &mut masked_image,
-        )?;
-        #[cfg(debug_assertions)]
-        opencv::imgcodecs::imwrite("debug_masked.jpg", &masked_image, &params)?
-            .then_some(())
-            .ok_or(ImageProcessingError::ImageWriteError)?;
+        let cropped_image = Mat::roi(full_image, roi)?.try_clone()?;
+        write_debug("debug_cropped.jpg", &cropped_image)?;
+        // PREPROCESS
+        let mut processed_image = cropped_image;
+        for (i, stage) in self.stages.iter().enumerate() {
+            processed_image = stage.apply(&processed_image)?;
+            write_debug(&format!("debug_stage_{}.jpg", i), &processed_image)?;
+        }
         // ENCODE
         let mut buf = Vector::new();
-        opencv::imgcodecs::imencode(".jpg", &masked_image, &mut buf, &params)?
+        opencv::imgcodecs::imencode(".jpg", &processed_image, &mut buf, &params)?
             .then_some(())
             .ok_or(ImageProcessingError::ImageEncodeError)?;
         // OCR
-        self.ocr.set_image_from_mem(buf.as_slice())?;
-        self.ocr.set_source_resolution(DPI);
-        let ocr_text = self.ocr.get_utf8_text()?.trim().to_string();
-        info!("Track read using Tesseract OCR: {}", ocr_text);
-        let track = Track::from_ocr_text(&ocr_text)?;
-        Ok(track)
+        let (text, confidence) = self.ocr.read_text(buf.as_slice(), DPI)?;
+        Ok(OcrReading { text, confidence })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OcrReading {
+    pub text: String,
+    /// Mean word confidence reported by tesseract, between 0 and 100.
+    pub confidence: i32,
+}
+
+/// Loads a frame from an image file, or the first frame of a video file.
+pub fn load_frame(path: &str) -> Result<Mat> {
+    let image = opencv::imgcodecs::imread(path, opencv::imgcodecs::IMREAD_COLOR)?;
+    if !image.empty() {
+        return Ok(image);
     }
+    read_video_frame(path)
+}
+
+fn read_video_frame(source: &str) -> Result<Mat> {
+    let mut capturer = VideoCapture::from_file(source, opencv::videoio::CAP_FFMPEG)?;
+    let mut full_image = Mat::default();
+    capturer
+        .read(&mut full_image)?
+        .then_some(())
+        .ok_or(ImageProcessingError::ImageReadError)?;
+    Ok(full_image)
 }
 
 #[derive(Error, Debug)]
@@ -1,2 +1,6 @@
 mod capture;
+mod ocr;
+mod preprocess;
+pub mod channel;
+pub mod deps;
 pub mod image;
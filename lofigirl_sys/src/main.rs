@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::{Parser, ValueEnum};
+use lofigirl_shared_common::config::{ConfigError, OverlayConfig, OverlayProfile};
+use lofigirl_shared_common::track::Track;
+use lofigirl_sys::image::{load_frame, FrameReader, ImageProcessor, OcrReading};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+const APP_NAME: &str = "lofigirl_ocr";
+
+/// Run the track recognition pipeline on a single image, video file or stream.
+#[derive(Parser, Debug)]
+#[clap(name = APP_NAME, author, version, about, long_about = None)]
+struct Opt {
+    /// Image file, video file or Youtube URL.
+    input: String,
+    /// Configuration toml file to read the overlay settings from.
+    #[clap(short, long, value_parser)]
+    config: Option<PathBuf>,
+    /// Overlay profile to use instead of the base settings.
+    #[clap(short, long, value_parser)]
+    profile: Option<String>,
+    /// Output format.
+    #[clap(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Directory to write the intermediate stage images into.
+    #[clap(short, long, value_parser)]
+    stages_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    #[serde(default)]
+    overlay: OverlayConfig,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    track: Option<Track>,
+    raw_text: String,
+    confidence: i32,
+    error: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(body())
+}
+
+async fn body() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    let opt = Opt::parse();
+    let config = match &opt.config {
+        Some(file_name) => toml::from_str(&std::fs::read_to_string(file_name)?)?,
+        None => Config::default(),
+    };
+    let profile = match &opt.profile {
+        Some(name) => config
+            .overlay
+            .profile_named(name)
+            .ok_or_else(|| ConfigError::UnknownOverlayProfile(name.to_owned()))?,
+        None => config.overlay.base_profile(),
+    };
+    if let Some(dir) = &opt.stages_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let reading = read(&opt, &profile).await?;
+    let report = match Track::from_ocr_text(&reading.text) {
+        Ok(track) => Report {
+            track: Some(track),
+            raw_text: reading.text,
+            confidence: reading.confidence,
+            error: None,
+        },
+        Err(e) => Report {
+            track: None,
+            raw_text: reading.text,
+            confidence: reading.confidence,
+            error: Some(e.to_string()),
+        },
+    };
+    match opt.format {
+        Format::Text => {
+            match (&report.track, &report.error) {
+                (Some(track), _) => println!("Track: {}", track),
+                (None, Some(e)) => println!("Track: {}", e),
+                (None, None) => println!("Track: -"),
+            }
+            println!("Raw text: {}", report.raw_text);
+            println!("Confidence: {}", report.confidence);
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+async fn read(opt: &Opt, profile: &OverlayProfile) -> Result<OcrReading> {
+    let stages_dir = opt.stages_dir.as_deref();
+    match Url::parse(&opt.input) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            let mut image_proc = ImageProcessor::new(url, profile)?;
+            let frame = image_proc.capture_frame().await?;
+            image_proc.read_frame(&frame, stages_dir)
+        }
+        _ => {
+            let mut reader = FrameReader::new(profile)?;
+            let frame = load_frame(&opt.input)?;
+            reader.read(&frame, stages_dir)
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+use anyhow::Result;
+use leptess::Variable;
+use leptess::capi;
+use lofigirl_shared_common::config::OcrConfig;
+use tracing::info;
+
+const DEFAULT_LANGUAGE: &str = "eng";
+
+/// Tesseract instance driven through its C API, since `user_words_file` is
+/// only read while the dictionaries are loaded and `LepTess` initializes
+/// tesseract without any variables.
+pub(crate) struct OcrEngine {
+    handle: *mut capi::TessBaseAPI,
+}
+
+// The handle is owned by the engine and only used through `&mut self`.
+unsafe impl Send for OcrEngine {}
+
+impl OcrEngine {
+    pub(crate) fn new(config: &OcrConfig) -> Result<OcrEngine> {
+        let languages = match &config.languages {
+            Some(languages) if !languages.is_empty() => languages.join("+"),
+            _ => DEFAULT_LANGUAGE.to_owned(),
+        };
+        let mut variables = Vec::new();
+        if let Some(psm) = config.page_seg_mode {
+            variables.push((Variable::TesseditPagesegMode, psm.to_string()));
+        }
+        if let Some(whitelist) = &config.whitelist {
+            variables.push((Variable::TesseditCharWhitelist, whitelist.clone()));
+        }
+        if let Some(blacklist) = &config.blacklist {
+            variables.push((Variable::TesseditCharBlacklist, blacklist.clone()));
+        }
+        if let Some(user_words_file) = &config.user_words_file {
+            variables.push((Variable::UserWordsFile, user_words_file.clone()));
+        }
+        let mut names: Vec<*mut c_char> = variables
+            .iter()
+            .map(|(variable, _)| variable.as_cstr().as_ptr() as *mut c_char)
+            .collect();
+        let values = variables
+            .iter()
+            .map(|(_, value)| CString::new(value.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut value_ptrs: Vec<*mut c_char> =
+            values.iter().map(|v| v.as_ptr() as *mut c_char).collect();
+        let data_path = config
+            .tessdata_path
+            .as_deref()
+            .map(CString::new)
+            .transpose()?;
+        let language = CString::new(languages.as_str())?;
+
+        let engine = OcrEngine {
+            handle: unsafe { capi::TessBaseAPICreate() },
+        };
+        // tesseract only reads the names and values during the call
+        let ret = unsafe {
+            capi::TessBaseAPIInit4(
+                engine.handle,
+                data_path.as_deref().map_or(ptr::null(), CStr::as_ptr),
+                language.as_ptr(),
+                capi::TessOcrEngineMode_OEM_DEFAULT,
+                ptr::null_mut(),
+                0,
+                names.as_mut_ptr(),
+                value_ptrs.as_mut_ptr(),
+                variables.len(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(anyhow::anyhow!(
+                "Tesseract could not be initialized with languages {}",
+                languages
+            ));
+        }
+        info!(
+            "OCR initialized with languages {}{}",
+            languages,
+            config
+                .user_words_file
+                .as_ref()
+                .map(|file| format!(" and user words from {}", file))
+                .unwrap_or_default()
+        );
+        Ok(engine)
+    }
+
+    /// Returns the recognized text along with tesseract's mean confidence.
+    pub(crate) fn read_text(&mut self, image: &[u8], dpi: i32) -> Result<(String, i32)> {
+        let mut pix = unsafe { capi::pixReadMem(image.as_ptr(), image.len()) };
+        if pix.is_null() {
+            return Err(anyhow::anyhow!("Leptonica could not read the image"));
+        }
+        // tesseract keeps its own clone of the image
+        unsafe {
+            capi::TessBaseAPISetImage2(self.handle, pix);
+            capi::pixDestroy(&mut pix);
+            capi::TessBaseAPISetSourceResolution(self.handle, dpi);
+        }
+        let raw_text = unsafe { capi::TessBaseAPIGetUTF8Text(self.handle) };
+        if raw_text.is_null() {
+            return Err(anyhow::anyhow!("Tesseract could not recognize the image"));
+        }
+        let text = unsafe { CStr::from_ptr(raw_text) }
+            .to_str()
+            .map(|text| text.trim().to_string());
+        unsafe { capi::TessDeleteText(raw_text) };
+        let confidence = unsafe { capi::TessBaseAPIMeanTextConf(self.handle) };
+        Ok((text?, confidence))
+    }
+}
+
+impl Drop for OcrEngine {
+    fn drop(&mut self) {
+        unsafe { capi::TessBaseAPIDelete(self.handle) };
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use lofigirl_shared_common::config::PreprocessStage;
+use opencv::core::{Mat, MatTraitConst, Point, Size, BORDER_CONSTANT};
+use opencv::imgproc;
+
+pub(crate) fn default_stages() -> Vec<PreprocessStage> {
+    vec![PreprocessStage::ColorRange {
+        low: [200, 200, 200],
+        high: [255, 255, 255],
+    }]
+}
+
+pub(crate) trait ApplyStage {
+    fn apply(&self, image: &Mat) -> Result<Mat>;
+}
+
+impl ApplyStage for PreprocessStage {
+    fn apply(&self, image: &Mat) -> Result<Mat> {
+        let mut output = Mat::default();
+        match self {
+            PreprocessStage::Resize { width } => {
+                let scale = *width as f64 / image.cols() as f64;
+                imgproc::resize(
+                    image,
+                    &mut output,
+                    Size::new(0, 0),
+                    scale,
+                    scale,
+                    imgproc::INTER_CUBIC,
+                )?;
+            }
+            PreprocessStage::Grayscale => {
+                if image.channels() == 1 {
+                    return Ok(image.try_clone()?);
+                }
+                imgproc::cvt_color_def(image, &mut output, imgproc::COLOR_BGR2GRAY)?;
+            }
+            PreprocessStage::AdaptiveThreshold { block_size, c } => {
+                imgproc::adaptive_threshold(
+                    image,
+                    &mut output,
+                    255.0,
+                    imgproc::ADAPTIVE_THRESH_GAUSSIAN_C,
+                    imgproc::THRESH_BINARY,
+                    *block_size,
+                    *c,
+                )?;
+            }
+            PreprocessStage::OtsuThreshold => {
+                imgproc::threshold(
+                    image,
+                    &mut output,
+                    0.0,
+                    255.0,
+                    imgproc::THRESH_BINARY | imgproc::THRESH_OTSU,
+                )?;
+            }
+            PreprocessStage::Dilate { kernel, iterations } => {
+                let kernel = structuring_element(*kernel)?;
+                imgproc::dilate(
+                    image,
+                    &mut output,
+                    &kernel,
+                    Point::new(-1, -1),
+                    *iterations,
+                    BORDER_CONSTANT,
+                    imgproc::morphology_default_border_value()?,
+                )?;
+            }
+            PreprocessStage::Erode { kernel, iterations } => {
+                let kernel = structuring_element(*kernel)?;
+                imgproc::erode(
+                    image,
+                    &mut output,
+                    &kernel,
+                    Point::new(-1, -1),
+                    *iterations,
+                    BORDER_CONSTANT,
+                    imgproc::morphology_default_border_value()?,
+                )?;
+            }
+            PreprocessStage::Invert => {
+                opencv::core::bitwise_not_def(image, &mut output)?;
+            }
+            PreprocessStage::Denoise { kernel } => {
+                imgproc::median_blur(image, &mut output, *kernel)?;
+            }
+            PreprocessStage::ColorRange { low, high } => {
+                let low = Mat::from_slice(low)?;
+                let high = Mat::from_slice(high)?;
+                opencv::core::in_range(image, &low, &high, &mut output)?;
+            }
+        }
+        Ok(output)
+    }
+}
+
+fn structuring_element(size: i32) -> Result<Mat> {
+    Ok(imgproc::get_structuring_element(
+        imgproc::MORPH_RECT,
+        Size::new(size, size),
+        Point::new(-1, -1),
+    )?)
+}
@@ -5,9 +5,9 @@ use std::rc::Rc;
 
 use lofigirl_shared_common::{
     api::{
-        Action, ScrobbleRequest, SessionRequest, SessionResponse, TokenRequest,
-        TokenResponse,
-    }, config::{LastFMClientPasswordConfig, LastFMClientSessionConfig, ListenBrainzConfig}, jwt::JWTClaims, track::Track, CLIENT_PING_INTERVAL, HEALTH_END_POINT, LASTFM_SESSION_END_POINT, SEND_END_POINT, TOKEN_END_POINT, TRACK_SOCKET_END_POINT
+        Action, EncryptionKeyResponse, RefreshRequest, ScrobbleRequest, SessionRequest, SessionResponse, SocketMessage,
+        TokenRequest, TokenResponse, SOCKET_PROTOCOL_VERSION,
+    }, config::{LastFMClientPasswordConfig, LastFMClientSessionConfig, ListenBrainzConfig}, encrypt::install_server_public_key, stream::StreamId, track::Track, CLIENT_PING_INTERVAL, ENCRYPTION_KEY_END_POINT, HEALTH_END_POINT, LASTFM_SESSION_END_POINT, SEND_END_POINT, TOKEN_END_POINT, TOKEN_REFRESH_END_POINT, TRACK_SOCKET_END_POINT
 };
 
 use gloo_net::{
@@ -37,7 +37,7 @@ struct Model {
     listenbrainz_form: ListenBrainzForm,
     lastfm_config: Option<LastFMClientSessionConfig>,
     listenbrainz_config: Option<ListenBrainzConfig>,
-    session_token: Option<String>,
+    tokens: Option<SessionTokens>,
     server_form: ServerForm,
     server_url: Option<String>,
     page: Page,
@@ -56,7 +56,11 @@ struct Model {
 fn init(_: Url, _: &mut impl Orders<Msg>) -> Model {
     let lastfm = storage::get_lastfm_config();
     let listenbrainz = storage::get_listenbrainz_token();
-    let session_token = storage::get_session_token();
+    // Only the refresh token is persisted, access token is obtained on start
+    let tokens = storage::get_refresh_token().map(|refresh_token| SessionTokens {
+        access_token: None,
+        refresh_token,
+    });
     let server = storage::get_server_url();
     Model {
         lastfm_form: Default::default(),
@@ -67,7 +71,7 @@ fn init(_: Url, _: &mut impl Orders<Msg>) -> Model {
         server_url: server,
         page: Page::Root,
         current_track: Default::default(),
-        session_token,
+        tokens,
         is_scrobbling: false,
         url: Default::default(),
         tx_handle: None,
@@ -75,6 +79,21 @@ fn init(_: Url, _: &mut impl Orders<Msg>) -> Model {
     }
 }
 
+#[derive(Debug, Clone)]
+struct SessionTokens {
+    access_token: Option<String>,
+    refresh_token: String,
+}
+
+impl From<TokenResponse> for SessionTokens {
+    fn from(response: TokenResponse) -> Self {
+        SessionTokens {
+            access_token: Some(response.access_token),
+            refresh_token: response.secure_refresh_token.into(),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 struct LastFMForm {
     username_input: ElRef<HtmlInputElement>,
@@ -116,9 +135,10 @@ enum Msg {
     StopPlaying,
     ServerHealthResponded(String),
     StartPlaying,
-    UpdateTokenThenPlay(String),
+    UpdateTokenThenPlay(SessionTokens),
     ListenSocket(Rc<Mutex<SplitStream<WebSocket>>>),
     NewTrackReceived(Rc<Mutex<SplitStream<WebSocket>>>, Track),
+    TrackActionsSent(Rc<Mutex<SplitStream<WebSocket>>>, SessionTokens),
     PongReceived,
 }
 
@@ -131,11 +151,10 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             let password = form.password_input.get().unwrap().value();
 
             let server = model.server_url.clone().unwrap();
-            let url = format!("{}{}", server, LASTFM_SESSION_END_POINT);
 
             orders.perform_cmd(async move {
                 let session_response =
-                    fetch_lastfm_session(&url, LastFMClientPasswordConfig { username, password })
+                    fetch_lastfm_session(&server, LastFMClientPasswordConfig { username, password })
                         .await
                         .unwrap();
                 Msg::LastFMSessionReceived(LastFMClientSessionConfig {
@@ -187,12 +206,18 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             model.server_url = Some(url);
         }
         Msg::CleanToken => {
-            model.session_token = None;
-            storage::remove_session_token();
+            model.tokens = None;
+            storage::remove_refresh_token();
         }
         Msg::StartPlaying => {
-            // if no session token, set session first
-            if model.session_token.is_none() {
+            // if no access token, refresh or request the session first
+            if model
+                .tokens
+                .as_ref()
+                .and_then(|t| t.access_token.as_ref())
+                .is_none()
+            {
+                let refresh_token = model.tokens.as_ref().map(|t| t.refresh_token.clone());
                 let server = model.server_url.clone().unwrap();
                 let l = model
                     .lastfm_config
@@ -203,12 +228,21 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                     .as_ref()
                     .map(|l| l.token.to_owned());
                 orders.perform_cmd(async move {
-                    let token = fetch_session_token(&server, l, ls).await.unwrap();
-                    Msg::UpdateTokenThenPlay(token.secure_token.into())
+                    let refreshed = match refresh_token {
+                        Some(refresh_token) => refresh_session_token(&server, refresh_token)
+                            .await
+                            .ok(),
+                        None => None,
+                    };
+                    let token = match refreshed {
+                        Some(token) => token,
+                        None => fetch_session_token(&server, l, ls).await.unwrap(),
+                    };
+                    Msg::UpdateTokenThenPlay(token.into())
                 });
             } else {
                 model.is_scrobbling = true;
-                let stream = model.url.url.get().unwrap().value();
+                let stream = stream_link(model.url.url.get().unwrap().value());
                 let server_url = WebUrl::new(&model.server_url.clone().unwrap()).unwrap();
                 // if https > wss else ws
                 let protocol = if server_url.protocol() == "https:" {
@@ -225,8 +259,17 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
                 let socket = WebSocket::open(&socket_url).unwrap();
                 let (mut tx, rx) = socket.split();
                 // send initial message and start pinging
+                let hello = SocketMessage::Hello {
+                    version: SOCKET_PROTOCOL_VERSION,
+                };
+                let subscribe = SocketMessage::Subscribe { stream_url: stream };
                 let tx_handle = orders.perform_cmd_with_handle(async move {
-                    tx.send(Message::Text(stream)).await.unwrap();
+                    tx.send(Message::Text(serde_json_wasm::to_string(&hello).unwrap()))
+                        .await
+                        .unwrap();
+                    tx.send(Message::Text(serde_json_wasm::to_string(&subscribe).unwrap()))
+                        .await
+                        .unwrap();
                     loop {
                         cmds::timeout(CLIENT_PING_INTERVAL.as_millis().try_into().unwrap(), || {})
                             .await;
@@ -241,10 +284,12 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             let rx_handle = orders.perform_cmd_with_handle(async move {
                 let message = rx.lock().await.next().await.unwrap().unwrap();
                 match message {
-                    Message::Text(track_str) => {
-                        let next_track: Track = serde_json_wasm::from_str(&track_str).unwrap();
-                        Msg::NewTrackReceived(rx, next_track)
-                    }
+                    Message::Text(message) => match serde_json_wasm::from_str(&message).unwrap() {
+                        SocketMessage::Track { track, .. } => Msg::NewTrackReceived(rx, track),
+                        // the stream cannot be followed anymore
+                        SocketMessage::Error { .. } => Msg::StopPlaying,
+                        _ => Msg::ListenSocket(rx),
+                    },
                     Message::Bytes(_bytes) => Msg::PongReceived,
                 }
             });
@@ -253,34 +298,57 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         Msg::NewTrackReceived(rx, next_track) => {
             let current_track = model.current_track.clone();
             model.current_track = next_track.clone();
-            let token = model.session_token.clone().unwrap();
+            let mut tokens = model.tokens.clone().unwrap();
             let server = model.server_url.clone().unwrap();
+            let stream = model.url.url.get().map(|input| stream_link(input.value()));
             orders.perform_cmd(async move {
                 if !current_track.is_empty() {
-                    post_track_action(&token, &server, current_track, Action::Listened)
-                        .await
-                        .unwrap();
+                    post_track_action(
+                        &mut tokens,
+                        &server,
+                        current_track,
+                        Action::Listened,
+                        stream.clone(),
+                    )
+                    .await
+                    .unwrap();
                 }
-                post_track_action(&token, &server, next_track, Action::PlayingNow)
+                post_track_action(&mut tokens, &server, next_track, Action::PlayingNow, stream)
                     .await
                     .unwrap();
-                Msg::ListenSocket(rx)
+                Msg::TrackActionsSent(rx, tokens)
             });
         }
-        Msg::UpdateTokenThenPlay(token) => {
-            model.session_token = Some(token.clone());
-            storage::set_session_token(&token);
+        Msg::TrackActionsSent(rx, tokens) => {
+            // tokens might have been refreshed while sending
+            storage::set_refresh_token(&tokens.refresh_token);
+            model.tokens = Some(tokens);
+            orders.send_msg(Msg::ListenSocket(rx));
+        }
+        Msg::UpdateTokenThenPlay(tokens) => {
+            storage::set_refresh_token(&tokens.refresh_token);
+            model.tokens = Some(tokens);
             orders.send_msg(Msg::StartPlaying);
         }
         Msg::PongReceived => {}
     }
 }
 
+/// The canonical link of the stream in the input, or the input as is for the
+/// server to reject.
+fn stream_link(input: String) -> String {
+    input
+        .parse::<StreamId>()
+        .map_or(input, |stream_id| stream_id.url().to_string())
+}
+
 async fn fetch_lastfm_session(
-    url: &str,
+    server: &str,
     password_config: LastFMClientPasswordConfig,
 ) -> anyhow::Result<SessionResponse> {
-    let session_response = Request::post(url)
+    fetch_encryption_key(server).await?;
+    let url = format!("{}{}", server, LASTFM_SESSION_END_POINT);
+    let session_response = Request::post(&url)
         .method(Method::POST)
         .json(&SessionRequest {
             username: password_config.username,
@@ -298,12 +366,14 @@ async fn fetch_session_token(
     lastfm_session_key: Option<String>,
     listenbrainz_token: Option<String>,
 ) -> anyhow::Result<TokenResponse> {
+    fetch_encryption_key(server).await?;
     let url = format!("{}{}", server, TOKEN_END_POINT);
     let token_response = Request::post(&url)
         .method(Method::POST)
         .json(&TokenRequest {
             secure_lastfm_session_key: lastfm_session_key.map(|s| s.into()),
             secure_listenbrainz_token: listenbrainz_token.map(|s| s.into()),
+            label: Some(env!("CARGO_PKG_NAME").to_owned()),
         })?
         .send()
         .await?
@@ -312,25 +382,69 @@ async fn fetch_session_token(
     Ok(token_response)
 }
 
+async fn refresh_session_token(
+    server: &str,
+    refresh_token: String,
+) -> anyhow::Result<TokenResponse> {
+    fetch_encryption_key(server).await?;
+    let url = format!("{}{}", server, TOKEN_REFRESH_END_POINT);
+    let response = Request::post(&url)
+        .method(Method::POST)
+        .json(&RefreshRequest {
+            secure_refresh_token: refresh_token.into(),
+        })?
+        .send()
+        .await?;
+    response
+        .ok()
+        .then_some(())
+        .ok_or_else(|| anyhow::anyhow!("refresh failed with status {}", response.status()))?;
+    Ok(response.json().await?)
+}
+
 async fn post_track_action(
-    token: &str,
+    tokens: &mut SessionTokens,
     server: &str,
     track: Track,
     action: Action,
+    stream_url: Option<String>,
 ) -> anyhow::Result<()> {
-    Request::post(&format!("{}{}", server, SEND_END_POINT))
-        .method(Method::POST)
-        .header(
-            "Authorization",
-            &("Bearer ".to_owned() + &JWTClaims::encode(token.to_owned())?),
-        )
-        .json(&ScrobbleRequest {
-            action,
-            track,
-        })?
+    let url = format!("{}{}", server, SEND_END_POINT);
+    let request = ScrobbleRequest {
+        action,
+        track,
+        stream_url,
+        listened_at: None,
+    };
+    let send = |access_token: String| {
+        Request::post(&url)
+            .method(Method::POST)
+            .header("Authorization", &format!("Bearer {}", access_token))
+            .json(&request)
+    };
+    let access_token = tokens.access_token.clone().unwrap_or_default();
+    let response = send(access_token)?.send().await?;
+    if response.status() == 401 {
+        // access token has expired, get a new pair and try once more
+        *tokens = refresh_session_token(server, tokens.refresh_token.clone())
+            .await?
+            .into();
+        let access_token = tokens.access_token.clone().unwrap_or_default();
+        send(access_token)?.send().await?;
+    }
+    Ok(())
+}
+
+/// Agrees on the `SecureString` encryption key with the server.
+async fn fetch_encryption_key(server: &str) -> anyhow::Result<()> {
+    let url = format!("{}{}", server, ENCRYPTION_KEY_END_POINT);
+    let key: EncryptionKeyResponse = Request::get(&url)
+        .method(Method::GET)
         .send()
+        .await?
+        .json()
         .await?;
-    Ok(())
+    install_server_public_key(&key.key_id, &key.public_key_base64)
 }
 
 async fn check_server_health(server: &str) -> anyhow::Result<()> {
@@ -6,7 +6,7 @@ use gloo_storage::LocalStorage;
 use gloo_storage::Storage;
 
 const LASTFM_SESSION_KEY: &str = "lastfm_session_key";
-const SESSION_TOKEN: &str = "session_token";
+const REFRESH_TOKEN: &str = "refresh_token";
 const LISTENBRAINZ_TOKEN: &str = "listenbrainz_token";
 const SERVER_URL: &str = "server_url";
 
@@ -28,12 +28,12 @@ pub fn remove_lastfm_config() {
     LocalStorage::delete(LASTFM_SESSION_KEY);
 }
 
-pub fn set_session_token(token: &str) {
-    LocalStorage::set(SESSION_TOKEN, token).unwrap();
+pub fn set_refresh_token(token: &str) {
+    LocalStorage::set(REFRESH_TOKEN, token).unwrap();
 }
 
-pub fn get_session_token() -> Option<String> {
-    match LocalStorage::get(SESSION_TOKEN) {
+pub fn get_refresh_token() -> Option<String> {
+    match LocalStorage::get(REFRESH_TOKEN) {
         Ok(token) => Some(token),
         Err(err) => match err {
             StorageError::KeyNotFound(_s) => None,
@@ -42,8 +42,8 @@ pub fn get_session_token() -> Option<String> {
     }
 }
 
-pub fn remove_session_token() {
-    LocalStorage::delete(SESSION_TOKEN);
+pub fn remove_refresh_token() {
+    LocalStorage::delete(REFRESH_TOKEN);
 }
 
 pub fn set_listenbrainz_token(token: &str) {
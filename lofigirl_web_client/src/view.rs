@@ -134,10 +134,10 @@ pub(crate) fn view(model: &Model) -> Vec<Node<Msg>> {
 
             let token = div![
                 div!["Session Info"],
-                match &model.session_token {
+                match &model.tokens {
                     Some(s) => {
                         div![
-                            format!("Using session token: {}", s),
+                            format!("Using refresh token: {}", s.refresh_token),
                             button!["CLEAN", ev(Ev::Click, |_| Msg::CleanToken),],
                         ]
                     }
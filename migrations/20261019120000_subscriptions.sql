@@ -0,0 +1,11 @@
+-- Streams the server scrobbles on behalf of a token without a connected client
+CREATE TABLE IF NOT EXISTS subscriptions (
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    token_id              INTEGER NOT NULL,
+    stream_id             TEXT NOT NULL,
+    stream_url            TEXT NOT NULL,
+    schedule              TEXT,
+    FOREIGN KEY(token_id) REFERENCES tokens(id)
+);
+
+CREATE INDEX IF NOT EXISTS subscriptions_stream_id ON subscriptions(stream_id);
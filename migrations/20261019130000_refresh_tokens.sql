@@ -0,0 +1,8 @@
+-- Refresh tokens issued by the server, rotated on every use
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    token_id              INTEGER NOT NULL,
+    refresh_token         TEXT NOT NULL UNIQUE,
+    expires_at            INTEGER NOT NULL,
+    FOREIGN KEY(token_id) REFERENCES tokens(id)
+);
@@ -0,0 +1,12 @@
+-- Credentials are encrypted by the server with its data key and looked up by
+-- a keyed hash. The plaintext columns are emptied by the server on its next
+-- start, as encrypting the existing rows needs the key.
+ALTER TABLE lastfm ADD COLUMN session_key_hash TEXT;
+ALTER TABLE lastfm ADD COLUMN encrypted_session_key BLOB;
+ALTER TABLE lastfm ADD COLUMN session_key_nonce BLOB;
+CREATE UNIQUE INDEX IF NOT EXISTS lastfm_session_key_hash ON lastfm(session_key_hash);
+
+ALTER TABLE listenbrainz ADD COLUMN token_hash TEXT;
+ALTER TABLE listenbrainz ADD COLUMN encrypted_token BLOB;
+ALTER TABLE listenbrainz ADD COLUMN token_nonce BLOB;
+CREATE UNIQUE INDEX IF NOT EXISTS listenbrainz_token_hash ON listenbrainz(token_hash);
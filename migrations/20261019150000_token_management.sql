@@ -0,0 +1,5 @@
+-- Token records carry a label and usage timestamps, and can be revoked
+ALTER TABLE tokens ADD COLUMN label TEXT;
+ALTER TABLE tokens ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tokens ADD COLUMN last_used_at INTEGER;
+ALTER TABLE tokens ADD COLUMN revoked BOOLEAN NOT NULL DEFAULT FALSE;
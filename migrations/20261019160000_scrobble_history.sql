@@ -0,0 +1,18 @@
+-- Every scrobble sent by or on behalf of a token, with the outcome on each
+-- service. The status columns are NULL for the services the token does not use.
+CREATE TABLE IF NOT EXISTS scrobbles (
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    token_id              INTEGER NOT NULL,
+    action                TEXT NOT NULL,
+    artist                TEXT NOT NULL,
+    song                  TEXT NOT NULL,
+    stream_id             TEXT,
+    lastfm_status         TEXT,
+    lastfm_error          TEXT,
+    listenbrainz_status   TEXT,
+    listenbrainz_error    TEXT,
+    created_at            INTEGER NOT NULL,
+    FOREIGN KEY(token_id) REFERENCES tokens(id)
+);
+
+CREATE INDEX IF NOT EXISTS scrobbles_token_id ON scrobbles(token_id, id);
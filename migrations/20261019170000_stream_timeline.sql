@@ -0,0 +1,11 @@
+-- Tracks detected on each stream. ended_at is NULL while the track is on air.
+CREATE TABLE IF NOT EXISTS stream_tracks (
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    stream_id             TEXT NOT NULL,
+    artist                TEXT NOT NULL,
+    song                  TEXT NOT NULL,
+    started_at            INTEGER NOT NULL,
+    ended_at              INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS stream_tracks_stream_id ON stream_tracks(stream_id, started_at);
@@ -0,0 +1,17 @@
+-- Listens which have failed on a service, retried with backoff until they
+-- are sent or too old for the service.
+CREATE TABLE IF NOT EXISTS outbox (
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    token_id              INTEGER NOT NULL,
+    service               TEXT NOT NULL,
+    artist                TEXT NOT NULL,
+    song                  TEXT NOT NULL,
+    listened_at           INTEGER NOT NULL,
+    attempts              INTEGER NOT NULL,
+    next_attempt_at       INTEGER NOT NULL,
+    last_error            TEXT,
+    FOREIGN KEY(token_id) REFERENCES tokens(id)
+);
+
+CREATE INDEX IF NOT EXISTS outbox_next_attempt_at ON outbox(next_attempt_at);
+CREATE INDEX IF NOT EXISTS outbox_token_id ON outbox(token_id, service);